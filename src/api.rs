@@ -1,6 +1,13 @@
 // Uses
-use crate::{job::Job, workerpool::Queue};
+use crate::{
+    export::ExportFormat,
+    job::{Job, JobSpec},
+    upload::{UploadSpec, UPLOAD_CHUNK_BYTES},
+    watch::WatchSpec,
+    workerpool::Queue,
+};
 use std::sync::{mpsc, Arc, Mutex};
+use uuid::Uuid;
 use warp::{reject::Reject, Filter, Rejection, Reply};
 
 // Pub uses
@@ -13,8 +20,14 @@ pub type Sender<T> = Arc<Mutex<mpsc::Sender<T>>>;
 //
 #[derive(Debug, Clone)]
 pub enum ApiMessageType {
-    Job(String),
+    Job(JobSpec),
     Elastic(uuid::Uuid),
+    Replay(uuid::Uuid),
+    Kibana(uuid::Uuid),
+    Export(uuid::Uuid, ExportFormat),
+    // Created before being queued, for a completed upload so its id could be given back, or for
+    // a watched file so its hash is only recorded once the job has been prepared
+    Created(Box<Job>),
 }
 
 // Functions
@@ -29,9 +42,33 @@ pub fn routes(
         .and_then(handlers::job::get);
     let job_post = warp::path!("job")
         .and(message_queue.clone().into_warp())
-        .and(string_post_body())
+        .and(job_spec_body())
         .and(warp::post())
         .and_then(handlers::job::post);
+    let job_schema = warp::path!("job" / Uuid / "schema")
+        .and(warp::query::<SchemaQuery>())
+        .and(warp::get())
+        .and_then(handlers::job::schema);
+    let job_status = warp::path!("job" / Uuid / "status")
+        .and(warp::get())
+        .and_then(handlers::job::status);
+    let job_export = warp::path!("job" / Uuid / "export")
+        .and(message_queue.clone().into_warp())
+        .and(warp::query::<ExportQuery>())
+        .and(warp::post())
+        .and_then(handlers::job::export);
+    let job_records = warp::path!("job" / Uuid / "records")
+        .and(warp::query::<RecordsQuery>())
+        .and(warp::get())
+        .and_then(handlers::job::records);
+    let job_drift = warp::path!("job" / Uuid / "drift")
+        .and(warp::query::<DriftQuery>())
+        .and(warp::get())
+        .and_then(handlers::job::drift);
+    let job_baseline = warp::path!("baseline" / String)
+        .and(string_post_body())
+        .and(warp::put())
+        .and_then(handlers::job::baseline);
     let job_delete = warp::path!("job")
         .and(current_job.clone().into_warp())
         .and(warp::delete())
@@ -39,10 +76,69 @@ pub fn routes(
     // Elastic
     let elastic_post = warp::path!("elastic")
         .and(message_queue.clone().into_warp())
-        .and(string_post_body())
+        .and(job_spec_body())
         .and(warp::post())
         .and_then(handlers::job::post); // Reuse same route
-    job_get.or(job_post).or(job_delete).or(elastic_post)
+    let kibana_post = warp::path!("kibana")
+        .and(message_queue.clone().into_warp())
+        .and(string_post_body())
+        .and(warp::post())
+        .and_then(handlers::job::kibana);
+    let elastic_replay = warp::path!("elastic" / "replay")
+        .and(message_queue.clone().into_warp())
+        .and(string_post_body())
+        .and(warp::post())
+        .and_then(handlers::job::replay);
+    // Upload
+    let upload_multipart = warp::path!("upload")
+        .and(message_queue.clone().into_warp())
+        .and(warp::multipart::form().max_length(u64::MAX))
+        .and(warp::post())
+        .and_then(handlers::upload::multipart);
+    let upload_post = warp::path!("upload")
+        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
+        .and(warp::post())
+        .and_then(handlers::upload::post);
+    let upload_get = warp::path!("upload" / Uuid)
+        .and(warp::get())
+        .and_then(handlers::upload::get);
+    let upload_patch = warp::path!("upload" / Uuid)
+        .and(message_queue.clone().into_warp())
+        .and(warp::header::<u64>("upload-offset"))
+        .and(warp::body::content_length_limit(*UPLOAD_CHUNK_BYTES).and(warp::body::bytes()))
+        .and(warp::patch())
+        .and_then(handlers::upload::patch);
+    // Watch
+    let watch_get = warp::path!("watch")
+        .and(warp::get())
+        .and_then(handlers::watch::get);
+    let watch_post = warp::path!("watch")
+        .and(message_queue.clone().into_warp())
+        .and(warp::body::content_length_limit(1024 * 16).and(warp::body::json()))
+        .and(warp::post())
+        .and_then(handlers::watch::post);
+    let watch_delete = warp::path!("watch" / Uuid)
+        .and(warp::delete())
+        .and_then(handlers::watch::delete);
+    job_get
+        .or(job_post)
+        .or(job_schema)
+        .or(job_status)
+        .or(job_export)
+        .or(job_records)
+        .or(job_drift)
+        .or(job_baseline)
+        .or(job_delete)
+        .or(elastic_post)
+        .or(elastic_replay)
+        .or(kibana_post)
+        .or(upload_multipart)
+        .or(upload_post)
+        .or(upload_get)
+        .or(upload_patch)
+        .or(watch_get)
+        .or(watch_post)
+        .or(watch_delete)
 }
 
 #[derive(Debug)]
@@ -52,6 +148,52 @@ impl Reject for CustomError {}
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct PostString(pub String);
 
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaFormat {
+    #[default]
+    JsonSchema,
+    Arrow,
+    Postgres,
+    Sqlite,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SchemaQuery {
+    #[serde(default)]
+    pub format: SchemaFormat,
+    // Limit output to a single generated index pattern
+    pub index: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+// A page of a parsed file's casted records
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct RecordsQuery {
+    // The parsed file's uuid, as in the job's file mapping
+    pub file: Uuid,
+    #[serde(default)]
+    pub from: usize,
+    #[serde(default = "default_records_size")]
+    pub size: usize,
+}
+
+fn default_records_size() -> usize {
+    100
+}
+
+// Compare against either a saved baseline or another job
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct DriftQuery {
+    pub baseline: Option<String>,
+    pub job: Option<Uuid>,
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct PostJson(pub serde_json::Value);
 
@@ -60,6 +202,9 @@ pub fn string_post_body() -> impl Filter<Extract = (PostString,), Error = warp::
     // (and to reject huge payloads)...
     warp::body::content_length_limit(1024 * 16).and(warp::body::json())
 }
+pub fn job_spec_body() -> impl Filter<Extract = (JobSpec,), Error = warp::Rejection> + Clone {
+    warp::body::content_length_limit(1024 * 16).and(warp::body::json())
+}
 pub fn json_post_body() -> impl Filter<Extract = (PostJson,), Error = warp::Rejection> + Clone {
     // When accepting a body, we want a JSON body
     // (and to reject huge payloads)...
@@ -87,14 +232,211 @@ mod routes {
         }
         pub async fn post(
             queue: Queue<ApiMessageType>,
-            path: PostString,
+            spec: JobSpec,
+        ) -> Result<Box<dyn Reply>, Rejection> {
+            match (&spec, uuid::Uuid::parse_str(spec.glob())) {
+                (JobSpec::Glob(_), Ok(uuid)) => queue.push(ApiMessageType::Elastic(uuid)),
+                _ => queue.push(ApiMessageType::Job(spec)),
+            }
+            Ok(Box::new(StatusCode::OK))
+        }
+        // Parsing status of a job and, once it has been sent to a sink, the ingest status
+        pub async fn status(id: uuid::Uuid) -> Result<Box<dyn Reply>, Rejection> {
+            let ingest = crate::sink::load_status(id).ok();
+            // Jobs still being parsed only have a status file, their mapping comes once done
+            let status = Job::load(id)
+                .map(|job| job.status)
+                .or_else(|_| crate::job::Status::load(id));
+            let parsing = match status {
+                Ok(status) => status,
+                Err(_) if ingest.is_some() => crate::job::Status::Pending,
+                Err(e) => {
+                    error!("{}", e);
+                    return Ok(Box::new(StatusCode::NOT_FOUND));
+                }
+            };
+            Ok(Box::new(warp::reply::json(&serde_json::json!({
+                "parsing": parsing,
+                "ingest": ingest,
+            }))))
+        }
+        pub async fn kibana(
+            queue: Queue<ApiMessageType>,
+            id: PostString,
         ) -> Result<Box<dyn Reply>, Rejection> {
-            match uuid::Uuid::parse_str(&path.0) {
-                Ok(uuid) => queue.push(ApiMessageType::Elastic(uuid)),
-                Err(_) => queue.push(ApiMessageType::Job(path.0)),
+            match uuid::Uuid::parse_str(&id.0) {
+                Ok(uuid) => queue.push(ApiMessageType::Kibana(uuid)),
+                Err(_) => return Ok(Box::new(StatusCode::BAD_REQUEST)),
             }
             Ok(Box::new(StatusCode::OK))
         }
+        pub async fn replay(
+            queue: Queue<ApiMessageType>,
+            id: PostString,
+        ) -> Result<Box<dyn Reply>, Rejection> {
+            let uuid = match uuid::Uuid::parse_str(&id.0) {
+                Ok(uuid) => uuid,
+                Err(_) => return Ok(Box::new(StatusCode::BAD_REQUEST)),
+            };
+            if !crate::elastic::dead_letter_path(uuid).exists() {
+                return Ok(Box::new(StatusCode::NOT_FOUND));
+            }
+            queue.push(ApiMessageType::Replay(uuid));
+            Ok(Box::new(StatusCode::OK))
+        }
+        // Export runs on a worker, files are written to the job's export directory
+        pub async fn export(
+            id: uuid::Uuid,
+            queue: Queue<ApiMessageType>,
+            query: ExportQuery,
+        ) -> Result<Box<dyn Reply>, Rejection> {
+            if let Err(e) = Job::load(id) {
+                error!("{}", e);
+                return Ok(Box::new(StatusCode::NOT_FOUND));
+            }
+            queue.push(ApiMessageType::Export(id, query.format));
+            Ok(Box::new(StatusCode::OK))
+        }
+        pub async fn schema(
+            id: uuid::Uuid,
+            query: SchemaQuery,
+        ) -> Result<Box<dyn Reply>, Rejection> {
+            use type_casting::schema::{arrow_schema, json_schema, sql_ddl, SqlDialect};
+            let job = match Job::load(id) {
+                Ok(job) => job,
+                Err(e) => {
+                    error!("{}", e);
+                    return Ok(Box::new(StatusCode::NOT_FOUND));
+                }
+            };
+            let mapping = job
+                .mapping
+                .lock()
+                .map_err(|e| warp::reject::custom(crate::api::CustomError(e.to_string())))?;
+            let indices = mapping
+                .index_pattern_mappings
+                .iter()
+                .filter(|(index, _)| query.index.is_none() || query.index.as_ref() == Some(index))
+                .collect::<Vec<_>>();
+            if indices.is_empty() {
+                return Ok(Box::new(StatusCode::NOT_FOUND));
+            }
+            let sql = |dialect| {
+                indices
+                    .iter()
+                    .filter_map(|(index, map)| sql_ddl(index, map, dialect))
+                    .collect::<Vec<_>>()
+                    .join("\n\n")
+            };
+            let json = |f: fn(&type_casting::Types) -> serde_json::Value| {
+                indices
+                    .iter()
+                    .map(|(index, map)| (index.to_string(), f(map)))
+                    .collect::<serde_json::Map<_, _>>()
+            };
+            Ok(match query.format {
+                SchemaFormat::JsonSchema => Box::new(warp::reply::json(&json(json_schema))),
+                SchemaFormat::Arrow => Box::new(warp::reply::json(&json(arrow_schema))),
+                SchemaFormat::Postgres => Box::new(sql(SqlDialect::Postgres)),
+                SchemaFormat::Sqlite => Box::new(sql(SqlDialect::Sqlite)),
+            })
+        }
+        // Casted records of a parsed file, paged with `from` and `size`. Compressed files are
+        // seeked to the frame holding `from` rather than read from the start.
+        pub async fn records(id: Uuid, query: RecordsQuery) -> Result<Box<dyn Reply>, Rejection> {
+            let job = match Job::load(id) {
+                Ok(job) => job,
+                Err(e) => {
+                    error!("{}", e);
+                    return Ok(Box::new(StatusCode::NOT_FOUND));
+                }
+            };
+            let read = tokio::task::spawn_blocking(move || {
+                let mapping = job.mapping.lock().unwrap();
+                let file = match mapping
+                    .file_mapping
+                    .iter()
+                    .find(|f| f.parsed_file_uuid == query.file)
+                {
+                    Some(file) => file,
+                    None => return Ok(None),
+                };
+                let mut records = Vec::new();
+                for res in mapping
+                    .casted_records_from(file, query.from)
+                    .map_err(|e| e.to_string())?
+                    .take(query.size)
+                {
+                    records.push(match res {
+                        Ok((index, record)) => serde_json::json!({
+                            "index": index,
+                            "record": record,
+                        }),
+                        Err(crate::error::CustomError::RecordError { err, index, record }) => {
+                            serde_json::json!({
+                                "index": index,
+                                "record": record,
+                                "error": err.to_string(),
+                            })
+                        }
+                        Err(e) => return Err(e.to_string()),
+                    });
+                }
+                Ok(Some(records))
+            })
+            .await
+            .map_err(|e| warp::reject::custom(crate::api::CustomError(e.to_string())))?;
+            match read {
+                Ok(Some(records)) => Ok(Box::new(warp::reply::json(&records))),
+                Ok(None) => Ok(Box::new(StatusCode::NOT_FOUND)),
+                Err(e) => {
+                    error!("{}", e);
+                    Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+                }
+            }
+        }
+        pub async fn drift(id: Uuid, query: DriftQuery) -> Result<Box<dyn Reply>, Rejection> {
+            let baseline = match (query.baseline, query.job) {
+                (Some(name), None) => crate::drift::load_baseline(&name),
+                (None, Some(job)) => Job::load(job).map(|j| j.mapping.lock().unwrap().clone()),
+                _ => return Ok(Box::new(StatusCode::BAD_REQUEST)),
+            };
+            let (baseline, job) = match (baseline, Job::load(id)) {
+                (Ok(baseline), Ok(job)) => (baseline, job),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("{}", e);
+                    return Ok(Box::new(StatusCode::NOT_FOUND));
+                }
+            };
+            let mapping = job
+                .mapping
+                .lock()
+                .map_err(|e| warp::reject::custom(crate::api::CustomError(e.to_string())))?;
+            let report = crate::drift::diff(&baseline, &mapping);
+            for (index, fields) in report.conflicts() {
+                warn!(
+                    "Job {} has conflicting field types for index {}: {:?}",
+                    id, index, fields
+                );
+            }
+            Ok(Box::new(warp::reply::json(&report)))
+        }
+        pub async fn baseline(name: String, job: PostString) -> Result<Box<dyn Reply>, Rejection> {
+            let saved = uuid::Uuid::parse_str(&job.0)
+                .map_err(|e| crate::error::CustomError::SchemaError(e.into()))
+                .and_then(Job::load)
+                .and_then(|job| {
+                    let mapping = job.mapping.lock().unwrap();
+                    crate::drift::save_baseline(&name, &mapping)
+                });
+            match saved {
+                Ok(()) => Ok(Box::new(StatusCode::OK)),
+                Err(e) => {
+                    error!("{}", e);
+                    Ok(Box::new(StatusCode::BAD_REQUEST))
+                }
+            }
+        }
         pub async fn delete(store: Store<Option<Job>>) -> Result<Box<dyn Reply>, Rejection> {
             match store.inner.write() {
                 Err(e) => Err(warp::reject::custom(crate::api::CustomError(e.to_string()))),
@@ -105,6 +447,207 @@ mod routes {
             }
         }
     }
+
+    // Upload handlers, files are received under UPLOAD_DIR and verified before a job is created
+    pub mod upload {
+        use super::*;
+        use crate::{
+            storage::STORAGE,
+            upload::{self, Upload, UploadError, UploadJob},
+        };
+        use futures::StreamExt;
+        use warp::hyper::body::Buf;
+
+        // Multipart file parts are written in chunks of up to this many bytes
+        const MULTIPART_CHUNK_BYTES: usize = 8 * 1024 * 1024;
+
+        // File I/O and hashing block, other requests are moved off this runtime thread meanwhile
+        fn blocking<F>(f: F) -> Result<Upload, UploadError>
+        where
+            F: FnOnce() -> Result<Upload, UploadError>,
+        {
+            tokio::task::block_in_place(f)
+        }
+
+        // Once complete, create the upload's job if one was asked for
+        fn completed(
+            queue: &Queue<ApiMessageType>,
+            res: Result<Upload, UploadError>,
+        ) -> Result<Upload, UploadError> {
+            let mut upload = res?;
+            if let Some(job) = upload::job(&STORAGE, &mut upload)? {
+                queue.push(ApiMessageType::Created(Box::new(job)));
+            }
+            Ok(upload)
+        }
+        fn reply(res: Result<Upload, UploadError>) -> Box<dyn Reply> {
+            match res {
+                Ok(upload) => Box::new(warp::reply::json(&upload)),
+                Err(e) => {
+                    let (status, mut body) = match &e {
+                        UploadError::NotFound => (StatusCode::NOT_FOUND, serde_json::json!({})),
+                        UploadError::Invalid(_) => (StatusCode::BAD_REQUEST, serde_json::json!({})),
+                        UploadError::Conflict(offset) => (
+                            StatusCode::CONFLICT,
+                            serde_json::json!({ "offset": offset }),
+                        ),
+                        UploadError::HashMismatch(hash) => (
+                            StatusCode::UNPROCESSABLE_ENTITY,
+                            serde_json::json!({ "sha256": hash }),
+                        ),
+                        UploadError::Failed(e) => {
+                            error!("{}", e);
+                            (StatusCode::INTERNAL_SERVER_ERROR, serde_json::json!({}))
+                        }
+                    };
+                    body["error"] = serde_json::Value::String(e.to_string());
+                    Box::new(warp::reply::with_status(warp::reply::json(&body), status))
+                }
+            }
+        }
+        pub async fn post(spec: UploadSpec) -> Result<Box<dyn Reply>, Rejection> {
+            let res = blocking(|| upload::create(&STORAGE, spec));
+            Ok(reply(res))
+        }
+        pub async fn get(id: Uuid) -> Result<Box<dyn Reply>, Rejection> {
+            Ok(reply(upload::load(&STORAGE, id)))
+        }
+        pub async fn patch(
+            id: Uuid,
+            queue: Queue<ApiMessageType>,
+            offset: u64,
+            chunk: warp::hyper::body::Bytes,
+        ) -> Result<Box<dyn Reply>, Rejection> {
+            let res = blocking(|| upload::append(&STORAGE, id, offset, &chunk));
+            Ok(reply(completed(&queue, res)))
+        }
+        // A whole file in one request, the `sha256`, `size` and optional `job` parts have to come
+        // before the `file` part
+        pub async fn multipart(
+            queue: Queue<ApiMessageType>,
+            mut form: warp::multipart::FormData,
+        ) -> Result<Box<dyn Reply>, Rejection> {
+            let (mut sha256, mut size, mut job) = (None, None, None);
+            let invalid = |e: String| Ok(reply(Err(UploadError::Invalid(e))));
+            while let Some(part) = form.next().await {
+                let part = match part {
+                    Ok(part) => part,
+                    Err(e) => return invalid(e.to_string()),
+                };
+                if part.name() != "file" {
+                    let name = part.name().to_string();
+                    let value = match read_part(part).await {
+                        Ok(value) => String::from_utf8_lossy(&value).trim().to_string(),
+                        Err(e) => return invalid(e.to_string()),
+                    };
+                    match name.as_str() {
+                        "sha256" => sha256 = Some(value),
+                        "size" => size = value.parse::<u64>().ok(),
+                        "job" => match serde_json::from_str::<UploadJob>(&value) {
+                            Ok(spec) => job = Some(spec),
+                            Err(e) => return invalid(format!("job: {}", e)),
+                        },
+                        _ => {}
+                    }
+                    continue;
+                }
+                let spec = match (sha256.take(), size.take()) {
+                    (Some(sha256), Some(size)) => UploadSpec {
+                        name: part.filename().unwrap_or("upload").to_string(),
+                        size,
+                        sha256,
+                        job: job.take(),
+                    },
+                    _ => return invalid("sha256 and size must come before file".into()),
+                };
+                let upload = match blocking(|| upload::create(&STORAGE, spec)) {
+                    Ok(upload) => upload,
+                    Err(e) => return Ok(reply(Err(e))),
+                };
+                let id = upload.id;
+                let res = receive(upload, part).await;
+                // Nothing can resume a multipart upload, what was received is removed
+                if res.is_err() {
+                    if let Err(e) = upload::discard(&STORAGE, id) {
+                        error!("{}", e);
+                    }
+                }
+                return Ok(reply(completed(&queue, res)));
+            }
+            invalid("no file part".into())
+        }
+        async fn receive(
+            mut upload: Upload,
+            part: warp::multipart::Part,
+        ) -> Result<Upload, UploadError> {
+            let mut stream = part.stream();
+            let mut chunk = Vec::new();
+            loop {
+                let next = stream.next().await;
+                if let Some(buf) = &next {
+                    match buf {
+                        Ok(buf) => chunk.extend_from_slice(buf.chunk()),
+                        Err(e) => return Err(UploadError::Invalid(e.to_string())),
+                    }
+                }
+                if chunk.len() >= MULTIPART_CHUNK_BYTES || (next.is_none() && !chunk.is_empty()) {
+                    let (id, offset) = (upload.id, upload.offset);
+                    upload = blocking(|| upload::append(&STORAGE, id, offset, &chunk))?;
+                    chunk.clear();
+                }
+                if next.is_none() {
+                    break;
+                }
+            }
+            match upload.path {
+                Some(_) => Ok(upload),
+                None => Err(UploadError::Invalid(format!(
+                    "received {} of {} bytes",
+                    upload.offset, upload.spec.size
+                ))),
+            }
+        }
+        async fn read_part(part: warp::multipart::Part) -> Result<Vec<u8>, warp::Error> {
+            let mut value = Vec::new();
+            let mut stream = part.stream();
+            while let Some(buf) = stream.next().await {
+                value.extend_from_slice(buf?.chunk());
+            }
+            Ok(value)
+        }
+    }
+
+    // Watch folder handlers
+    pub mod watch {
+        use super::*;
+        use crate::storage::STORAGE;
+
+        pub async fn get() -> Result<Box<dyn Reply>, Rejection> {
+            Ok(Box::new(warp::reply::json(&crate::watch::list())))
+        }
+        pub async fn post(
+            queue: Queue<ApiMessageType>,
+            spec: WatchSpec,
+        ) -> Result<Box<dyn Reply>, Rejection> {
+            match crate::watch::add(&STORAGE, &queue, spec) {
+                Ok(watch) => Ok(Box::new(warp::reply::json(&watch))),
+                Err(e) => {
+                    error!("{}", e);
+                    Ok(Box::new(StatusCode::BAD_REQUEST))
+                }
+            }
+        }
+        pub async fn delete(id: Uuid) -> Result<Box<dyn Reply>, Rejection> {
+            match crate::watch::remove(&STORAGE, id) {
+                Ok(true) => Ok(Box::new(StatusCode::OK)),
+                Ok(false) => Ok(Box::new(StatusCode::NOT_FOUND)),
+                Err(e) => {
+                    error!("{}", e);
+                    Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+                }
+            }
+        }
+    }
 }
 
 mod store {
@@ -0,0 +1,331 @@
+use crate::{error::CustomError, storage::Storage};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+use uuid::Uuid;
+
+// Triage collections (KAPE, Velociraptor, ...) are expanded into the job's directory, only the
+// members a parser supports are extracted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    SevenZ,
+}
+
+// Where a parsed file came from when it was extracted from an archive
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveMember {
+    pub archive: PathBuf,
+    // Path within the archive
+    pub member: PathBuf,
+}
+
+impl ArchiveFormat {
+    // Detected from the file's magic bytes, tar files from the `ustar` magic of their first header
+    pub fn detect(path: &Path) -> io::Result<Option<Self>> {
+        let mut header = Vec::with_capacity(512);
+        File::open(path)?.take(512).read_to_end(&mut header)?;
+        let ustar = |header: &[u8]| header.get(257..262) == Some(b"ustar");
+        Ok(match &header[..] {
+            [0x50, 0x4b, 0x03, 0x04, ..] | [0x50, 0x4b, 0x05, 0x06, ..] => Some(Self::Zip),
+            [0x37, 0x7a, 0xbc, 0xaf, 0x27, 0x1c, ..] => Some(Self::SevenZ),
+            [0x1f, 0x8b, ..] => {
+                let mut tar_header = Vec::with_capacity(512);
+                flate2::read::GzDecoder::new(File::open(path)?)
+                    .take(512)
+                    .read_to_end(&mut tar_header)?;
+                ustar(&tar_header).then_some(Self::TarGz)
+            }
+            header if ustar(header) => Some(Self::Tar),
+            _ => None,
+        })
+    }
+}
+
+// Member names as a relative path, anything that could leave the extraction directory is dropped
+fn member_path(name: &str) -> Option<PathBuf> {
+    let path = name
+        .replace('\\', "/")
+        .split('/')
+        .map(Path::new)
+        .flat_map(Path::components)
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect::<PathBuf>();
+    match path.as_os_str().is_empty() {
+        true => None,
+        false => Some(path),
+    }
+}
+
+// Called with each file in an archive, its name, size and contents
+type EachMember<'a> = dyn FnMut(&str, u64, &mut dyn Read) -> Result<(), CustomError> + 'a;
+
+fn each_member(
+    path: &Path,
+    format: ArchiveFormat,
+    each: &mut EachMember,
+) -> Result<(), CustomError> {
+    let err = |e: &dyn std::fmt::Display| {
+        CustomError::ArchiveError(format!("{}: {}", path.display(), e).into())
+    };
+    let file = File::open(path).map_err(|e| err(&e))?;
+    match format {
+        ArchiveFormat::Zip => {
+            let mut archive = zip::ZipArchive::new(file).map_err(|e| err(&e))?;
+            for i in 0..archive.len() {
+                let mut member = archive.by_index(i).map_err(|e| err(&e))?;
+                if member.is_file() {
+                    let name = member.name().to_string();
+                    each(&name, member.size(), &mut member)?;
+                }
+            }
+            Ok(())
+        }
+        ArchiveFormat::Tar | ArchiveFormat::TarGz => {
+            let reader: Box<dyn Read> = match format {
+                ArchiveFormat::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+                _ => Box::new(file),
+            };
+            let mut archive = tar::Archive::new(reader);
+            for member in archive.entries().map_err(|e| err(&e))? {
+                let mut member = member.map_err(|e| err(&e))?;
+                if member.header().entry_type().is_file() {
+                    let name = member.path().map_err(|e| err(&e))?;
+                    let name = name.to_string_lossy().to_string();
+                    each(&name, member.size(), &mut member)?;
+                }
+            }
+            Ok(())
+        }
+        ArchiveFormat::SevenZ => {
+            let mut archive = sevenz_rust::SevenZReader::open(path, sevenz_rust::Password::empty())
+                .map_err(|e| err(&e))?;
+            // Failures are carried out of the closure, 7z only knows its own errors
+            let mut failed = None;
+            archive
+                .for_each_entries(|entry, reader| {
+                    if entry.is_directory() || !entry.has_stream() {
+                        return Ok(true);
+                    }
+                    match each(entry.name(), entry.size(), reader) {
+                        Ok(()) => Ok(true),
+                        Err(e) => {
+                            failed = Some(e);
+                            Ok(false)
+                        }
+                    }
+                })
+                .map_err(|e| err(&e))?;
+            failed.map_or(Ok(()), Err)
+        }
+    }
+}
+
+// Extract the members of an archive that have a parser into `dir`, returning where each was
+// written. Collection metadata (see `collection::is_metadata`) is extracted but not returned.
+// Members are checked against the parsers' magic bytes as they are read, the rest are skipped
+// without being written.
+pub fn expand(
+    storage: &Storage,
+    archive: &Path,
+    format: ArchiveFormat,
+    dir: &Path,
+) -> Result<Vec<(PathBuf, ArchiveMember)>, CustomError> {
+    let mut extracted = Vec::new();
+    let err = |e: io::Error| CustomError::ArchiveError(format!("{}: {}", dir.display(), e).into());
+    each_member(archive, format, &mut |name, size, reader| {
+        let member = match member_path(name) {
+            Some(member) => member,
+            None => return Ok(()),
+        };
+        let mut header = Vec::with_capacity(8);
+        reader.take(8).read_to_end(&mut header).map_err(err)?;
+        let parser = crate::Parser::detect(&header, &member);
+        // A collection's metadata is extracted too, to tag the members that are parsed
+        if parser == crate::Parser::None && !crate::collection::is_metadata(&member) {
+            // Solid 7z blocks are read through, skipped members still have to be consumed
+            io::copy(reader, &mut io::sink()).map_err(err)?;
+            return Ok(());
+        }
+        storage.ensure_space(size)?;
+        let path = dir.join(&member);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(err)?;
+        }
+        let mut file = File::create(&path).map_err(err)?;
+        // No more is written than the space checked for, whatever the member's header says
+        io::copy(
+            &mut header.as_slice().chain(&mut *reader).take(size),
+            &mut file,
+        )
+        .map_err(err)?;
+        if header.len() as u64 > size || reader.read(&mut [0]).map_err(err)? > 0 {
+            drop(file);
+            fs::remove_file(&path).map_err(err)?;
+            return Err(CustomError::ArchiveError(
+                format!(
+                    "{}: {} is larger than its header says",
+                    archive.display(),
+                    name
+                )
+                .into(),
+            ));
+        }
+        if parser == crate::Parser::None {
+            return Ok(());
+        }
+        extracted.push((
+            path,
+            ArchiveMember {
+                archive: archive.to_path_buf(),
+                member,
+            },
+        ));
+        Ok(())
+    })?;
+    Ok(extracted)
+}
+
+// Replace any archives among a job's paths with their extracted members. An archive that fails to
+// expand is logged and left out of the job.
+pub fn expand_paths(
+    storage: &Storage,
+    job: Uuid,
+    paths: Vec<PathBuf>,
+) -> (Vec<PathBuf>, Vec<(PathBuf, ArchiveMember)>) {
+    let mut files = Vec::new();
+    let mut members = Vec::new();
+    for path in paths {
+        let format = match ArchiveFormat::detect(&path) {
+            Ok(Some(format)) => format,
+            _ => {
+                files.push(path);
+                continue;
+            }
+        };
+        let dir = storage.archive_dir(job).join(Uuid::new_v4().to_string());
+        match expand(storage, &path, format, &dir) {
+            Ok(extracted) => {
+                info!(
+                    "Expanded {:?} archive {}, {} members to parse",
+                    format,
+                    path.display(),
+                    extracted.len()
+                );
+                for (path, member) in extracted {
+                    files.push(path.clone());
+                    members.push((path, member));
+                }
+            }
+            Err(e) => error!("{}", e),
+        }
+    }
+    (files, members)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn expand_archives() {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&root).unwrap();
+        let storage = Storage::new(&root, 0);
+        let evtx = b"ElfFile\0 some evtx bytes".to_vec();
+        let members: [(&str, &[u8]); 3] = [
+            ("C/Windows/System32/winevt/Logs/Security.evtx", &evtx),
+            ("C/$MFT", b"FILE0\0\0\0 some mft bytes"),
+            ("C/Users/a/NTUSER.DAT", b"regf"),
+        ];
+
+        let zip_path = root.join("collection.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+        for (name, contents) in members {
+            zip.start_file(name, Default::default()).unwrap();
+            zip.write_all(contents).unwrap();
+        }
+        // Can't be written outside the extraction directory
+        zip.start_file("../../escape.evtx", Default::default())
+            .unwrap();
+        zip.write_all(&evtx).unwrap();
+        zip.finish().unwrap();
+
+        let tar_path = root.join("collection.tar.gz");
+        let gz = flate2::write::GzEncoder::new(
+            File::create(&tar_path).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(gz);
+        for (name, contents) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            tar.append_data(&mut header, name, contents).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let sevenz_path = root.join("collection.7z");
+        let mut sevenz = sevenz_rust::SevenZWriter::create(&sevenz_path).unwrap();
+        for (name, contents) in members {
+            let source = root.join("member");
+            fs::write(&source, contents).unwrap();
+            let entry = sevenz_rust::SevenZArchiveEntry::from_path(&source, name.to_string());
+            sevenz
+                .push_archive_entry(entry, Some(File::open(&source).unwrap()))
+                .unwrap();
+        }
+        sevenz.finish().unwrap();
+
+        let plain = root.join("Security.evtx");
+        fs::write(&plain, &evtx).unwrap();
+
+        assert_eq!(
+            ArchiveFormat::detect(&zip_path).unwrap(),
+            Some(ArchiveFormat::Zip)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(&tar_path).unwrap(),
+            Some(ArchiveFormat::TarGz)
+        );
+        assert_eq!(
+            ArchiveFormat::detect(&sevenz_path).unwrap(),
+            Some(ArchiveFormat::SevenZ)
+        );
+        assert_eq!(ArchiveFormat::detect(&plain).unwrap(), None);
+
+        let job = Uuid::new_v4();
+        let (files, extracted) = expand_paths(
+            &storage,
+            job,
+            vec![zip_path.clone(), tar_path, sevenz_path, plain.clone()],
+        );
+        assert_eq!(files.len(), 8);
+        assert_eq!(files[7], plain);
+        assert_eq!(extracted.len(), 7);
+        let (path, member) = &extracted[0];
+        assert!(path.starts_with(storage.archive_dir(job)));
+        assert_eq!(fs::read(path).unwrap(), evtx);
+        assert_eq!(
+            member,
+            &ArchiveMember {
+                archive: zip_path,
+                member: PathBuf::from("C/Windows/System32/winevt/Logs/Security.evtx"),
+            }
+        );
+        assert_eq!(extracted[1].1.member, PathBuf::from("C/$MFT"));
+        assert_eq!(extracted[2].1.member, PathBuf::from("escape.evtx"));
+        assert_eq!(extracted[6].1.member, PathBuf::from("C/$MFT"));
+        assert!(extracted.iter().all(|(path, _)| path.exists()));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+use crate::{
+    elastic::{compress, document_id},
+    error::CustomError,
+    sink::RETRY_POLICY,
+    type_map::{get_value, skip_failed, Mapping, ParsedFileStats},
+};
+use serde_json::{Map, Value};
+use type_casting::{
+    schema::{flatten, flatten_value},
+    Types,
+};
+
+lazy_static! {
+    static ref CLICKHOUSE_ADDRESS: String = std::env::var("CLICKHOUSE_ADDRESS")
+        .unwrap_or_else(|_| "http://0.0.0.0:8123".to_string());
+    // user:password
+    static ref CLICKHOUSE_USER: String =
+        std::env::var("CLICKHOUSE_USER").unwrap_or_else(|_| "default:".to_string());
+    static ref CLICKHOUSE_DATABASE: String =
+        std::env::var("CLICKHOUSE_DATABASE").unwrap_or_else(|_| "ulp".to_string());
+    static ref BATCH_ROWS: usize = crate::env_usize("CLICKHOUSE_BATCH_ROWS", 50000);
+}
+
+// String fields with few distinct values (hosts, channels, users, ...) stored as LowCardinality,
+// matched against the end of the field's path. Fields overridden to an Elastic `keyword` are too.
+const KEYWORD_SUFFIXES: &[&str] = &[
+    "Name", "Domain", "Computer", "Channel", "Guid", "Sid", "Type", "Level", "Keywords", "module",
+];
+
+const ID: &str = "_id";
+const TIMESTAMP: &str = "@timestamp";
+
+fn quote_identifier(name: &str) -> String {
+    let mut quoted = String::from('`');
+    for c in name.chars() {
+        if c == '\\' || c == '`' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('`');
+    quoted
+}
+
+fn table_name(table: &str) -> String {
+    format!(
+        "{}.{}",
+        quote_identifier(&CLICKHOUSE_DATABASE),
+        quote_identifier(table)
+    )
+}
+
+fn keyword(mapping: &Mapping, index: &str, path: &str) -> bool {
+    let overridden = mapping
+        .overrides
+        .get(Some(index), path)
+        .and_then(|o| o.elastic())
+        .and_then(|e| e.get("type"))
+        .map(|t| t == "keyword");
+    overridden.unwrap_or_else(|| KEYWORD_SUFFIXES.iter().any(|s| path.ends_with(s)))
+}
+
+pub fn column_type(t: &Types, keyword: bool) -> &'static str {
+    match t {
+        Types::Bool => "Nullable(Bool)",
+        Types::Int => "Nullable(Int64)",
+        Types::Float => "Nullable(Float64)",
+        Types::IPv4 => "Nullable(IPv4)",
+        Types::IPv6 => "Nullable(IPv6)",
+        Types::Date => "Nullable(DateTime64(6, 'UTC'))",
+        Types::Null | Types::Str if keyword => "LowCardinality(Nullable(String))",
+        // Lists are stored as their JSON
+        _ => "Nullable(String)",
+    }
+}
+
+// The database, then a MergeTree table per index pattern partitioned by month on `@timestamp`,
+// then its flattened columns added with IF NOT EXISTS. The table's deduplication window drops an
+// INSERT that is retried with the same rows.
+pub fn table_ddl(mapping: &Mapping, table: &str, t: &Types) -> Vec<String> {
+    let name = table_name(table);
+    let columns = flatten(t)
+        .into_iter()
+        .filter(|(path, _)| path != ID && path != TIMESTAMP)
+        .map(|(path, t)| {
+            format!(
+                "ADD COLUMN IF NOT EXISTS {} {}",
+                quote_identifier(&path),
+                column_type(&t, keyword(mapping, table, &path))
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut statements = vec![
+        format!(
+            "CREATE DATABASE IF NOT EXISTS {}",
+            quote_identifier(&CLICKHOUSE_DATABASE)
+        ),
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} ({} String, {} DateTime64(6, 'UTC')) ENGINE = MergeTree \
+             PARTITION BY toYYYYMM({}) ORDER BY ({}) \
+             SETTINGS non_replicated_deduplication_window = 1000",
+            name,
+            quote_identifier(ID),
+            quote_identifier(TIMESTAMP),
+            quote_identifier(TIMESTAMP),
+            quote_identifier(TIMESTAMP)
+        ),
+    ];
+    if !columns.is_empty() {
+        statements.push(format!("ALTER TABLE {} {}", name, columns.join(", ")));
+    }
+    statements
+}
+
+// Send a query, retrying while ClickHouse is unavailable. Returns the response body.
+fn query(query: &str, body: Vec<u8>, gzip: bool) -> Result<String, CustomError> {
+    let client = reqwest::blocking::Client::new();
+    let (user, password) = CLICKHOUSE_USER
+        .split_once(':')
+        .unwrap_or((CLICKHOUSE_USER.as_str(), ""));
+    let mut attempt = 0;
+    loop {
+        let mut request = client
+            .post(CLICKHOUSE_ADDRESS.as_str())
+            .query(&[("query", query), ("date_time_input_format", "best_effort")])
+            .basic_auth(user, Some(password))
+            .body(body.clone());
+        if gzip {
+            request = request.header("Content-Encoding", "gzip");
+        }
+        let res = request.send();
+        let retry = match &res {
+            Ok(res) => matches!(res.status().as_u16(), 502..=504),
+            Err(_) => true,
+        };
+        if retry && attempt < RETRY_POLICY.max_retries {
+            let delay = RETRY_POLICY.backoff(attempt);
+            warn!("ClickHouse request failed, retrying in {:?}", delay);
+            std::thread::sleep(delay);
+            attempt += 1;
+            continue;
+        }
+        let res = res.map_err(|e| CustomError::ClickHouseError(e.into()))?;
+        return match res.status().is_success() {
+            true => res
+                .text()
+                .map_err(|e| CustomError::ClickHouseError(e.into())),
+            false => Err(CustomError::ClickHouseError(
+                res.text().unwrap_or_default().into(),
+            )),
+        };
+    }
+}
+
+pub fn create_tables(mapping: &Mapping) -> Result<(), CustomError> {
+    for (pattern, t) in &mapping.index_pattern_mappings {
+        for statement in table_ddl(mapping, pattern, t) {
+            query(&statement, Vec::new(), false)?;
+        }
+    }
+    Ok(())
+}
+
+// A JSONEachRow row, fields flattened to the table's dotted columns
+pub fn row(id: String, record: Value, time_field: Option<&str>) -> String {
+    let time = time_field
+        .and_then(|field| get_value(&record, field))
+        .cloned()
+        .unwrap_or_else(|| Value::from(0));
+    let mut row = Map::new();
+    row.insert(ID.to_string(), Value::String(id));
+    row.insert(TIMESTAMP.to_string(), time);
+    for (path, value) in flatten_value(record) {
+        let value = match value {
+            list @ Value::Array(_) => Value::String(list.to_string()),
+            value => value,
+        };
+        row.entry(path).or_insert(value);
+    }
+    Value::Object(row).to_string()
+}
+
+fn insert(table: &str, rows: String) -> Result<(), CustomError> {
+    let body = compress(rows.into_bytes()).map_err(|e| CustomError::ClickHouseError(e.into()))?;
+    query(
+        &format!("INSERT INTO {} FORMAT JSONEachRow", table_name(table)),
+        body,
+        true,
+    )
+    .map(|_| ())
+}
+
+// Insert a parsed file's casted records as JSONEachRow, one INSERT per CLICKHOUSE_BATCH_ROWS rows.
+// Records that can't be cast are counted as failed. ClickHouse rejects an INSERT with any row it
+// can't parse, that error is returned for the file and rows from its earlier INSERTs stay in the
+// table.
+pub fn insert_file(map: &Mapping, file: &ParsedFileStats) -> Result<usize, CustomError> {
+    insert_batches(map, file, *BATCH_ROWS, insert)
+}
+
+// Batch a parsed file's casted records per index pattern as JSONEachRow rows, `insert` sends each
+// batch. Returns the number of records that failed.
+fn insert_batches(
+    map: &Mapping,
+    file: &ParsedFileStats,
+    size: usize,
+    mut insert: impl FnMut(&str, String) -> Result<(), CustomError>,
+) -> Result<usize, CustomError> {
+    let mut tables: std::collections::BTreeMap<String, (Option<String>, String, usize)> =
+        Default::default();
+    let mut failed = 0;
+    for (record, res) in map.casted_records(file)?.enumerate() {
+        let (pattern, json) = match skip_failed(res, &mut failed)? {
+            Some(record) => record,
+            None => continue,
+        };
+        let (time_field, rows, count) = tables.entry(pattern.clone()).or_insert_with(|| {
+            let time_field = map
+                .index_pattern_mappings
+                .get(&pattern)
+                .and_then(crate::kibana::time_field);
+            (time_field, String::new(), 0)
+        });
+        let id = document_id(&file.file_hash, record, &file.parser_used);
+        rows.push_str(&row(id, json, time_field.as_deref()));
+        rows.push('\n');
+        *count += 1;
+        if *count >= size {
+            insert(&pattern, std::mem::take(rows))?;
+            *count = 0;
+        }
+    }
+    for (pattern, (_, rows, count)) in tables {
+        if count > 0 {
+            insert(&pattern, rows)?;
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn table_ddl_test() {
+        let record = json!({
+            "Event": {"System": {"Computer": "WS01", "EventID": 4624}},
+            "Time": "2021-01-01T00:00:00Z",
+            "Message": "a",
+            "Ip": "127.0.0.1",
+            "List": [1]
+        });
+        let statements = table_ddl(&Mapping::default(), "evtx", &Types::get_type(&record));
+        assert_eq!(statements.len(), 3);
+        assert!(statements[1].starts_with(
+            "CREATE TABLE IF NOT EXISTS `ulp`.`evtx` (`_id` String, `@timestamp` DateTime64(6, 'UTC')) ENGINE = MergeTree"
+        ));
+        assert_eq!(
+            statements[2],
+            "ALTER TABLE `ulp`.`evtx` \
+             ADD COLUMN IF NOT EXISTS `Event.System.Computer` LowCardinality(Nullable(String)), \
+             ADD COLUMN IF NOT EXISTS `Event.System.EventID` Nullable(Int64), \
+             ADD COLUMN IF NOT EXISTS `Ip` Nullable(IPv4), \
+             ADD COLUMN IF NOT EXISTS `List` Nullable(String), \
+             ADD COLUMN IF NOT EXISTS `Message` Nullable(String), \
+             ADD COLUMN IF NOT EXISTS `Time` Nullable(DateTime64(6, 'UTC'))"
+        );
+    }
+
+    #[test]
+    fn row_test() {
+        let record = json!({"a": {"b": "2021-01-01T00:00:00Z"}, "c": [1, 2]});
+        assert_eq!(
+            row("id".to_string(), record, Some("a.b")),
+            r#"{"_id":"id","@timestamp":"2021-01-01T00:00:00Z","a.b":"2021-01-01T00:00:00Z","c":"[1,2]"}"#
+        );
+    }
+
+    #[test]
+    fn insert_batches_test() {
+        let (map, file) = crate::sink::test_file(&[
+            json!({"EntryId": 5, "FullPath": "a", "StandardInfoCreated": "2021-01-01T00:00:00Z"}),
+            json!({"EntryId": 6, "FullPath": "b", "Flags": {"Deleted": true}}),
+            json!({"EntryId": 7, "FullPath": "c", "StandardInfoCreated": "2021-01-03T00:00:00Z"}),
+        ]);
+        let mut batches = Vec::new();
+        let failed = insert_batches(&map, &file, 2, |table, rows| {
+            batches.push((table.to_string(), rows));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(failed, 1);
+        let id = |record| document_id(&file.file_hash, record, &crate::Parser::Mft);
+        assert_eq!(
+            batches,
+            vec![
+                (
+                    "mft".to_string(),
+                    format!(
+                        "{}\n{}\n",
+                        json!({"_id": id(0), "@timestamp": "2021-01-01T00:00:00+00:00", "EntryId": 5, "FullPath": "a", "StandardInfoCreated": "2021-01-01T00:00:00+00:00"}),
+                        // Records without a timestamp are at the epoch
+                        json!({"_id": id(1), "@timestamp": 0, "EntryId": 6, "Flags.Deleted": true, "FullPath": "b"}),
+                    )
+                ),
+                (
+                    "mft".to_string(),
+                    format!(
+                        "{}\n",
+                        json!({"_id": id(2), "@timestamp": "2021-01-03T00:00:00+00:00", "EntryId": 7, "FullPath": "c", "StandardInfoCreated": "2021-01-03T00:00:00+00:00"}),
+                    )
+                ),
+            ]
+        );
+        // An INSERT ClickHouse rejects fails the file
+        let err = insert_batches(&map, &file, 2, |_, _| {
+            Err(CustomError::ClickHouseError("Cannot parse input".into()))
+        });
+        std::fs::remove_dir_all(file.parsed_file_path.parent().unwrap()).unwrap();
+        assert!(matches!(err, Err(CustomError::ClickHouseError(_))));
+    }
+}
@@ -0,0 +1,247 @@
+use crate::archive::ArchiveMember;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+lazy_static! {
+    // KAPE's logs and containers are named `{yyyy-MM-ddTHHmmss}_...`
+    static ref KAPE_LOG: Regex =
+        Regex::new(r"^(\d{4}-\d{2}-\d{2}T\d{6})_(ConsoleLog\.txt|CopyLog\.csv)$").unwrap();
+    static ref KAPE_NAME: Regex = Regex::new(r"^(\d{4}-\d{2}-\d{2}T\d{6})_(.+)$").unwrap();
+    // Velociraptor's offline collector names its output `Collection-{host}-{yyyy-MM-ddTHH_mm_ssZ}`
+    static ref VELOCIRAPTOR_NAME: Regex =
+        Regex::new(r"^Collection-(.+)-(\d{4}-\d{2}-\d{2}T\d{2}_\d{2}_\d{2}Z?)$").unwrap();
+}
+
+const CLIENT_INFO: &str = "client_info.json";
+const COLLECTION_CONTEXT: &str = "collection_context.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CollectionTool {
+    Kape,
+    Velociraptor,
+}
+
+// A triage collection a parsed file was found in, added to each of its records and its parsed
+// file stats
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Collection {
+    pub tool: CollectionTool,
+    // The collection's directory, or its archive
+    pub path: PathBuf,
+    pub host: Option<String>,
+    pub collected: Option<DateTime<Utc>>,
+}
+
+// Collection files read for their metadata, extracted from archives along with parsed members
+pub fn is_metadata(member: &Path) -> bool {
+    match member.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name == CLIENT_INFO || name == COLLECTION_CONTEXT || KAPE_LOG.is_match(name),
+        None => false,
+    }
+}
+
+fn kape_time(time: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H%M%S")
+        .ok()
+        .map(|t| Utc.from_utc_datetime(&t))
+}
+
+fn velociraptor_time(time: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(time.trim_end_matches('Z'), "%Y-%m-%dT%H_%M_%S")
+        .ok()
+        .map(|t| Utc.from_utc_datetime(&t))
+}
+
+fn read_json(path: PathBuf) -> Option<Value> {
+    serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+}
+
+// The collection rooted at `dir`, if it has either tool's layout. `name` is the directory's name,
+// or the archive's for an expanded archive.
+pub fn detect_root(dir: &Path, name: &str) -> Option<Collection> {
+    let client_info = read_json(dir.join(CLIENT_INFO));
+    let context = read_json(dir.join(COLLECTION_CONTEXT));
+    let velociraptor_name = VELOCIRAPTOR_NAME.captures(name);
+    if client_info.is_some()
+        || context.is_some()
+        || (velociraptor_name.is_some() && dir.join("uploads").is_dir())
+    {
+        let host = client_info
+            .as_ref()
+            .and_then(|info| info.get("Hostname").or_else(|| info.get("Fqdn")))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .or_else(|| velociraptor_name.as_ref().map(|c| c[1].to_string()));
+        // Microseconds since the epoch
+        let collected = context
+            .as_ref()
+            .and_then(|context| context.get("create_time"))
+            .and_then(Value::as_i64)
+            .and_then(|micros| Utc.timestamp_opt(micros / 1_000_000, 0).single())
+            .or_else(|| {
+                velociraptor_name
+                    .as_ref()
+                    .and_then(|c| velociraptor_time(&c[2]))
+            });
+        return Some(Collection {
+            tool: CollectionTool::Velociraptor,
+            path: dir.to_path_buf(),
+            host,
+            collected,
+        });
+    }
+    let log_time = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| {
+            let name = entry.file_name();
+            let time = KAPE_LOG.captures(name.to_str()?)?[1].to_string();
+            kape_time(&time)
+        });
+    let kape_name = KAPE_NAME.captures(name);
+    if log_time.is_none() && kape_name.is_none() {
+        return None;
+    }
+    // Containers are named after the host, a directory found by its logs alone could be named
+    // anything
+    Some(Collection {
+        tool: CollectionTool::Kape,
+        path: dir.to_path_buf(),
+        host: kape_name.as_ref().map(|c| c[2].to_string()),
+        collected: log_time.or_else(|| kape_name.and_then(|c| kape_time(&c[1]))),
+    })
+}
+
+fn archive_name(archive: &Path) -> String {
+    let name = archive
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    [".zip", ".7z", ".tgz", ".gz", ".tar"]
+        .iter()
+        .fold(name, |name, ext| name.trim_end_matches(ext))
+        .to_string()
+}
+
+// Detects the collection each of a job's files belongs to, looking up from the file for a
+// directory with a collection's layout. Expanded archives are checked as a whole, named after
+// the archive.
+#[derive(Default)]
+pub struct Detector {
+    // By directory and the name it is detected with
+    roots: HashMap<(PathBuf, String), Option<Collection>>,
+}
+
+impl Detector {
+    fn root(&mut self, dir: &Path, name: &str) -> Option<Collection> {
+        self.roots
+            .entry((dir.to_path_buf(), name.to_string()))
+            .or_insert_with(|| detect_root(dir, name))
+            .clone()
+    }
+    pub fn detect(&mut self, path: &Path, archive: Option<&ArchiveMember>) -> Option<Collection> {
+        if let Some(archive) = archive {
+            let dir = path.ancestors().nth(archive.member.components().count())?;
+            return self
+                .root(dir, &archive_name(&archive.archive))
+                .map(|collection| Collection {
+                    path: archive.archive.clone(),
+                    ..collection
+                });
+        }
+        path.ancestors().skip(1).find_map(|dir| {
+            let name = dir.file_name()?.to_str()?.to_string();
+            self.root(dir, &name)
+        })
+    }
+}
+
+// Add the collection to a record, `host.name` is only set where the record has none of its own
+// (ie. forwarded events)
+pub fn tag(collection: &Collection, record: Value) -> Value {
+    let mut record = match record {
+        Value::Object(record) => record,
+        record => return record,
+    };
+    let has_host = record.get("host").and_then(|h| h.get("name")).is_some();
+    if let (Some(host), false) = (&collection.host, has_host) {
+        crate::ecs::insert(&mut record, "host.name", Value::from(host.as_str()));
+    }
+    if let Ok(value) = serde_json::to_value(collection) {
+        record.insert("collection".to_string(), value);
+    }
+    Value::Object(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn detect_collections() {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let kape = root.join("WS01");
+        let kape_logs = kape.join("C/Windows/System32/winevt/Logs");
+        fs::create_dir_all(&kape_logs).unwrap();
+        fs::write(kape.join("2023-06-12T035026_ConsoleLog.txt"), "").unwrap();
+        let velociraptor = root.join("Collection-WS02.corp-2023-06-12T03_50_26Z");
+        let uploads = velociraptor.join("uploads/auto/C%3A/Windows/System32/winevt/Logs");
+        fs::create_dir_all(&uploads).unwrap();
+        fs::write(
+            velociraptor.join(CLIENT_INFO),
+            r#"{"Hostname": "WS02", "Fqdn": "WS02.corp"}"#,
+        )
+        .unwrap();
+        fs::create_dir_all(root.join("plain")).unwrap();
+
+        let mut detector = Detector::default();
+        let collection = detector
+            .detect(&kape_logs.join("Security.evtx"), None)
+            .unwrap();
+        assert_eq!(
+            collection,
+            Collection {
+                tool: CollectionTool::Kape,
+                path: kape.clone(),
+                host: None,
+                collected: Some(Utc.with_ymd_and_hms(2023, 6, 12, 3, 50, 26).unwrap()),
+            }
+        );
+        let collection = detector.detect(&uploads.join("System.evtx"), None).unwrap();
+        assert_eq!(collection.tool, CollectionTool::Velociraptor);
+        assert_eq!(collection.host.as_deref(), Some("WS02"));
+        assert_eq!(
+            collection.collected,
+            Some(Utc.with_ymd_and_hms(2023, 6, 12, 3, 50, 26).unwrap())
+        );
+        assert_eq!(detector.detect(&root.join("plain/a.evtx"), None), None);
+
+        // Expanded archives are named after the archive
+        let member = ArchiveMember {
+            archive: root.join("2023-06-12T035026_WS03.zip"),
+            member: PathBuf::from("C/$MFT"),
+        };
+        let collection = detector
+            .detect(&root.join("plain/C/$MFT"), Some(&member))
+            .unwrap();
+        assert_eq!(collection.host.as_deref(), Some("WS03"));
+        assert_eq!(collection.path, member.archive);
+
+        let record = tag(&collection, json!({"EntryId": 5}));
+        assert_eq!(record["host"]["name"], "WS03");
+        assert_eq!(record["collection"]["tool"], "kape");
+        assert_eq!(record["collection"]["collected"], "2023-06-12T03:50:26Z");
+        let record = tag(&collection, json!({"host": {"name": "WS04"}}));
+        assert_eq!(record["host"]["name"], "WS04");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+// How a task's parsed records are stored, set by DATA_COMPRESSION
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "data",
+            Compression::Gzip => "data.gz",
+            Compression::Zstd => "data.zst",
+        }
+    }
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DataConfig {
+    pub compression: Compression,
+    // Start a new segment once one reaches this many bytes on disk, 0 never rotates
+    pub segment_bytes: usize,
+    // Uncompressed bytes per independently compressed frame
+    pub frame_bytes: usize,
+}
+
+lazy_static! {
+    pub static ref DATA_CONFIG: DataConfig = DataConfig {
+        compression: crate::env_enum("DATA_COMPRESSION", Compression::None),
+        segment_bytes: crate::env_usize("DATA_SEGMENT_BYTES", 0),
+        frame_bytes: crate::env_usize("DATA_FRAME_BYTES", 1024 * 1024),
+    };
+}
+
+// Path of a segment of a `{task}.data` file, the first keeps the task's name:
+// `{task}.data.zst`, `{task}.1.data.zst`, `{task}.2.data.zst`, ...
+pub fn segment_path(path: impl AsRef<Path>, segment: usize, compression: Compression) -> PathBuf {
+    let path = path.as_ref();
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default();
+    let stem = name
+        .trim_end_matches(".gz")
+        .trim_end_matches(".zst")
+        .trim_end_matches(".data");
+    path.with_file_name(match segment {
+        0 => format!("{}.{}", stem, compression.extension()),
+        n => format!("{}.{}.{}", stem, n, compression.extension()),
+    })
+}
+
+// Every segment of a data file, given its first
+pub fn segments(path: impl AsRef<Path>) -> Vec<PathBuf> {
+    let compression = Compression::from_path(path.as_ref());
+    let mut segments = vec![path.as_ref().to_path_buf()];
+    loop {
+        let next = segment_path(path.as_ref(), segments.len(), compression);
+        if !next.exists() {
+            return segments;
+        }
+        segments.push(next);
+    }
+}
+
+// Compressed segments have an index of `{offset} {record}` lines, where each frame starts and the
+// number of records it is preceded by, ending with the segment's length and record count
+fn index_path(segment: &Path) -> PathBuf {
+    let mut path = segment.as_os_str().to_owned();
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+fn read_index(segment: &Path) -> io::Result<Option<Vec<(u64, usize)>>> {
+    let file = match File::open(index_path(segment)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut frames = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let frame = line
+            .split_once(' ')
+            .and_then(|(offset, record)| Some((offset.parse().ok()?, record.parse().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed frame index"))?;
+        frames.push(frame);
+    }
+    Ok(Some(frames))
+}
+
+fn decoder(compression: Compression, file: File) -> io::Result<Box<dyn Read + Send>> {
+    Ok(match compression {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(file)?),
+    })
+}
+
+pub type Lines = Box<dyn Iterator<Item = io::Result<String>> + Send>;
+
+// Lines of every segment of a data file, whichever way it is compressed
+pub fn read_lines(path: impl AsRef<Path>) -> io::Result<Lines> {
+    read_lines_from(path, 0)
+}
+
+// As above, starting after `skip` records. Frames before it are seeked past using the segments'
+// indexes where they have them.
+pub fn read_lines_from(path: impl AsRef<Path>, skip: usize) -> io::Result<Lines> {
+    let mut skip = skip;
+    let mut seekable = true;
+    let mut reader: Box<dyn Read + Send> = Box::new(io::empty());
+    for segment in segments(path) {
+        let mut file = File::open(&segment)?;
+        if seekable && skip > 0 {
+            if let Some(frames) = read_index(&segment)? {
+                let records = frames.last().map_or(0, |(_, r)| *r);
+                if skip >= records {
+                    skip -= records;
+                    continue;
+                }
+                let (offset, record) = frames
+                    .iter()
+                    .rev()
+                    .find(|(_, r)| *r <= skip)
+                    .copied()
+                    .unwrap_or((0, 0));
+                file.seek(SeekFrom::Start(offset))?;
+                skip -= record;
+            }
+            seekable = false;
+        }
+        reader = Box::new(reader.chain(decoder(Compression::from_path(&segment), file)?));
+    }
+    Ok(Box::new(BufReader::new(reader).lines().skip(skip)))
+}
+
+// Writes a task's records as NDJSON, compressing them a frame at a time and rotating segments.
+// Nothing is lost if `finish` is not called, but write errors at drop are only logged.
+pub struct DataWriter {
+    path: PathBuf,
+    config: DataConfig,
+    segment: usize,
+    file: BufWriter<File>,
+    index: Option<BufWriter<File>>,
+    // Bytes and records in the segment, up to the end of the last frame
+    offset: u64,
+    records: usize,
+    frame: Vec<u8>,
+    frame_records: usize,
+}
+
+impl DataWriter {
+    // Create the data file for `{task}.data`, replacing any segments from an earlier run
+    pub fn create(path: impl AsRef<Path>, config: &DataConfig) -> io::Result<Self> {
+        let path = segment_path(path, 0, config.compression);
+        for segment in segments(&path) {
+            for file in [index_path(&segment), segment] {
+                match fs::remove_file(file) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        let (file, index) = Self::open(&path, config.compression)?;
+        Ok(DataWriter {
+            path,
+            config: config.clone(),
+            segment: 0,
+            file,
+            index,
+            offset: 0,
+            records: 0,
+            frame: Vec::new(),
+            frame_records: 0,
+        })
+    }
+    fn open(
+        segment: &Path,
+        compression: Compression,
+    ) -> io::Result<(BufWriter<File>, Option<BufWriter<File>>)> {
+        let index = match compression {
+            Compression::None => None,
+            _ => Some(BufWriter::new(File::create(index_path(segment))?)),
+        };
+        Ok((BufWriter::new(File::create(segment)?), index))
+    }
+    // The first segment, recorded as the task's parsed file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn write_record(&mut self, record: &serde_json::Value) -> io::Result<()> {
+        serde_json::to_writer(&mut self.frame, record)?;
+        self.frame.push(b'\n');
+        self.frame_records += 1;
+        if self.frame.len() >= self.config.frame_bytes {
+            self.flush_frame()?;
+        }
+        Ok(())
+    }
+    fn flush_frame(&mut self) -> io::Result<()> {
+        if self.frame_records == 0 {
+            return Ok(());
+        }
+        if self.config.segment_bytes > 0
+            && self.offset > 0
+            && self.offset as usize >= self.config.segment_bytes
+        {
+            self.rotate()?;
+        }
+        let frame = std::mem::take(&mut self.frame);
+        let bytes = match self.config.compression {
+            Compression::None => frame,
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&frame)?;
+                encoder.finish()?
+            }
+            Compression::Zstd => zstd::bulk::compress(&frame, 0)?,
+        };
+        if let Some(index) = &mut self.index {
+            writeln!(index, "{} {}", self.offset, self.records)?;
+        }
+        self.file.write_all(&bytes)?;
+        self.offset += bytes.len() as u64;
+        self.records += std::mem::take(&mut self.frame_records);
+        Ok(())
+    }
+    // Close the segment's index and file
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(index) = &mut self.index {
+            writeln!(index, "{} {}", self.offset, self.records)?;
+            index.flush()?;
+        }
+        self.file.flush()
+    }
+    fn rotate(&mut self) -> io::Result<()> {
+        self.close()?;
+        self.segment += 1;
+        let segment = segment_path(&self.path, self.segment, self.config.compression);
+        (self.file, self.index) = Self::open(&segment, self.config.compression)?;
+        self.offset = 0;
+        self.records = 0;
+        Ok(())
+    }
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.flush_frame()?;
+        self.close()?;
+        // Closed, nothing is left for drop
+        self.index = None;
+        Ok(())
+    }
+}
+
+impl Drop for DataWriter {
+    fn drop(&mut self) {
+        if self.index.is_some() || self.frame_records > 0 {
+            if let Err(e) = self.finish() {
+                error!("Failed to write {}: {}", self.path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn write_and_read_segments() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let records = (0..100)
+            .map(|i| json!({"EntryId": i, "FullPath": format!("file{}", i)}))
+            .collect::<Vec<_>>();
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let config = DataConfig {
+                compression,
+                segment_bytes: 512,
+                frame_bytes: 256,
+            };
+            let mut writer = DataWriter::create(dir.join("task.data"), &config).unwrap();
+            for record in &records {
+                writer.write_record(record).unwrap();
+            }
+            writer.finish().unwrap();
+            let path = writer.path().to_path_buf();
+            assert_eq!(path, segment_path(dir.join("task.data"), 0, compression));
+            assert!(segments(&path).len() > 2);
+            // Compressed segments index where each of their frames starts
+            let frames = read_index(&path).unwrap();
+            assert_eq!(frames.is_some(), compression != Compression::None);
+            if let Some(frames) = frames {
+                assert!(frames.len() > 2);
+                assert_eq!(frames[0], (0, 0));
+                assert_eq!(frames.last().unwrap().0, fs::metadata(&path).unwrap().len());
+            }
+            let read = |skip| {
+                read_lines_from(&path, skip)
+                    .unwrap()
+                    .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+                    .collect::<Vec<serde_json::Value>>()
+            };
+            assert_eq!(read(0), records);
+            assert_eq!(read(57), records[57..]);
+            assert!(read(100).is_empty());
+        }
+        // Rewriting a task's data removes the segments of the earlier run
+        let config = DataConfig {
+            compression: Compression::Zstd,
+            segment_bytes: 0,
+            frame_bytes: 256,
+        };
+        let mut writer = DataWriter::create(dir.join("task.data"), &config).unwrap();
+        writer.write_record(&records[0]).unwrap();
+        drop(writer);
+        let path = segment_path(dir.join("task.data"), 0, Compression::Zstd);
+        assert_eq!(segments(&path).len(), 1);
+        assert_eq!(read_lines(&path).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{error::CustomError, storage::STORAGE, type_map::Mapping};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+use type_casting::{merge_consume, schema::list_item_type, Types};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Added(Types),
+    Removed(Types),
+    Changed {
+        from: Types,
+        to: Types,
+        // The new type can hold every value of the old one, ie. Int -> Str
+        widened: bool,
+        // The Elastic field type differs, existing indices will reject the new mapping
+        conflict: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MappingDiff {
+    pub global: BTreeMap<String, Change>,
+    pub added_indices: Vec<String>,
+    pub removed_indices: Vec<String>,
+    pub indices: BTreeMap<String, BTreeMap<String, Change>>,
+}
+
+impl MappingDiff {
+    pub fn is_empty(&self) -> bool {
+        self.global.is_empty()
+            && self.added_indices.is_empty()
+            && self.removed_indices.is_empty()
+            && self.indices.is_empty()
+    }
+    // Fields, keyed by index pattern, whose change would cause an Elastic mapping error
+    pub fn conflicts(&self) -> BTreeMap<&str, Vec<&str>> {
+        let mut conflicts = BTreeMap::new();
+        for (index, changes) in &self.indices {
+            let fields = changes
+                .iter()
+                .filter(|(_, c)| matches!(c, Change::Changed { conflict: true, .. }))
+                .map(|(f, _)| f.as_str())
+                .collect::<Vec<_>>();
+            if !fields.is_empty() {
+                conflicts.insert(index.as_str(), fields);
+            }
+        }
+        conflicts
+    }
+}
+
+pub fn diff(baseline: &Mapping, current: &Mapping) -> MappingDiff {
+    let mut report = MappingDiff {
+        global: diff_types(&baseline.map, &current.map),
+        ..Default::default()
+    };
+    for (index, map) in &current.index_pattern_mappings {
+        match baseline.index_pattern_mappings.get(index) {
+            Some(base) => {
+                let changes = diff_types(base, map);
+                if !changes.is_empty() {
+                    report.indices.insert(index.clone(), changes);
+                }
+            }
+            None => report.added_indices.push(index.clone()),
+        }
+    }
+    for index in baseline.index_pattern_mappings.keys() {
+        if !current.index_pattern_mappings.contains_key(index) {
+            report.removed_indices.push(index.clone());
+        }
+    }
+    report
+}
+
+// Changes between two type maps keyed by dotted field path
+pub fn diff_types(baseline: &Types, current: &Types) -> BTreeMap<String, Change> {
+    let mut changes = BTreeMap::new();
+    recurse("", Some(baseline), Some(current), &mut changes);
+    return changes;
+    fn recurse(
+        path: &str,
+        baseline: Option<&Types>,
+        current: Option<&Types>,
+        changes: &mut BTreeMap<String, Change>,
+    ) {
+        match (baseline, current) {
+            (Some(Types::Object(a)), Some(Types::Object(b))) => {
+                let mut keys = a.keys().chain(b.keys()).collect::<Vec<_>>();
+                keys.sort();
+                keys.dedup();
+                for key in keys {
+                    let child = match path.is_empty() {
+                        true => key.to_string(),
+                        false => format!("{}.{}", path, key),
+                    };
+                    recurse(&child, a.get(key), b.get(key), changes);
+                }
+            }
+            (Some(Types::List(a)), Some(Types::List(b))) => recurse(
+                path,
+                Some(&list_item_type(a)),
+                Some(&list_item_type(b)),
+                changes,
+            ),
+            (None, Some(t)) => {
+                changes.insert(path.to_string(), Change::Added(t.clone()));
+            }
+            (Some(t), None) => {
+                changes.insert(path.to_string(), Change::Removed(t.clone()));
+            }
+            (Some(from), Some(to)) if from != to => {
+                changes.insert(
+                    path.to_string(),
+                    Change::Changed {
+                        from: from.clone(),
+                        to: to.clone(),
+                        widened: is_widened(from, to),
+                        conflict: crate::elastic::elastic_type(from)
+                            != crate::elastic::elastic_type(to),
+                    },
+                );
+            }
+            _ => (),
+        }
+    }
+}
+
+fn is_widened(from: &Types, to: &Types) -> bool {
+    match (from, to) {
+        (Types::Object(_) | Types::List(_), _) | (_, Types::Object(_) | Types::List(_)) => false,
+        _ => &merge_consume(from.clone(), to.clone()) == to,
+    }
+}
+
+fn baseline_path(name: &str) -> Result<PathBuf, CustomError> {
+    match !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        true => Ok(STORAGE.baseline_dir().join(format!("{}.json", name))),
+        false => Err(CustomError::SchemaError(
+            format!("Invalid baseline name: {}", name).into(),
+        )),
+    }
+}
+
+pub fn save_baseline(name: &str, mapping: &Mapping) -> Result<(), CustomError> {
+    let path = baseline_path(name)?;
+    let contents =
+        serde_json::to_string(mapping).map_err(|e| CustomError::SchemaError(e.into()))?;
+    STORAGE.write(path, contents)
+}
+
+pub fn load_baseline(name: &str) -> Result<Mapping, CustomError> {
+    let path = baseline_path(name)?;
+    let contents = fs::read_to_string(&path).map_err(|e| {
+        CustomError::SchemaError(
+            format!("Failed to read baseline {}: {}", path.display(), e).into(),
+        )
+    })?;
+    serde_json::from_str(&contents).map_err(|e| CustomError::SchemaError(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn field_changes() {
+        let baseline = Types::get_type(&json!({"a": 1, "b": {"c": 1}, "d": "x"}));
+        let current = Types::get_type(&json!({"a": "text", "b": {"c": 1.5}, "e": true}));
+        let changes = diff_types(&baseline, &current);
+        assert_eq!(
+            changes.get("a"),
+            Some(&Change::Changed {
+                from: Types::Int,
+                to: Types::Str,
+                widened: true,
+                conflict: true
+            })
+        );
+        assert_eq!(
+            changes.get("b.c"),
+            Some(&Change::Changed {
+                from: Types::Int,
+                to: Types::Float,
+                widened: true,
+                conflict: true
+            })
+        );
+        assert_eq!(changes.get("d"), Some(&Change::Removed(Types::Str)));
+        assert_eq!(changes.get("e"), Some(&Change::Added(Types::Bool)));
+        assert_eq!(changes.len(), 4);
+    }
+
+    #[test]
+    fn index_changes() {
+        let mut baseline = Mapping::default();
+        let mut current = Mapping::default();
+        baseline
+            .index_pattern_mappings
+            .insert("a".into(), Types::get_type(&json!({"x": 1})));
+        baseline
+            .index_pattern_mappings
+            .insert("b".into(), Types::get_type(&json!({"x": 1})));
+        current
+            .index_pattern_mappings
+            .insert("a".into(), Types::get_type(&json!({"x": "y"})));
+        current
+            .index_pattern_mappings
+            .insert("c".into(), Types::get_type(&json!({"x": 1})));
+        let report = diff(&baseline, &current);
+        assert_eq!(report.added_indices, vec!["c"]);
+        assert_eq!(report.removed_indices, vec!["b"]);
+        assert_eq!(report.conflicts().get("a"), Some(&vec!["x"]));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::Parser;
+use serde_json::{Map, Value};
+
+// Elastic Common Schema field and the source paths it is read from, the first present wins
+type Table = &'static [(&'static str, &'static [&'static str])];
+
+const EVTX: Table = &[
+    (
+        "@timestamp",
+        &["Event.System.TimeCreated_attributes.SystemTime"],
+    ),
+    ("event.code", &["Event.System.EventID"]),
+    ("event.provider", &["Event.System.Provider_attributes.Name"]),
+    ("host.name", &["Event.System.Computer"]),
+    ("winlog.channel", &["Event.System.Channel"]),
+    ("winlog.record_id", &["Event.System.EventRecordID"]),
+    (
+        "user.name",
+        &[
+            "Event.EventData.TargetUserName",
+            "Event.EventData.SubjectUserName",
+            "Event.EventData.User",
+        ],
+    ),
+    (
+        "user.domain",
+        &[
+            "Event.EventData.TargetDomainName",
+            "Event.EventData.SubjectDomainName",
+        ],
+    ),
+    (
+        "source.ip",
+        &["Event.EventData.IpAddress", "Event.EventData.SourceIp"],
+    ),
+    (
+        "process.pid",
+        &["Event.EventData.ProcessId", "Event.EventData.NewProcessId"],
+    ),
+    (
+        "process.executable",
+        &["Event.EventData.NewProcessName", "Event.EventData.Image"],
+    ),
+    ("process.command_line", &["Event.EventData.CommandLine"]),
+    (
+        "process.parent.executable",
+        &[
+            "Event.EventData.ParentProcessName",
+            "Event.EventData.ParentImage",
+        ],
+    ),
+    (
+        "file.path",
+        &[
+            "Event.EventData.TargetFilename",
+            "Event.EventData.ObjectName",
+        ],
+    ),
+];
+
+const MFT: Table = &[
+    ("@timestamp", &["StandardInfoLastModified"]),
+    ("file.path", &["FullPath"]),
+    ("file.size", &["FileSize"]),
+    ("file.inode", &["EntryId"]),
+    ("file.created", &["StandardInfoCreated"]),
+    ("file.mtime", &["StandardInfoLastModified"]),
+    ("file.accessed", &["StandardInfoLastAccess"]),
+];
+
+fn table(parser: &Parser) -> Table {
+    match parser {
+        Parser::Evtx => EVTX,
+        Parser::Mft => MFT,
+        Parser::None => &[],
+    }
+}
+
+// Field the parser's original record is kept under once normalised
+pub fn namespace(parser: &Parser) -> &'static str {
+    match parser {
+        Parser::Evtx => "evtx",
+        Parser::Mft => "mft",
+        Parser::None => "none",
+    }
+}
+
+fn get<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(value, |value, key| value.get(key))
+        .filter(|v| !v.is_null())
+}
+
+pub(crate) fn insert(map: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        // @timestamp and friends are kept as is
+        Some((head, rest)) if !head.starts_with('@') => {
+            let child = map
+                .entry(head.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if let Value::Object(child) = child {
+                insert(child, rest, value);
+            }
+        }
+        _ => {
+            map.insert(path.to_string(), value);
+        }
+    }
+}
+
+// Map a parsed record to ECS, the original record is kept under the parser's namespace
+pub fn normalise(parser: &Parser, record: Value) -> Value {
+    let mut doc = Map::new();
+    for (field, sources) in table(parser) {
+        if let Some(value) = sources.iter().find_map(|path| get(&record, path)) {
+            insert(&mut doc, field, value.clone());
+        }
+    }
+    insert(&mut doc, "event.module", Value::from(namespace(parser)));
+    doc.insert(namespace(parser).to_string(), record);
+    Value::Object(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn evtx_record() {
+        let record = json!({"Event": {
+            "System": {
+                "EventID": 4624,
+                "Computer": "WS01",
+                "TimeCreated_attributes": {"SystemTime": "2021-01-01T00:00:00Z"}
+            },
+            "EventData": {"SubjectUserName": "-", "TargetUserName": "bob", "IpAddress": null}
+        }});
+        assert_eq!(
+            normalise(&Parser::Evtx, record.clone()),
+            json!({
+                "@timestamp": "2021-01-01T00:00:00Z",
+                "event": {"code": 4624, "module": "evtx"},
+                "host": {"name": "WS01"},
+                "user": {"name": "bob"},
+                "evtx": record
+            })
+        );
+    }
+}
@@ -1,156 +1,986 @@
 use crate::error::CustomError;
-use crate::type_map::{IndexPatternObject, Mapping};
+use crate::overrides::Overrides;
+use crate::sink::RETRY_POLICY;
+use crate::type_map::{Mapping, ParsedFileStats};
+use flate2::{write::GzEncoder, Compression};
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde_json::json;
 use std::{
     collections::BTreeMap,
-    fs::File,
-    io::{self, BufRead},
-    path::{Path, PathBuf},
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
 };
 use type_casting::Types as TypeMap;
 
-pub fn send_mapping(index: String, data: TypeMap) -> Result<(), CustomError> {
+fn credentials() -> (&'static str, &'static str) {
+    crate::ELASTIC_USER
+        .split_once(':')
+        .unwrap_or((crate::ELASTIC_USER.as_str(), ""))
+}
+
+fn url(path: &str) -> String {
+    format!("{}/{}", crate::ELASTIC_ADDRESS.trim_end_matches('/'), path)
+}
+
+// Request against the configured Elastic address using the configured credentials
+fn request(
+    client: &reqwest::blocking::Client,
+    method: reqwest::Method,
+    path: &str,
+) -> reqwest::blocking::RequestBuilder {
+    let (user, password) = credentials();
+    client
+        .request(method, url(path))
+        .basic_auth(user, Some(password))
+}
+
+// As above, using the shared pooled client for bulk requests
+fn async_request(method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+    let (user, password) = credentials();
+    CLIENT
+        .request(method, url(path))
+        .basic_auth(user, Some(password))
+}
+
+fn check_response(
+    res: reqwest::blocking::Response,
+    action: &str,
+) -> Result<reqwest::blocking::Response, CustomError> {
+    match res.status().is_success() {
+        true => Ok(res),
+        false => Err(CustomError::ElasticError(
+            format!(
+                "An error has occured whilst {}. {}",
+                action,
+                res.text().unwrap_or_default()
+            )
+            .into(),
+        )),
+    }
+}
+
+// Create the index for an index pattern, or apply the mapping additively to the existing index,
+// returns whether the index was created. Incompatible field type changes are rejected before
+// anything is sent, unless `additive` where only the fields the index doesn't map yet are sent.
+pub fn send_mapping(
+    index: String,
+    data: TypeMap,
+    overrides: &Overrides,
+    additive: bool,
+) -> Result<bool, CustomError> {
+    let name = sanitise_string_elastic(&index);
+    let mapping = as_elastic_map(&data, overrides, &index, &MAPPING_LIMITS);
+    let (settings, mappings) = (&mapping["settings"], &mapping["mappings"]);
     let client = reqwest::blocking::Client::new();
-    // loop {
-    let res = client
-        .put(format!(
-            "http://0.0.0.0:9200/{}",
-            sanitise_string_elastic(&index)
-        ))
-        .basic_auth("elastic", Some("changeme"))
-        .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
-        .body(as_elastic_map(&data))
+    // Compare against the existing index first
+    let res = request(&client, reqwest::Method::GET, &format!("{}/_mapping", name))
         .send()
         .map_err(|e| CustomError::ElasticError(e.into()))?;
-    match res.status() {
-        reqwest::StatusCode::OK => (),
+    let existing = match res.status() {
+        reqwest::StatusCode::NOT_FOUND => None,
         _ => {
-            error!(
-                "An error has occured whilst uploading an index mapping. {}",
-                res.text()
-                    .map_err(|e| CustomError::ElasticError(e.into()))?
+            let body: serde_json::Value = check_response(res, "reading an index mapping")?
+                .json()
+                .map_err(|e| CustomError::ElasticError(e.into()))?;
+            body.as_object()
+                .and_then(|m| m.values().next())
+                .map(|v| v["mappings"].clone())
+        }
+    };
+    if let Some(existing) = &existing {
+        let conflicts = mapping_conflicts(existing, mappings);
+        if !conflicts.is_empty() && additive {
+            warn!(
+                "Index {} keeps its mapping of fields: {}",
+                name,
+                conflicts.join(", ")
             );
+        } else if !conflicts.is_empty() {
+            return Err(CustomError::ElasticError(
+                format!(
+                    "Incompatible mapping changes for index {}, fields: {}",
+                    name,
+                    conflicts.join(", ")
+                )
+                .into(),
+            ));
+        }
+    }
+    // Create the index with its own mapping, or add new fields to the existing index
+    if existing.is_some() {
+        let res = request(
+            &client,
+            reqwest::Method::PUT,
+            &format!("{}/_settings", name),
+        )
+        .json(settings)
+        .send()
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+        check_response(res, "updating index settings")?;
+    }
+    let created = existing.is_none();
+    let mappings = match existing {
+        Some(existing) if additive => {
+            let added = new_fields(&existing, mappings);
+            if added.is_empty() {
+                return Ok(false);
+            }
+            json!({ "properties": added })
         }
+        _ => mappings.clone(),
+    };
+    let res = match created {
+        true => request(&client, reqwest::Method::PUT, &name)
+            .json(&json!({ "settings": settings, "mappings": mappings }))
+            .send(),
+        false => request(&client, reqwest::Method::PUT, &format!("{}/_mapping", name))
+            .json(&mappings)
+            .send(),
+    }
+    .map_err(|e| CustomError::ElasticError(e.into()))?;
+    check_response(res, "uploading an index mapping")?;
+    Ok(created)
+}
+
+// Create or update a component and an index template per parser, matching all of its indices
+// (ie. `evtx_*`) with the mappings of the job's indices merged, so indices created by later jobs
+// or by the bulk API pick the mapping up. Templates are named after the parser's index prefix.
+// An existing component template only gains fields, those it already maps are kept as they are.
+pub fn send_templates(mapping: &Mapping) -> Result<(), CustomError> {
+    let client = reqwest::blocking::Client::new();
+    for parser in mapping.parsers() {
+        let (prefix, wildcard) = (parser.index_prefix(), parser.index_wildcard());
+        let mut data = TypeMap::Null;
+        for (index, t) in &mapping.index_pattern_mappings {
+            let index = sanitise_string_elastic(index);
+            let matches = match wildcard.ends_with('*') {
+                true => index.starts_with(&prefix),
+                false => index == prefix,
+            };
+            if matches {
+                type_casting::merge(&mut data, t.clone());
+            }
+        }
+        if data == TypeMap::Null {
+            continue;
+        }
+        let mut template = as_elastic_map(&data, &mapping.overrides, &wildcard, &MAPPING_LIMITS);
+        let name = prefix.trim_end_matches('_');
+        let component = format!("{}_mappings", name);
+        let res = request(
+            &client,
+            reqwest::Method::GET,
+            &format!("_component_template/{}", component),
+        )
+        .send()
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+        if res.status() != reqwest::StatusCode::NOT_FOUND {
+            let body: serde_json::Value = check_response(res, "reading a component template")?
+                .json()
+                .map_err(|e| CustomError::ElasticError(e.into()))?;
+            let existing = &body["component_templates"][0]["component_template"]["template"];
+            let mut mappings = existing["mappings"].clone();
+            grow(&mut mappings, &template["mappings"]);
+            template["mappings"] = mappings;
+        }
+        let res = request(
+            &client,
+            reqwest::Method::PUT,
+            &format!("_component_template/{}", component),
+        )
+        .json(&json!({ "template": template }))
+        .send()
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+        check_response(res, "uploading a component template")?;
+        let res = request(
+            &client,
+            reqwest::Method::PUT,
+            &format!("_index_template/{}", name),
+        )
+        .json(&json!({
+            "index_patterns": [wildcard],
+            "composed_of": [component],
+            "priority": 100,
+            "_meta": { "managed_by": "ulp" },
+        }))
+        .send()
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+        check_response(res, "uploading an index template")?;
     }
-    // }
     Ok(())
 }
-//
-fn as_elastic_map(map: &TypeMap) -> String {
-    return format!("{{\"mappings\":{}}}", recurse(map));
-    fn recurse(t: &TypeMap) -> String {
+
+fn field_type(v: &serde_json::Value) -> &str {
+    match v.get("type").and_then(|t| t.as_str()) {
+        Some(t) => t,
+        None if v.get("properties").is_some() => "object",
+        None => "",
+    }
+}
+
+// The fields of `new` that `existing` doesn't map yet, as properties of an Elastic mapping.
+// Fields `existing` maps with another type are left out.
+pub fn new_fields(
+    existing: &serde_json::Value,
+    new: &serde_json::Value,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut added = serde_json::Map::new();
+    let mapped = existing.get("properties").and_then(|p| p.as_object());
+    let properties = new.get("properties").and_then(|p| p.as_object());
+    for (key, field) in properties.into_iter().flatten() {
+        match mapped.and_then(|m| m.get(key)) {
+            None => {
+                added.insert(key.to_string(), field.clone());
+            }
+            Some(current) if field_type(current) == field_type(field) => {
+                let nested = new_fields(current, field);
+                if !nested.is_empty() {
+                    added.insert(key.to_string(), json!({ "properties": nested }));
+                }
+            }
+            Some(_) => (),
+        }
+    }
+    added
+}
+
+// Add the fields of `new` to the mapping `existing`, keeping every field it already maps
+pub fn grow(existing: &mut serde_json::Value, new: &serde_json::Value) {
+    if !existing["properties"].is_object() {
+        existing["properties"] = json!({});
+    }
+    for (key, field) in new_fields(existing, new) {
+        match existing["properties"].get_mut(&key) {
+            Some(current) => grow(current, &field),
+            None => existing["properties"][&key] = field,
+        }
+    }
+}
+
+// Dotted paths of fields whose type differs between two Elastic mappings
+pub fn mapping_conflicts(existing: &serde_json::Value, new: &serde_json::Value) -> Vec<String> {
+    let mut conflicts = Vec::new();
+    recurse("", existing, new, &mut conflicts);
+    return conflicts;
+    fn recurse(
+        path: &str,
+        existing: &serde_json::Value,
+        new: &serde_json::Value,
+        conflicts: &mut Vec<String>,
+    ) {
+        let (existing, new) = match (
+            existing.get("properties").and_then(|p| p.as_object()),
+            new.get("properties").and_then(|p| p.as_object()),
+        ) {
+            (Some(e), Some(n)) => (e, n),
+            _ => return,
+        };
+        for (key, new_field) in new {
+            if let Some(existing_field) = existing.get(key) {
+                let child = match path.is_empty() {
+                    true => key.to_string(),
+                    false => format!("{}.{}", path, key),
+                };
+                if field_type(existing_field) != field_type(new_field) {
+                    conflicts.push(child);
+                } else {
+                    recurse(&child, existing_field, new_field, conflicts);
+                }
+            }
+        }
+    }
+}
+// The Elastic field type a type map entry is indexed as
+pub fn elastic_type(t: &TypeMap) -> &'static str {
+    use TypeMap::*;
+    match t {
+        Null => "keyword",
+        Bool => "boolean",
+        Int => "long",
+        Float => "double",
+        IPv4 | IPv6 => "ip",
+        Date => "date",
+        Str | List(_) => "text",
+        Object(_) => "object",
+    }
+}
+// Limits applied when generating an Elastic mapping, defaults match Elastic's own
+#[derive(Debug, Clone)]
+pub struct MappingLimits {
+    pub total_fields: usize,
+    pub depth: usize,
+    // Objects with more leaf fields than this are mapped as a single flattened field
+    pub flattened_width: usize,
+}
+
+lazy_static! {
+    pub static ref MAPPING_LIMITS: MappingLimits = MappingLimits {
+        total_fields: crate::env_usize("ELASTIC_TOTAL_FIELDS_LIMIT", 1000),
+        depth: crate::env_usize("ELASTIC_DEPTH_LIMIT", 20),
+        flattened_width: crate::env_usize("ELASTIC_FLATTENED_WIDTH", 250),
+    };
+}
+
+// Generates the index settings and mappings for a type map
+pub fn as_elastic_map(
+    map: &TypeMap,
+    overrides: &Overrides,
+    index: &str,
+    limits: &MappingLimits,
+) -> serde_json::Value {
+    let mut mappings = match map {
+        TypeMap::Object(m) => {
+            json!({ "properties": properties(m, overrides, index, "", 1, limits) })
+        }
+        _ => json!({ "properties": {} }),
+    };
+    // Collapse the widest objects until the mapping fits within the total fields limit
+    while count_fields(&mappings) > limits.total_fields {
+        if !flatten_widest(&mut mappings) {
+            break;
+        }
+    }
+    return json!({
+        "settings": {
+            "index.mapping.total_fields.limit": limits.total_fields,
+            "index.mapping.depth.limit": limits.depth,
+        },
+        "mappings": mappings,
+    });
+    fn properties(
+        m: &BTreeMap<String, TypeMap>,
+        overrides: &Overrides,
+        index: &str,
+        path: &str,
+        depth: usize,
+        limits: &MappingLimits,
+    ) -> serde_json::Value {
+        let mut props = serde_json::Map::new();
+        for (k, v) in m {
+            let path = match path.is_empty() {
+                true => k.to_string(),
+                false => format!("{}.{}", path, k),
+            };
+            let field = recurse(v, overrides, index, &path, depth, limits);
+            insert_dotted(&mut props, k, field);
+        }
+        serde_json::Value::Object(props)
+    }
+    fn recurse(
+        t: &TypeMap,
+        overrides: &Overrides,
+        index: &str,
+        path: &str,
+        depth: usize,
+        limits: &MappingLimits,
+    ) -> serde_json::Value {
         use TypeMap::*;
+        // User supplied mapping fragments replace the inferred mapping for that field
+        if let Some(fragment) = overrides.get(Some(index), path).and_then(|o| o.elastic()) {
+            return fragment.clone();
+        }
         match t {
-            Null => String::from("{\"type\": \"keyword\",\"null_value\": \"NULL\"}"),
-            Bool => String::from("{\"type\": \"boolean\"}"),
-            // UnsignedInteger => String::from("{\"type\": \"unsigned_long\"}"),
-            Int => String::from("{\"type\": \"long\"}"),
-            Float => String::from("{\"type\": \"double\"}"),
-            IPv4 => String::from("{\"type\": \"ip\"}"),
-            IPv6 => String::from("{\"type\": \"ip\"}"),
-            Date => String::from("{\"type\": \"date\", \"format\": \"yyyy-MM-dd HH:mm:ss||yyyy-MM-dd||epoch_millis||date_optional_time||basic_ordinal_date_time\"}"),
-            Str => String::from("{\"type\": \"text\", \"fields\": {\"keyword\": {\"type\": \"keyword\", \"ignore_above\": 256}}}"),
-            List(_) => {
-                String::from("{\"type\": \"text\", \"fields\": {\"keyword\": {\"type\": \"keyword\", \"ignore_above\": 256}}}")
+            Null => json!({"type": "keyword", "null_value": "NULL"}),
+            Date => {
+                json!({"type": "date", "format": "yyyy-MM-dd HH:mm:ss||yyyy-MM-dd||epoch_millis||date_optional_time||basic_ordinal_date_time"})
+            }
+            Str | List(_) => {
+                json!({"type": "text", "fields": {"keyword": {"type": "keyword", "ignore_above": 256}}})
+            }
+            Object(m) => match depth >= limits.depth || leaf_count(t) > limits.flattened_width {
+                true => json!({"type": "flattened"}),
+                false => {
+                    json!({ "properties": properties(m, overrides, index, path, depth + 1, limits) })
+                }
             },
-            Object(ref m) => {
-                let mut parts: Vec<String> = Vec::new();
-                for (k, v) in m {
-                    parts.push(format!("\"{}\": {} ", k, recurse(v)));
-                    // map.insert(k.to_string(), String::new(format!("{{\"properties\":{}}}", recurse(v.clone()))));
+            _ => json!({ "type": elastic_type(t) }),
+        }
+    }
+    fn leaf_count(t: &TypeMap) -> usize {
+        match t {
+            TypeMap::Object(m) => m.values().map(leaf_count).sum(),
+            _ => 1,
+        }
+    }
+}
+
+// Elastic expands dotted field names into objects, so do the same when building the mapping
+fn insert_dotted(
+    props: &mut serde_json::Map<String, serde_json::Value>,
+    key: &str,
+    field: serde_json::Value,
+) {
+    let parts = key.split('.').filter(|p| !p.is_empty()).collect::<Vec<_>>();
+    match parts.split_first() {
+        None => warn!("Skipping field with an empty name in mapping: {:?}", key),
+        Some((name, [])) => match props.get_mut(*name) {
+            None => {
+                props.insert(name.to_string(), field);
+            }
+            Some(existing) => match (
+                existing
+                    .get_mut("properties")
+                    .and_then(|p| p.as_object_mut()),
+                field.get("properties").and_then(|p| p.as_object()),
+            ) {
+                (Some(existing), Some(new)) => {
+                    for (k, v) in new {
+                        insert_dotted(existing, k, v.clone());
+                    }
                 }
-                // let string = parts.join(",");
-                format!("{{\"properties\": {{{}}}}}", parts.join(","))
+                _ => warn!("Conflicting definitions for field {:?} in mapping", key),
             },
+        },
+        Some((name, rest)) => {
+            let parent = props
+                .entry(name.to_string())
+                .or_insert_with(|| json!({ "properties": {} }));
+            match parent.get_mut("properties").and_then(|p| p.as_object_mut()) {
+                Some(parent) => insert_dotted(parent, &rest.join("."), field),
+                None => warn!("Conflicting definitions for field {:?} in mapping", key),
+            }
+        }
+    }
+}
+
+// Number of fields as counted against index.mapping.total_fields.limit
+fn count_fields(v: &serde_json::Value) -> usize {
+    let mut count = 0;
+    for key in ["properties", "fields"] {
+        if let Some(props) = v.get(key).and_then(|p| p.as_object()) {
+            count += props.values().map(|p| 1 + count_fields(p)).sum::<usize>();
+        }
+    }
+    count
+}
+
+// Replace the object with the most direct children with a flattened field
+fn flatten_widest(mappings: &mut serde_json::Value) -> bool {
+    fn widest(v: &serde_json::Value, path: &mut Vec<String>, best: &mut (usize, Vec<String>)) {
+        if let Some(props) = v.get("properties").and_then(|p| p.as_object()) {
+            for (k, child) in props {
+                if let Some(width) = child
+                    .get("properties")
+                    .and_then(|p| p.as_object())
+                    .map(|p| p.len())
+                {
+                    path.push(k.clone());
+                    if width > best.0 {
+                        *best = (width, path.clone());
+                    }
+                    widest(child, path, best);
+                    path.pop();
+                }
+            }
         }
     }
+    let mut best = (0, Vec::new());
+    widest(mappings, &mut Vec::new(), &mut best);
+    if best.1.is_empty() {
+        return false;
+    }
+    let mut target = mappings;
+    for key in &best.1 {
+        target = &mut target["properties"][key];
+    }
+    *target = json!({"type": "flattened"});
+    true
 }
 
-pub fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
+// Reads compressed and rotated data files too
+pub fn read_lines<P>(filename: P) -> io::Result<crate::data_file::Lines>
 where
     P: AsRef<Path>,
 {
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+    crate::data_file::read_lines(filename)
+}
+
+// Stable document id so re-running an ingest overwrites rather than duplicates documents
+pub fn document_id(file_hash: &str, record: usize, parser: &crate::Parser) -> String {
+    use sha2::Digest;
+    let mut hash_digest = sha2::Sha256::new();
+    hash_digest.update(format!("{}:{}:{:?}", file_hash, record, parser));
+    format!("{:x}", hash_digest.finalize())
+}
+
+// A casted document ready to be sent with the bulk API
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BulkDocument {
+    pub index: String,
+    pub id: String,
+    pub document: serde_json::Value,
+}
+
+// A document Elastic refused, or that ran out of retries, written to the job's dead letter file
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DeadLetter {
+    #[serde(flatten)]
+    pub document: BulkDocument,
+    pub status: u16,
+    pub error: serde_json::Value,
+}
+
+// Bulk batches are cut at whichever limit is reached first
+#[derive(Debug, Clone)]
+pub struct BulkLimits {
+    pub max_bytes: usize,
+    pub max_docs: usize,
+    // In-flight bulk requests across all workers
+    pub concurrency: usize,
+    pub gzip: bool,
+}
+
+lazy_static! {
+    pub static ref BULK_LIMITS: BulkLimits = BulkLimits {
+        max_bytes: crate::env_usize("ELASTIC_BULK_MAX_BYTES", 10 * 1024 * 1024),
+        max_docs: crate::env_usize("ELASTIC_BULK_MAX_DOCS", 5000),
+        concurrency: crate::env_usize("ELASTIC_BULK_CONCURRENCY", 4),
+        gzip: crate::env_bool("ELASTIC_BULK_GZIP", true),
+    };
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .pool_max_idle_per_host(BULK_LIMITS.concurrency)
+        .build()
+        .expect("Failed to build Elastic client");
+    // Workers are plain threads, bulk requests are driven on this runtime
+    static ref RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
+        .thread_name("ulp-elastic")
+        .enable_all()
+        .build()
+        .expect("Failed to start Elastic runtime");
+    static ref BULK_PERMITS: tokio::sync::Semaphore =
+        tokio::sync::Semaphore::new(BULK_LIMITS.concurrency);
+}
+
+// Documents and their serialised action/source lines
+#[derive(Debug, Default)]
+pub struct Batch {
+    pub docs: Vec<BulkDocument>,
+    pub body: Vec<u8>,
+}
+
+impl Batch {
+    fn from_docs(docs: Vec<BulkDocument>) -> Result<Self, CustomError> {
+        let mut batch = Batch::default();
+        for doc in docs {
+            let lines = bulk_lines(&doc)?;
+            batch.body.extend(lines);
+            batch.docs.push(doc);
+        }
+        Ok(batch)
+    }
+}
+
+fn bulk_lines(doc: &BulkDocument) -> Result<Vec<u8>, CustomError> {
+    let mut lines = serde_json::to_vec(&json!({"index": {
+        "_index": sanitise_string_elastic(&doc.index),
+        "_id": doc.id,
+    }}))
+    .map_err(|e| CustomError::ElasticError(e.into()))?;
+    lines.push(b'\n');
+    serde_json::to_writer(&mut lines, &doc.document)
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+    lines.push(b'\n');
+    Ok(lines)
+}
+
+pub struct Batcher<'a> {
+    limits: &'a BulkLimits,
+    current: Batch,
+}
+
+impl<'a> Batcher<'a> {
+    pub fn new(limits: &'a BulkLimits) -> Self {
+        Batcher {
+            limits,
+            current: Batch::default(),
+        }
+    }
+    // Returns the previous batch when adding this document would take it over a limit, a single
+    // document larger than max_bytes is sent on its own
+    pub fn push(&mut self, doc: BulkDocument) -> Result<Option<Batch>, CustomError> {
+        let lines = bulk_lines(&doc)?;
+        let full = !self.current.docs.is_empty()
+            && (self.current.docs.len() >= self.limits.max_docs
+                || self.current.body.len() + lines.len() > self.limits.max_bytes);
+        let ready = match full {
+            true => Some(std::mem::take(&mut self.current)),
+            false => None,
+        };
+        self.current.body.extend(lines);
+        self.current.docs.push(doc);
+        Ok(ready)
+    }
+    pub fn finish(self) -> Option<Batch> {
+        match self.current.docs.is_empty() {
+            true => None,
+            false => Some(self.current),
+        }
+    }
+}
+
+pub fn dead_letter_path(job_id: uuid::Uuid) -> std::path::PathBuf {
+    crate::storage::STORAGE.dead_letter_path(job_id)
+}
+
+fn write_dead_letters(job_id: uuid::Uuid, failed: &[DeadLetter]) -> Result<(), CustomError> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let mut lines = String::new();
+    for letter in failed {
+        lines.push_str(
+            &serde_json::to_string(letter).map_err(|e| CustomError::ElasticError(e.into()))?,
+        );
+        lines.push('\n');
+    }
+    append_dead_letters(job_id, &lines)
+}
+
+fn append_dead_letters(job_id: uuid::Uuid, lines: &str) -> Result<(), CustomError> {
+    // Single write so concurrent workers appending to the same file don't interleave lines
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dead_letter_path(job_id))
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+    file.write_all(lines.as_bytes())
+        .map_err(|e| CustomError::ElasticError(e.into()))
+}
+
+fn set_refresh_interval(
+    indices: &[String],
+    interval: serde_json::Value,
+) -> Result<(), CustomError> {
+    let client = reqwest::blocking::Client::new();
+    for index in indices {
+        let res = request(
+            &client,
+            reqwest::Method::PUT,
+            &format!("{}/_settings", sanitise_string_elastic(index)),
+        )
+        .json(&json!({ "index": { "refresh_interval": interval } }))
+        .send()
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+        check_response(res, "updating the index refresh interval")?;
+    }
+    Ok(())
 }
 
+// Refreshes are disabled while a job is being sent, they are restored and run once at the end
+pub fn begin_ingest(indices: &[String]) -> Result<(), CustomError> {
+    set_refresh_interval(indices, json!("-1"))
+}
+
+pub fn finish_ingest(indices: &[String]) -> Result<(), CustomError> {
+    set_refresh_interval(indices, serde_json::Value::Null)?;
+    let client = reqwest::blocking::Client::new();
+    for index in indices {
+        let res = request(
+            &client,
+            reqwest::Method::POST,
+            &format!("{}/_refresh", sanitise_string_elastic(index)),
+        )
+        .send()
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+        check_response(res, "refreshing an index")?;
+    }
+    Ok(())
+}
+
+// Cast and send a parsed file, returns the number of documents written to the dead letter file.
+// Records that can't be read back or cast are dead lettered as they are met.
 pub fn normalise_then_send(
+    job_id: uuid::Uuid,
     map: Mapping,
-    data: PathBuf,
-    parser: &crate::Parser,
-) -> Result<(), CustomError> {
-    let mut buffer = Vec::with_capacity(1000);
-    let index_pattern: IndexPatternObject = parser.default_index_pattern().into();
-    for line in read_lines(data).map_err(|e| CustomError::ElasticError(e.into()))? {
-        let json = serde_json::from_str(&line.map_err(|e| CustomError::ElasticError(e.into()))?)
+    file: &ParsedFileStats,
+) -> Result<usize, CustomError> {
+    let unreadable = std::cell::Cell::new(0);
+    let docs = map
+        .casted_records(file)?
+        .enumerate()
+        .filter_map(|(record, res)| {
+            let id = document_id(&file.file_hash, record, &file.parser_used);
+            let (err, index, record) = match res {
+                Ok((index, document)) => {
+                    return Some(Ok(BulkDocument {
+                        index,
+                        id,
+                        document,
+                    }))
+                }
+                Err(CustomError::RecordError { err, index, record }) => (err, index, record),
+                Err(e) => return Some(Err(e)),
+            };
+            let letter = DeadLetter {
+                document: BulkDocument {
+                    index,
+                    id,
+                    document: record,
+                },
+                status: 0,
+                error: json!({"type": "record_error", "reason": err.to_string()}),
+            };
+            unreadable.set(unreadable.get() + 1);
+            write_dead_letters(job_id, &[letter]).err().map(Err)
+        });
+    let failed = ingest(job_id, docs)? + unreadable.get();
+    if failed > 0 {
+        warn!(
+            "{} documents from {} could not be ingested, see {}",
+            failed,
+            file.parsed_file_path.display(),
+            dead_letter_path(job_id).display()
+        );
+    }
+    Ok(failed)
+}
+
+// Re-send every document in a job's dead letter file, anything failing again is written back to it
+pub fn replay_dead_letters(job_id: uuid::Uuid) -> Result<(), CustomError> {
+    let replay_path = crate::storage::STORAGE.replay_path(job_id);
+    fs::rename(dead_letter_path(job_id), &replay_path)
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+    // Dead letters handed to ingest, the rest are put back if the replay fails
+    let taken = std::cell::Cell::new(0);
+    let docs = read_lines(&replay_path)
+        .map_err(|e| CustomError::ElasticError(e.into()))?
+        .map(|line| {
+            let letter = serde_json::from_str::<DeadLetter>(
+                &line.map_err(|e| CustomError::ElasticError(e.into()))?,
+            )
             .map_err(|e| CustomError::ElasticError(e.into()))?;
-        let data_pattern = index_pattern.generate_index_pattern(&json);
-        let json = map.cast_json(json, Some(&data_pattern))?;
-        buffer.push((data_pattern, json));
-        if buffer.len() == buffer.capacity() {
-            println!("{:?}", std::mem::size_of_val(&*buffer));
-            bulk_api(&mut buffer).map_err(|e| CustomError::ElasticError(e))?;
+            taken.set(taken.get() + 1);
+            Ok(letter.document)
+        });
+    let failed = match ingest(job_id, docs) {
+        Ok(failed) => failed,
+        Err(e) => {
+            restore_replay(job_id, &replay_path, taken.get())?;
+            return Err(e);
         }
+    };
+    info!(
+        "Replayed dead letters for job {}, {} documents failed again",
+        job_id, failed
+    );
+    fs::remove_file(&replay_path).map_err(|e| CustomError::ElasticError(e.into()))
+}
+
+// Append a replay file's dead letters after the first `skip` back to the dead letter file
+fn restore_replay(job_id: uuid::Uuid, replay_path: &Path, skip: usize) -> Result<(), CustomError> {
+    let mut lines = String::new();
+    for line in read_lines(replay_path)
+        .map_err(|e| CustomError::ElasticError(e.into()))?
+        .skip(skip)
+    {
+        lines.push_str(&line.map_err(|e| CustomError::ElasticError(e.into()))?);
+        lines.push('\n');
     }
-    if !buffer.is_empty() {
-        println!("{:?}", std::mem::size_of_val(&*buffer));
-        bulk_api(&mut buffer).map_err(|e| CustomError::ElasticError(e))?;
+    if !lines.is_empty() {
+        append_dead_letters(job_id, &lines)?;
     }
-    Ok(())
+    fs::remove_file(replay_path).map_err(|e| CustomError::ElasticError(e.into()))
 }
 
-pub fn bulk_api(
-    buffer: &mut Vec<(String, serde_json::Value)>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut req_body = String::new();
-    let mut req_by_uuid = BTreeMap::new();
-    for (pattern, json) in buffer.drain(..) {
-        // Bulk API + Elastic Drain
-        let uuid = uuid::Uuid::new_v4();
-        let index_obj = format!(
-            "{{\"index\" : {{ \"_index\" : \"{}\", \"_id\" : \"{}\"}} }}",
-            sanitise_string_elastic(&pattern),
-            uuid
-        );
-        //
-        let json_str = serde_json::to_string(&json)?; // Think I can get away with as_str()
-        req_body.push_str(&index_obj);
-        req_body.push('\n');
-        req_body.push_str(&json_str);
-        req_body.push('\n');
-        //
-        req_by_uuid.insert(uuid, (pattern, json_str));
+// Put back dead letters left aside by replays that were interrupted, ie. by a restart
+pub fn restore_replays() {
+    let pattern = crate::storage::STORAGE
+        .root()
+        .join("*")
+        .join("dead_letter.*.replay")
+        .to_string_lossy()
+        .to_string();
+    for path in glob::glob(&pattern).into_iter().flatten().flatten() {
+        let job_id = match path
+            .parent()
+            .and_then(|dir| dir.file_name())
+            .and_then(|name| uuid::Uuid::parse_str(&name.to_string_lossy()).ok())
+        {
+            Some(job_id) => job_id,
+            None => continue,
+        };
+        match restore_replay(job_id, &path, 0) {
+            Ok(()) => info!("Restored interrupted dead letter replay {}", path.display()),
+            Err(e) => error!("{}", e),
+        }
     }
-    let client = reqwest::blocking::Client::new();
-    loop {
-        let res_raw = client
-            .post("http://0.0.0.0:9200/_bulk?refresh=wait_for")
-            .basic_auth("elastic", Some("changeme"))
-            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson")
-            .body(req_body.clone())
-            .send()?;
-        // .json()?;
-        let text: serde_json::Value = res_raw.json()?;
-        let res: response::BulkResponse = match serde_json::from_value(text.clone()) {
-            Ok(r) => r,
+}
+
+// Batch and send documents, failures are written to the job's dead letter file. Each batch is sent
+// by a task on RUNTIME while the next is built, up to BULK_LIMITS.concurrency of them at once.
+// Returns how many documents failed.
+pub fn ingest<I>(job_id: uuid::Uuid, docs: I) -> Result<usize, CustomError>
+where
+    I: Iterator<Item = Result<BulkDocument, CustomError>>,
+{
+    // The task's error is carried as a string, CustomError can't be sent between threads
+    let send =
+        |batch: Batch| RUNTIME.spawn(async { bulk_api(batch).await.map_err(|e| e.to_string()) });
+    let mut batcher = Batcher::new(&BULK_LIMITS);
+    let mut in_flight = FuturesUnordered::new();
+    let mut failed = 0;
+    let mut error = None;
+    for doc in docs {
+        let batch = match doc.and_then(|doc| batcher.push(doc)) {
+            Ok(Some(batch)) => batch,
+            Ok(None) => continue,
             Err(e) => {
-                println!("{:?}", e);
-                println!("{:?}", text.to_string());
-                // println!("{:?}", req_body.to_string());
-                return Err(e.into());
+                error = Some(e);
+                break;
             }
         };
-        match res.contains_errors() {
-            true => match res.has_bulk_rejection_errors() {
-                Some(errors) => {
-                    println!("Bulk rejection errors detected, retrying \n{:#?}", errors);
-                    std::thread::sleep(std::time::Duration::from_secs(1));
+        in_flight.push(send(batch));
+        if in_flight.len() >= BULK_LIMITS.concurrency {
+            if let Some(res) = RUNTIME.block_on(in_flight.next()) {
+                match record_failures(job_id, res) {
+                    Ok(n) => failed += n,
+                    Err(e) => {
+                        error = Some(e);
+                        break;
+                    }
                 }
-                None => break,
-            },
-            false => break,
+            }
         }
     }
-    Ok(())
+    // Documents already batched are still sent, and every batch in flight is waited for so their
+    // failures reach the dead letter file whether or not ingest stopped early
+    if let Some(batch) = batcher.finish() {
+        in_flight.push(send(batch));
+    }
+    while let Some(res) = RUNTIME.block_on(in_flight.next()) {
+        match record_failures(job_id, res) {
+            Ok(n) => failed += n,
+            Err(e) => error = error.or(Some(e)),
+        }
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(failed),
+    }
+}
+
+fn record_failures(
+    job_id: uuid::Uuid,
+    res: Result<Result<Vec<DeadLetter>, String>, tokio::task::JoinError>,
+) -> Result<usize, CustomError> {
+    let failed = res
+        .map_err(|e| CustomError::ElasticError(e.into()))?
+        .map_err(|e| CustomError::ElasticError(e.into()))?;
+    write_dead_letters(job_id, &failed)?;
+    Ok(failed.len())
+}
+
+pub(crate) fn compress(body: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 8), Compression::fast());
+    encoder.write_all(&body)?;
+    encoder.finish()
+}
+
+// Sends a batch, retrying rejected (429/503) items with backoff. Returns the documents that
+// failed outright or exhausted the retry budget.
+pub async fn bulk_api(batch: Batch) -> Result<Vec<DeadLetter>, CustomError> {
+    let mut batch = batch;
+    let mut failed = Vec::new();
+    let mut attempt = 0;
+    while !batch.docs.is_empty() {
+        let mut req = async_request(reqwest::Method::POST, "_bulk")
+            .header(reqwest::header::CONTENT_TYPE, "application/x-ndjson");
+        let body = std::mem::take(&mut batch.body);
+        req = match BULK_LIMITS.gzip {
+            true => req
+                .header(reqwest::header::CONTENT_ENCODING, "gzip")
+                .body(compress(body).map_err(|e| CustomError::ElasticError(e.into()))?),
+            false => req.body(body),
+        };
+        let res = {
+            let _permit = BULK_PERMITS
+                .acquire()
+                .await
+                .map_err(|e| CustomError::ElasticError(e.into()))?;
+            match req.send().await {
+                Err(e) => Err(e.to_string()),
+                Ok(res) if response::is_retryable(res.status().as_u16()) => {
+                    Err(format!("HTTP {}", res.status()))
+                }
+                Ok(res) => res
+                    .json::<response::BulkResponse>()
+                    .await
+                    .map_err(|e| e.to_string()),
+            }
+        };
+        let pending = std::mem::take(&mut batch.docs);
+        let retry = match res {
+            // Transport level failure, the whole request is retried
+            Err(e) => {
+                warn!("Bulk request failed (attempt {}): {}", attempt + 1, e);
+                let reason = json!({"type": "transport_error", "reason": e});
+                pending
+                    .into_iter()
+                    .map(|doc| (doc, 0, reason.clone()))
+                    .collect::<Vec<_>>()
+            }
+            // Items can't be matched to documents, none are known to have been indexed
+            Ok(res) if res.items.len() != pending.len() => {
+                let reason = format!(
+                    "Bulk response contained {} items for {} documents",
+                    res.items.len(),
+                    pending.len()
+                );
+                error!("{}", reason);
+                let error = json!({"type": "bulk_response_mismatch", "reason": reason});
+                failed.extend(pending.into_iter().map(|document| DeadLetter {
+                    document,
+                    status: 0,
+                    error: error.clone(),
+                }));
+                break;
+            }
+            Ok(res) => {
+                let mut retry = Vec::new();
+                for (doc, item) in pending.into_iter().zip(res.items) {
+                    let item = item.into_inner();
+                    match item.error {
+                        None => (),
+                        Some(error) if response::is_retryable(item.status) => {
+                            retry.push((doc, item.status, error))
+                        }
+                        Some(error) => failed.push(DeadLetter {
+                            document: doc,
+                            status: item.status,
+                            error,
+                        }),
+                    }
+                }
+                retry
+            }
+        };
+        if retry.is_empty() {
+            break;
+        }
+        if attempt >= RETRY_POLICY.max_retries {
+            warn!(
+                "Retry budget exhausted, {} documents sent to dead letter file",
+                retry.len()
+            );
+            failed.extend(
+                retry
+                    .into_iter()
+                    .map(|(document, status, error)| DeadLetter {
+                        document,
+                        status,
+                        error,
+                    }),
+            );
+            break;
+        }
+        let delay = RETRY_POLICY.backoff(attempt);
+        debug!("Retrying {} rejected documents in {:?}", retry.len(), delay);
+        tokio::time::sleep(delay).await;
+        batch = Batch::from_docs(retry.into_iter().map(|(doc, _, _)| doc).collect())?;
+        attempt += 1;
+    }
+    Ok(failed)
 }
 
 pub fn sanitise_string_elastic(source: &str) -> String {
@@ -182,97 +1012,43 @@ pub fn sanitise_string_elastic(source: &str) -> String {
 }
 
 mod response {
-    use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
-    #[derive(Debug, Deserialize, Clone)]
+    use serde::{Deserialize, Serialize};
+    #[derive(Debug, Deserialize, Serialize, Clone)]
     pub struct BulkResponse {
         pub took: u64,
         pub errors: bool,
-        #[serde(skip_serializing)]
         pub items: Vec<BulkItem>,
     }
-    impl BulkResponse {
-        pub fn contains_errors(&self) -> bool {
-            self.errors
-        }
-        pub fn errored_items(&self) -> Vec<BulkItem> {
-            self.items
-                .iter()
-                .filter(|i| match i {
-                    BulkItem::Index {
-                        error,
-                        index: _,
-                        result: _,
-                        status: _,
-                    } => error.is_some(),
-                })
-                .cloned()
-                .collect::<Vec<BulkItem>>()
-        }
-        pub fn has_bulk_rejection_errors(&self) -> Option<Vec<BulkItem>> {
-            let errors = self
-                .items
-                .iter()
-                .filter(|i| match i {
-                    BulkItem::Index {
-                        error,
-                        index: _,
-                        result: _,
-                        status: _,
-                    } => match error {
-                        Some(e) => e.is_bulk_rejection(),
-                        None => false,
-                    },
-                })
-                .cloned()
-                .collect::<Vec<BulkItem>>();
-            match errors.is_empty() {
-                true => None,
-                false => Some(errors),
-            }
-        }
-    }
 
     #[derive(Debug, Deserialize, Serialize, Clone)]
     pub enum BulkItem {
         #[serde(rename = "index")]
-        Index {
-            #[serde(rename = "_index")]
-            index: String,
-            result: String,
-            status: u64,
-            error: Option<BulkError>,
-        },
+        Index(BulkItemResult),
     }
+    impl BulkItem {
+        pub fn into_inner(self) -> BulkItemResult {
+            match self {
+                BulkItem::Index(result) => result,
+            }
+        }
+    }
+
     #[derive(Debug, Deserialize, Serialize, Clone)]
-    pub struct BulkError {
-        pub r#type: String,
-        pub reason: String,
-        pub index_uuid: String,
-        pub shard: u64,
+    pub struct BulkItemResult {
+        #[serde(rename = "_index")]
         pub index: String,
+        #[serde(rename = "_id", default)]
+        pub id: String,
+        #[serde(default)]
+        pub result: Option<String>,
+        pub status: u16,
+        #[serde(default)]
+        pub error: Option<serde_json::Value>,
     }
-    impl BulkError {
-        pub fn is_bulk_rejection(&self) -> bool {
-            self.reason == "es_rejected_execution_exception"
-        }
-        pub fn _resource_already_exists_exception(&self) -> bool {
-            self.reason == "resource_already_exists_exception"
-        }
-    }
-    impl Serialize for BulkResponse {
-        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            // 3 is the number of fields in the struct.
-            let mut state = serializer.serialize_struct("BulkResponse", 4)?;
-            state.serialize_field("took", &self.took)?;
-            state.serialize_field("errors", &self.errors)?;
-            state.serialize_field("items", &self.items.len())?;
-            let errored = self.errored_items();
-            state.serialize_field("error_items", &errored)?;
-            state.end()
-        }
+
+    // Too many requests (es_rejected_execution_exception) or unavailable, worth retrying
+    pub fn is_retryable(status: u16) -> bool {
+        matches!(status, 429 | 503)
     }
 }
 // mod mapping {
@@ -310,3 +1086,169 @@ mod response {
 //         }
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::MappingLimits;
+    use crate::overrides::Overrides;
+    use crate::Parser;
+    use serde_json::json;
+    use type_casting::Types;
+    #[test]
+    fn as_elastic_map_test() {
+        let limits = MappingLimits {
+            total_fields: 1000,
+            depth: 20,
+            flattened_width: 3,
+        };
+        let data = json!({
+            "a.b": 1,
+            "a": {"c": "x"},
+            "q\"uote": true,
+            "wide": {"1": 1, "2": 2, "3": 3, "4": 4}
+        });
+        let map =
+            super::as_elastic_map(&Types::get_type(&data), &Overrides::default(), "i", &limits);
+        assert_eq!(
+            map["mappings"]["properties"]["a"]["properties"]["b"],
+            json!({"type": "long"})
+        );
+        assert_eq!(
+            map["mappings"]["properties"]["a"]["properties"]["c"]["type"],
+            json!("text")
+        );
+        assert_eq!(
+            map["mappings"]["properties"]["q\"uote"],
+            json!({"type": "boolean"})
+        );
+        assert_eq!(
+            map["mappings"]["properties"]["wide"],
+            json!({"type": "flattened"})
+        );
+        assert_eq!(
+            map["settings"]["index.mapping.total_fields.limit"],
+            json!(1000)
+        );
+    }
+    #[test]
+    fn total_fields_limit() {
+        let limits = MappingLimits {
+            total_fields: 5,
+            depth: 20,
+            flattened_width: 100,
+        };
+        let data = json!({"a": {"b": 1, "c": 2, "d": 3}, "e": {"f": 1}});
+        let map =
+            super::as_elastic_map(&Types::get_type(&data), &Overrides::default(), "i", &limits);
+        assert_eq!(
+            map["mappings"]["properties"]["a"],
+            json!({"type": "flattened"})
+        );
+        assert!(super::count_fields(&map["mappings"]) <= 5);
+    }
+    #[test]
+    fn document_id_test() {
+        let id = super::document_id("abc", 1, &Parser::Evtx);
+        assert_eq!(id, super::document_id("abc", 1, &Parser::Evtx));
+        assert_ne!(id, super::document_id("abc", 2, &Parser::Evtx));
+        assert_ne!(id, super::document_id("abc", 1, &Parser::Mft));
+        assert_ne!(id, super::document_id("abd", 1, &Parser::Evtx));
+    }
+    #[test]
+    fn batcher_test() {
+        let limits = super::BulkLimits {
+            max_bytes: 200,
+            max_docs: 3,
+            concurrency: 1,
+            gzip: false,
+        };
+        let doc = |i: usize, s: &str| super::BulkDocument {
+            index: "a".into(),
+            id: i.to_string(),
+            document: json!({ "s": s }),
+        };
+        let mut batcher = super::Batcher::new(&limits);
+        let mut batches = Vec::new();
+        // Count limit
+        for i in 0..4 {
+            batches.extend(batcher.push(doc(i, "x")).unwrap());
+        }
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].docs.len(), 3);
+        // Byte limit, an oversized document still gets a batch of its own
+        batches.extend(batcher.push(doc(4, &"x".repeat(300))).unwrap());
+        batches.extend(batcher.push(doc(5, "x")).unwrap());
+        batches.extend(batcher.finish());
+        assert_eq!(
+            batches.iter().map(|b| b.docs.len()).collect::<Vec<_>>(),
+            vec![3, 1, 1, 1]
+        );
+        assert!(batches[2].body.len() > limits.max_bytes);
+        assert!(batches
+            .iter()
+            .all(|b| b.body.iter().filter(|c| **c == b'\n').count() == b.docs.len() * 2));
+    }
+    #[test]
+    fn bulk_response_test() {
+        let res: super::response::BulkResponse = serde_json::from_value(json!({
+            "took": 1,
+            "errors": true,
+            "items": [
+                {"index": {"_index": "a", "_id": "1", "result": "created", "status": 201}},
+                {"index": {"_index": "a", "_id": "2", "status": 429,
+                    "error": {"type": "es_rejected_execution_exception", "reason": "queue full"}}}
+            ]
+        }))
+        .unwrap();
+        let items = res
+            .items
+            .into_iter()
+            .map(|i| i.into_inner())
+            .collect::<Vec<_>>();
+        assert!(items[0].error.is_none());
+        assert!(super::response::is_retryable(items[1].status));
+    }
+    #[test]
+    fn mapping_conflicts_test() {
+        let existing = json!({"properties": {
+            "a": {"type": "long"},
+            "b": {"properties": {"c": {"type": "keyword"}, "d": {"type": "ip"}}},
+            "e": {"type": "text"}
+        }});
+        let new = json!({"properties": {
+            "a": {"type": "text"},
+            "b": {"properties": {"c": {"type": "keyword"}, "d": {"type": "text"}}},
+            "e": {"properties": {"f": {"type": "long"}}},
+            "g": {"type": "long"}
+        }});
+        assert_eq!(
+            super::mapping_conflicts(&existing, &new),
+            vec!["a", "b.d", "e"]
+        );
+    }
+    #[test]
+    fn grow_test() {
+        let mut existing = json!({"properties": {
+            "a": {"type": "long"},
+            "b": {"properties": {"c": {"type": "keyword"}}}
+        }});
+        let new = json!({"properties": {
+            "a": {"type": "text"},
+            "b": {"properties": {"c": {"type": "text"}, "d": {"type": "ip"}}},
+            "e": {"type": "long"}
+        }});
+        assert_eq!(
+            serde_json::Value::Object(super::new_fields(&existing, &new)),
+            json!({"b": {"properties": {"d": {"type": "ip"}}}, "e": {"type": "long"}})
+        );
+        super::grow(&mut existing, &new);
+        assert_eq!(
+            existing,
+            json!({"properties": {
+                "a": {"type": "long"},
+                "b": {"properties": {"c": {"type": "keyword"}, "d": {"type": "ip"}}},
+                "e": {"type": "long"}
+            }})
+        );
+    }
+}
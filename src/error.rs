@@ -13,6 +13,31 @@ pub enum CustomError {
         path: PathBuf,
     },
     StatGenerationError(Box<dyn error::Error>),
+    SchemaError(Box<dyn error::Error>),
+    SinkError(Box<dyn error::Error>),
+    KibanaError(Box<dyn error::Error>),
+    SplunkError(Box<dyn error::Error>),
+    SqliteError(Box<dyn error::Error>),
+    MongoError(Box<dyn error::Error>),
+    PostgresError(Box<dyn error::Error>),
+    ClickHouseError(Box<dyn error::Error>),
+    SyslogError(Box<dyn error::Error>),
+    StorageError(Box<dyn error::Error>),
+    ArchiveError(Box<dyn error::Error>),
+    WatchError(Box<dyn error::Error>),
+    UploadError(Box<dyn error::Error>),
+    ExportError(Box<dyn error::Error>),
+    JobLoadError {
+        err: Box<dyn error::Error>,
+        job_id: uuid::Uuid,
+    },
+    // One parsed record that couldn't be read back or cast, kept with its index pattern (empty
+    // when the record isn't JSON) so it can be dead lettered while the rest of its file is sent
+    RecordError {
+        err: Box<dyn error::Error>,
+        index: String,
+        record: serde_json::Value,
+    },
 }
 impl std::error::Error for CustomError {}
 
@@ -53,6 +78,102 @@ impl fmt::Display for CustomError {
                     e
                 )
             }
+            CustomError::SchemaError(e) => {
+                write!(
+                    f,
+                    "SchemaError (Failed to load, apply or compare a schema): {}",
+                    e
+                )
+            }
+            CustomError::SinkError(e) => {
+                write!(
+                    f,
+                    "SinkError (Failed to send a job to an output sink): {}",
+                    e
+                )
+            }
+            CustomError::KibanaError(e) => {
+                write!(
+                    f,
+                    "KibanaError (Failed to provision Kibana data views or saved objects): {}",
+                    e
+                )
+            }
+            CustomError::SplunkError(e) => {
+                write!(
+                    f,
+                    "SplunkError (Failed to send events to the Splunk HTTP Event Collector): {}",
+                    e
+                )
+            }
+            CustomError::SqliteError(e) => {
+                write!(
+                    f,
+                    "SqliteError (Failed to write a job's records to its SQLite database): {}",
+                    e
+                )
+            }
+            CustomError::MongoError(e) => {
+                write!(
+                    f,
+                    "MongoError (Failed to write a job's records to MongoDB): {}",
+                    e
+                )
+            }
+            CustomError::PostgresError(e) => {
+                write!(
+                    f,
+                    "PostgresError (Failed to write a job's records to PostgreSQL): {}",
+                    e
+                )
+            }
+            CustomError::ClickHouseError(e) => {
+                write!(
+                    f,
+                    "ClickHouseError (Failed to write a job's records to ClickHouse): {}",
+                    e
+                )
+            }
+            CustomError::SyslogError(e) => {
+                write!(
+                    f,
+                    "SyslogError (Failed to forward records over syslog): {}",
+                    e
+                )
+            }
+            CustomError::StorageError(e) => {
+                write!(f, "StorageError (Failed to manage job storage): {}", e)
+            }
+            CustomError::ArchiveError(e) => {
+                write!(f, "ArchiveError (Failed to expand an archive): {}", e)
+            }
+            CustomError::WatchError(e) => {
+                write!(f, "WatchError (Failed to watch a folder): {}", e)
+            }
+            CustomError::UploadError(e) => {
+                write!(f, "UploadError (Failed to receive an upload): {}", e)
+            }
+            CustomError::ExportError(e) => {
+                write!(
+                    f,
+                    "ExportError (Failed to export a job's records to files): {}",
+                    e
+                )
+            }
+            CustomError::JobLoadError { err, job_id } => {
+                write!(
+                    f,
+                    "JobLoadError (Failed to load a completed job's mappings file), for job {}: {}",
+                    job_id, err
+                )
+            }
+            CustomError::RecordError { err, index, .. } => {
+                write!(
+                    f,
+                    "RecordError (Failed to read back or cast a parsed record), for index {}: {}",
+                    index, err
+                )
+            }
             CustomError::TaskCreationError { err, job_id, path } => {
                 write!(
                     f,
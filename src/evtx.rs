@@ -2,35 +2,32 @@
 use evtx::ParserSettings;
 use std::{
     convert::TryFrom,
-    fs::{self, OpenOptions},
-    io::Write,
     sync::{Arc, Mutex},
 };
 type EvtxParser = evtx::EvtxParser<std::fs::File>;
-use crate::{error::CustomError, job::Task, type_map::IndexPatternObject, type_map::Mapping};
+use crate::{
+    collection::Collection,
+    data_file::{DataWriter, DATA_CONFIG},
+    error::CustomError,
+    job::Task,
+    storage::STORAGE,
+    type_map::IndexPatternObject,
+    type_map::Mapping,
+};
 
 pub struct Parser {
     pub parser: EvtxParser,
     //
-    data_file: fs::File,
+    data_file: DataWriter,
     mapping_ref: Arc<Mutex<Mapping>>,
+    collection: Option<Collection>,
 }
 
 impl TryFrom<&Task> for Parser {
     type Error = CustomError;
     fn try_from(task: &Task) -> Result<Self, Self::Error> {
-        std::fs::create_dir_all(format!("{}/{}/", crate::UPLOAD_DIR_ENV, task.job_id))
-            .map_err(|e| CustomError::ParserRunError(e.into()))?;
-        let data_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(format!(
-                "{}/{}/{}.data",
-                crate::UPLOAD_DIR_ENV,
-                task.job_id,
-                task.id
-            ))
+        STORAGE.create_job_dir(task.job_id)?;
+        let data_file = DataWriter::create(STORAGE.data_path(task.job_id, task.id), &DATA_CONFIG)
             .map_err(|e| CustomError::ParserRunError(e.into()))?;
         let parser = evtx::EvtxParser::from_path(&task.path)
             .map_err(|e| CustomError::ParserRunError(e.into()))?
@@ -39,22 +36,33 @@ impl TryFrom<&Task> for Parser {
             parser,
             data_file,
             mapping_ref: task.mapping_ref.clone(),
+            collection: task.collection.clone(),
         })
     }
 }
 impl Parser {
     pub fn run(&mut self, pattern: IndexPatternObject) -> Result<(), CustomError> {
+        let ecs = self.mapping_ref.lock().map(|m| m.ecs).unwrap_or(false);
         //  Iterate over inner parser object
         for record in self.parser.records_json_value() {
             // Read in json object
             let json = record.map_err(|e| CustomError::ParserRunError(e.into()))?;
+            let json = match ecs {
+                true => crate::ecs::normalise(&crate::Parser::Evtx, json.data),
+                false => json.data,
+            };
+            let json = match &self.collection {
+                Some(collection) => crate::collection::tag(collection, json),
+                None => json,
+            };
             // Write to file
-            writeln!(&mut self.data_file, "{}", json.data)
+            self.data_file
+                .write_record(&json)
                 .map_err(|e| CustomError::ParserRunError(e.into()))?;
             // Generate type mapping
             match self.mapping_ref.lock() {
                 Ok(mut mapping) => {
-                    mapping.map_json(&json.data, &pattern);
+                    mapping.map_json(&json, &pattern);
                 }
                 Err(_err) => {
                     return Err(CustomError::ParserRunError(
@@ -63,6 +71,8 @@ impl Parser {
                 }
             }
         }
-        Ok(())
+        self.data_file
+            .finish()
+            .map_err(|e| CustomError::ParserRunError(e.into()))
     }
 }
@@ -0,0 +1,529 @@
+use crate::{
+    error::CustomError,
+    job::Job,
+    type_map::{skip_failed, Mapping},
+};
+use arrow::{
+    datatypes::{DataType, Field, Schema, TimeUnit},
+    json::reader::{Decoder, ReaderBuilder},
+};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use type_casting::{
+    schema::{flatten, flatten_value, list_item_type},
+    Types,
+};
+
+// Records buffered per index pattern before being written as a row group
+const BATCH_SIZE: usize = 8192;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Parquet,
+    Csv,
+    // Sleuthkit bodyfile of MFT records, for mactime
+    Bodyfile,
+}
+
+impl FromStr for ExportFormat {
+    type Err = CustomError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::from(s))
+            .map_err(|_| CustomError::ExportError(format!("Unknown export format {}", s).into()))
+    }
+}
+
+// Arrow type of a field of type_casting's Arrow schema, empty structs have no Parquet
+// representation and are dropped
+fn arrow_type(field: &Value) -> Option<DataType> {
+    let children = || {
+        field["children"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(arrow_field)
+    };
+    Some(match field["type"]["name"].as_str()? {
+        "null" => DataType::Null,
+        "bool" => DataType::Boolean,
+        "int" => DataType::Int64,
+        "floatingpoint" => DataType::Float64,
+        // Named zones need arrow's chrono-tz feature, an offset reads the same everywhere
+        "timestamp" => DataType::Timestamp(TimeUnit::Microsecond, Some("+00:00".into())),
+        "utf8" => DataType::Utf8,
+        "list" => DataType::List(Arc::new(children().next()?)),
+        "struct" => {
+            let fields = children().collect::<Vec<_>>();
+            if fields.is_empty() {
+                return None;
+            }
+            DataType::Struct(fields.into())
+        }
+        _ => return None,
+    })
+}
+
+fn arrow_field(field: &Value) -> Option<Field> {
+    Some(Field::new(
+        field["name"].as_str()?,
+        arrow_type(field)?,
+        true,
+    ))
+}
+
+// Arrow schema of an index pattern's mapping, nested objects are kept as structs
+pub fn arrow_schema(t: &Types) -> Schema {
+    let schema = type_casting::schema::arrow_schema(t);
+    let fields = schema["fields"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(arrow_field)
+        .collect::<Vec<_>>();
+    Schema::new(fields)
+}
+
+// Lists mixing types are exported as lists of strings, which the JSON decoder only reads strings
+// into. Their other items are written as JSON.
+fn stringify_mixed_lists(value: &mut Value, t: &Types) {
+    match (value, t) {
+        (Value::Object(fields), Types::Object(map)) => {
+            for (key, t) in map {
+                if let Some(value) = fields.get_mut(key) {
+                    stringify_mixed_lists(value, t);
+                }
+            }
+        }
+        (Value::Array(items), Types::List(map)) => {
+            let item_type = list_item_type(map);
+            for item in items {
+                match item_type {
+                    Types::Str if !item.is_string() && !item.is_null() => {
+                        *item = Value::String(item.to_string())
+                    }
+                    _ => stringify_mixed_lists(item, &item_type),
+                }
+            }
+        }
+        _ => (),
+    }
+}
+
+// Index patterns can contain anything a generated field value does
+fn file_name(pattern: &str, extension: &str) -> String {
+    let name = pattern
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect::<String>();
+    format!("{}.{}", name, extension)
+}
+
+// A file written per index pattern
+trait PatternFile: Sized {
+    const EXTENSION: &'static str;
+    fn create(path: PathBuf, t: &Types) -> Result<Self, CustomError>;
+    fn push(&mut self, record: Value) -> Result<(), CustomError>;
+    fn close(self) -> Result<PathBuf, CustomError>;
+}
+
+struct ParquetFile {
+    path: PathBuf,
+    t: Types,
+    decoder: Decoder,
+    writer: ArrowWriter<fs::File>,
+}
+
+impl PatternFile for ParquetFile {
+    const EXTENSION: &'static str = "parquet";
+    fn create(path: PathBuf, t: &Types) -> Result<Self, CustomError> {
+        let schema = Arc::new(arrow_schema(t));
+        let decoder = ReaderBuilder::new(schema.clone())
+            .with_batch_size(BATCH_SIZE)
+            .build_decoder()
+            .map_err(|e| CustomError::ExportError(e.into()))?;
+        let file = fs::File::create(&path).map_err(|e| CustomError::ExportError(e.into()))?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let writer = ArrowWriter::try_new(file, schema, Some(properties))
+            .map_err(|e| CustomError::ExportError(e.into()))?;
+        Ok(ParquetFile {
+            path,
+            t: t.clone(),
+            decoder,
+            writer,
+        })
+    }
+    fn push(&mut self, record: Value) -> Result<(), CustomError> {
+        let mut record = record;
+        stringify_mixed_lists(&mut record, &self.t);
+        self.decoder
+            .serialize(&[record])
+            .map_err(|e| CustomError::ExportError(e.into()))?;
+        match self.decoder.len() >= BATCH_SIZE {
+            true => self.flush(),
+            false => Ok(()),
+        }
+    }
+    fn close(mut self) -> Result<PathBuf, CustomError> {
+        self.flush()?;
+        self.writer
+            .close()
+            .map_err(|e| CustomError::ExportError(e.into()))?;
+        Ok(self.path)
+    }
+}
+
+impl ParquetFile {
+    fn flush(&mut self) -> Result<(), CustomError> {
+        if let Some(batch) = self
+            .decoder
+            .flush()
+            .map_err(|e| CustomError::ExportError(e.into()))?
+        {
+            self.writer
+                .write(&batch)
+                .map_err(|e| CustomError::ExportError(e.into()))?;
+        }
+        Ok(())
+    }
+}
+
+// Flattened columns, lists and non string values are written as JSON
+struct CsvFile {
+    path: PathBuf,
+    columns: Vec<String>,
+    writer: csv::Writer<fs::File>,
+}
+
+impl PatternFile for CsvFile {
+    const EXTENSION: &'static str = "csv";
+    fn create(path: PathBuf, t: &Types) -> Result<Self, CustomError> {
+        let columns = flatten(t).into_iter().map(|(c, _)| c).collect::<Vec<_>>();
+        let mut writer =
+            csv::Writer::from_path(&path).map_err(|e| CustomError::ExportError(e.into()))?;
+        writer
+            .write_record(&columns)
+            .map_err(|e| CustomError::ExportError(e.into()))?;
+        Ok(CsvFile {
+            path,
+            columns,
+            writer,
+        })
+    }
+    fn push(&mut self, record: Value) -> Result<(), CustomError> {
+        let mut values = flatten_value(record);
+        let row = self.columns.iter().map(|c| match values.remove(c) {
+            None | Some(Value::Null) => String::new(),
+            Some(Value::String(s)) => s,
+            Some(value) => value.to_string(),
+        });
+        self.writer
+            .write_record(row)
+            .map_err(|e| CustomError::ExportError(e.into()))
+    }
+    fn close(mut self) -> Result<PathBuf, CustomError> {
+        self.writer
+            .flush()
+            .map_err(|e| CustomError::ExportError(e.into()))?;
+        Ok(self.path)
+    }
+}
+
+// Write the casted records of every parsed file to `dir`, a file per index pattern except for
+// bodyfiles
+pub fn export(
+    mapping: &Mapping,
+    format: ExportFormat,
+    dir: impl AsRef<Path>,
+) -> Result<Vec<PathBuf>, CustomError> {
+    fs::create_dir_all(&dir).map_err(|e| CustomError::ExportError(e.into()))?;
+    match format {
+        ExportFormat::Parquet => per_pattern::<ParquetFile>(mapping, dir.as_ref()),
+        ExportFormat::Csv => per_pattern::<CsvFile>(mapping, dir.as_ref()),
+        ExportFormat::Bodyfile => bodyfile(mapping, dir.as_ref()).map(|path| vec![path]),
+    }
+}
+
+fn per_pattern<F: PatternFile>(mapping: &Mapping, dir: &Path) -> Result<Vec<PathBuf>, CustomError> {
+    let mut files: BTreeMap<String, F> = BTreeMap::new();
+    let mut failed = 0;
+    for file in &mapping.file_mapping {
+        for res in mapping.casted_records(file)? {
+            let (pattern, record) = match skip_failed(res, &mut failed)? {
+                Some(record) => record,
+                None => continue,
+            };
+            if !files.contains_key(&pattern) {
+                let t = mapping
+                    .index_pattern_mappings
+                    .get(&pattern)
+                    .ok_or_else(|| {
+                        CustomError::ExportError(
+                            format!("No mapping for index pattern {}", pattern).into(),
+                        )
+                    })?;
+                let path = dir.join(file_name(&pattern, F::EXTENSION));
+                files.insert(pattern.clone(), F::create(path, t)?);
+            }
+            files.get_mut(&pattern).unwrap().push(record)?;
+        }
+    }
+    if failed > 0 {
+        warn!(
+            "{} records could not be read back and were left out",
+            failed
+        );
+    }
+    files.into_values().map(F::close).collect()
+}
+
+// MFT timestamps written as the bodyfile's atime, mtime and crtime, the flattened entry has no
+// record change time so ctime is always 0. The $FILE_NAME times get a line of their own as
+// `fls -m` does.
+const BODYFILE_TIMES: [(&str, [&str; 3]); 2] = [
+    (
+        "",
+        [
+            "StandardInfoLastAccess",
+            "StandardInfoLastModified",
+            "StandardInfoCreated",
+        ],
+    ),
+    (
+        " ($FILE_NAME)",
+        [
+            "FileNameLastAccess",
+            "FileNameLastModified",
+            "FileNameCreated",
+        ],
+    ),
+];
+
+// Sleuthkit 3.x bodyfile line(s) of an MFT record:
+// MD5|name|inode|mode_as_string|UID|GID|size|atime|mtime|ctime|crtime
+fn bodyfile_lines(record: &Value) -> Vec<String> {
+    let time = |field: &str| {
+        record
+            .get(field)
+            .and_then(Value::as_str)
+            .and_then(|s| Types::str_date(s).ok())
+            .map(|date| date.timestamp())
+    };
+    let mut name = record
+        .get("FullPath")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    if record.get("IsDeleted").and_then(Value::as_bool) == Some(true) {
+        name.push_str(" (deleted)");
+    }
+    let mode = match record.get("IsADirectory").and_then(Value::as_bool) {
+        Some(true) => "d/drwxrwxrwx",
+        _ => "r/rrwxrwxrwx",
+    };
+    let inode = record
+        .get("EntryId")
+        .map(Value::to_string)
+        .unwrap_or_default();
+    let size = record
+        .get("FileSize")
+        .and_then(Value::as_u64)
+        .unwrap_or_default();
+    BODYFILE_TIMES
+        .iter()
+        .filter_map(|(suffix, fields)| {
+            let times = fields.map(time);
+            if times.iter().all(Option::is_none) {
+                return None;
+            }
+            let [atime, mtime, crtime] = times.map(Option::unwrap_or_default);
+            Some(format!(
+                "0|{}{}|{}|{}|0|0|{}|{}|{}|0|{}",
+                name, suffix, inode, mode, size, atime, mtime, crtime
+            ))
+        })
+        .collect()
+}
+
+// A single bodyfile for every MFT parsed by the job, other parsers are skipped
+fn bodyfile(mapping: &Mapping, dir: &Path) -> Result<PathBuf, CustomError> {
+    use std::io::Write;
+    let path = dir.join("timeline.bodyfile");
+    let mut writer = std::io::BufWriter::new(
+        fs::File::create(&path).map_err(|e| CustomError::ExportError(e.into()))?,
+    );
+    let mft = crate::Parser::Mft;
+    let mut failed = 0;
+    for file in mapping.file_mapping.iter().filter(|f| f.parser_used == mft) {
+        for res in mapping.casted_records(file)? {
+            let (_, record) = match skip_failed(res, &mut failed)? {
+                Some(record) => record,
+                None => continue,
+            };
+            // ECS normalised records keep the original under the parser's namespace
+            let record = match mapping.ecs {
+                true => record
+                    .get(crate::ecs::namespace(&mft))
+                    .cloned()
+                    .unwrap_or(record),
+                false => record,
+            };
+            for line in bodyfile_lines(&record) {
+                writeln!(writer, "{}", line).map_err(|e| CustomError::ExportError(e.into()))?;
+            }
+        }
+    }
+    if failed > 0 {
+        warn!(
+            "{} records could not be read back and were left out",
+            failed
+        );
+    }
+    writer
+        .flush()
+        .map_err(|e| CustomError::ExportError(e.into()))?;
+    Ok(path)
+}
+
+// Export a completed job into its export directory
+pub fn export_job(job: uuid::Uuid, format: ExportFormat) -> Result<Vec<PathBuf>, CustomError> {
+    let job = Job::load(job)?;
+    let mapping = job.mapping.lock().unwrap();
+    let dir = crate::storage::STORAGE.export_dir(job.id);
+    let paths = export(&mapping, format, &dir)?;
+    info!(
+        "Exported job {} as {:?} to {}",
+        job.id,
+        format,
+        dir.display()
+    );
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::type_map::ParsedFileStats;
+    use parquet::{file::reader::FileReader, file::serialized_reader::SerializedFileReader};
+    use serde_json::json;
+
+    #[test]
+    fn schema_test() {
+        let t = Types::get_type(&json!({
+            "a": 1,
+            "b": {"c": "2021-01-01T00:00:00Z", "d": {}},
+            "e": ["x", "y"]
+        }));
+        let schema = arrow_schema(&t);
+        assert_eq!(schema.field(0).data_type(), &DataType::Int64);
+        assert_eq!(
+            schema.field(1).data_type(),
+            &DataType::Struct(
+                vec![Field::new(
+                    "c",
+                    DataType::Timestamp(TimeUnit::Microsecond, Some("+00:00".into())),
+                    true
+                )]
+                .into()
+            )
+        );
+        assert_eq!(
+            schema.field(2).data_type(),
+            &DataType::List(Arc::new(Field::new("item", DataType::Utf8, true)))
+        );
+    }
+
+    #[test]
+    fn parquet_export() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let records = [
+            json!({"FullPath": "a", "Created": "2021-01-01T00:00:00Z", "Flags": {"Deleted": true}}),
+            json!({"FullPath": "b", "Created": "2021-01-01T00:00:01Z", "Flags": {"Deleted": false}}),
+            // Exported as a list of strings
+            json!({"FullPath": "c", "Names": ["c", 1, {"d": true}]}),
+        ];
+        let mut map = Mapping::default();
+        let pattern = map.index_pattern(&crate::Parser::Mft);
+        let parsed_file_path = dir.join("a.data");
+        let mut contents = String::new();
+        for record in &records {
+            map.map_json(record, &pattern);
+            contents.push_str(&format!("{}\n", record));
+        }
+        fs::write(&parsed_file_path, contents).unwrap();
+        map.file_mapping.push(ParsedFileStats {
+            parsed_file_path,
+            parser_used: crate::Parser::Mft,
+            ..Default::default()
+        });
+        let paths = export(&map, ExportFormat::Parquet, dir.join("export")).unwrap();
+        assert_eq!(paths, vec![dir.join("export").join("mft.parquet")]);
+        let reader = SerializedFileReader::new(fs::File::open(&paths[0]).unwrap()).unwrap();
+        let rows = reader.metadata().file_metadata().num_rows();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(rows, 3);
+    }
+
+    #[test]
+    fn csv_and_bodyfile_export() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(&dir).unwrap();
+        let record = json!({
+            "EntryId": 5,
+            "FileSize": 10,
+            "IsADirectory": false,
+            "IsDeleted": true,
+            "FullPath": "Windows/a.exe",
+            "StandardInfoCreated": "2021-01-01T00:00:00Z",
+            "StandardInfoLastModified": "2021-01-01T00:00:01Z",
+            "FileNameCreated": "2021-01-01T00:00:02Z",
+            "Names": ["a", "b"]
+        });
+        let mut map = Mapping::default();
+        let pattern = map.index_pattern(&crate::Parser::Mft);
+        map.map_json(&record, &pattern);
+        let parsed_file_path = dir.join("a.data");
+        fs::write(&parsed_file_path, format!("{}\n", record)).unwrap();
+        map.file_mapping.push(ParsedFileStats {
+            parsed_file_path,
+            parser_used: crate::Parser::Mft,
+            ..Default::default()
+        });
+        let csv = export(&map, ExportFormat::Csv, &dir).unwrap();
+        let body = export(&map, ExportFormat::Bodyfile, &dir).unwrap();
+        let csv = fs::read_to_string(&csv[0]).unwrap();
+        let body = fs::read_to_string(&body[0]).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            vec![
+                "EntryId,FileNameCreated,FileSize,FullPath,IsADirectory,IsDeleted,Names,StandardInfoCreated,StandardInfoLastModified",
+                r#"5,2021-01-01T00:00:02+00:00,10,Windows/a.exe,false,true,"[""a"",""b""]",2021-01-01T00:00:00+00:00,2021-01-01T00:00:01+00:00"#,
+            ]
+        );
+        assert_eq!(
+            body.lines().collect::<Vec<_>>(),
+            vec![
+                "0|Windows/a.exe (deleted)|5|r/rrwxrwxrwx|0|0|10|0|1609459201|0|1609459200",
+                "0|Windows/a.exe (deleted) ($FILE_NAME)|5|r/rrwxrwxrwx|0|0|10|0|0|0|1609459202",
+            ]
+        );
+    }
+}
@@ -1,7 +1,16 @@
-use crate::{error::CustomError, type_map::Mapping};
+use crate::{
+    archive::ArchiveMember,
+    collection::{Collection, Detector},
+    error::CustomError,
+    sink::{OnComplete, SinkRun},
+    storage::Storage,
+    type_map::Mapping,
+};
 use glob::glob;
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashMap,
+    fs,
     path::PathBuf,
     sync::{Arc, Mutex},
     time::Instant, // {io, io::prelude::*},
@@ -19,6 +28,44 @@ pub struct Job {
     pub processed: Vec<Task>,
     #[serde(with = "approx_instant")]
     pub completed: Instant,
+    #[serde(default)]
+    pub on_complete: Option<OnComplete>,
+    // Only set while files are being sent to the sink per file
+    #[serde(skip)]
+    pub sink_run: Option<Arc<SinkRun>>,
+    // Paths that were extracted from an archive, kept in their parsed file stats
+    #[serde(skip)]
+    pub archive_members: HashMap<PathBuf, ArchiveMember>,
+    // Paths found in a triage collection, their records are tagged with its host
+    #[serde(skip)]
+    pub collections: HashMap<PathBuf, Collection>,
+}
+
+// Body of POST /job, either a plain path glob or a glob with a follow on action, ie.
+// {"glob": "/forensic_data/**/*.evtx", "on_complete": {"sink": "elastic", "per_file": true}}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum JobSpec {
+    Glob(String),
+    Full {
+        glob: String,
+        #[serde(default)]
+        on_complete: Option<OnComplete>,
+    },
+}
+
+impl JobSpec {
+    pub fn glob(&self) -> &str {
+        match self {
+            JobSpec::Glob(glob) | JobSpec::Full { glob, .. } => glob,
+        }
+    }
+    pub fn on_complete(&self) -> Option<&OnComplete> {
+        match self {
+            JobSpec::Glob(_) => None,
+            JobSpec::Full { on_complete, .. } => on_complete.as_ref(),
+        }
+    }
 }
 
 mod approx_instant {
@@ -49,6 +96,18 @@ mod approx_instant {
 }
 
 impl Job {
+    // Read a completed job back from its mappings file
+    pub fn load(id: Uuid) -> Result<Self, CustomError> {
+        let path = crate::storage::STORAGE.mapping_path(id);
+        let contents = fs::read_to_string(&path).map_err(|e| CustomError::JobLoadError {
+            err: format!("Failed to read mapping file at {}. {}", path.display(), e).into(),
+            job_id: id,
+        })?;
+        serde_json::from_str(&contents).map_err(|e| CustomError::JobLoadError {
+            err: e.into(),
+            job_id: id,
+        })
+    }
     pub fn from_glob(path_glob: &str) -> Option<Self> {
         // Test file_path for parser_type
         let mut paths = Vec::new();
@@ -67,10 +126,45 @@ impl Job {
                 processed: Vec::new(),
                 status: Status::default(),
                 completed: Instant::now(),
-                mapping: Arc::new(Mutex::new(Mapping::default())),
+                mapping: Arc::new(Mutex::new(Mapping {
+                    ecs: *crate::ECS_NORMALISE,
+                    ..Mapping::with_overrides(crate::TYPE_OVERRIDES.clone())
+                })),
+                on_complete: None,
+                sink_run: None,
+                archive_members: HashMap::new(),
+                collections: HashMap::new(),
             }),
         }
     }
+    // Expand any archives among the job's paths into its directory, their members are parsed in
+    // their place, then detect the collections its files were found in. Archives can be large so
+    // this is left to a worker once the job's directory has been prepared.
+    pub fn expand(&mut self, storage: &Storage) {
+        let paths = std::mem::take(&mut self.paths);
+        let (paths, members) = crate::archive::expand_paths(storage, self.id, paths);
+        self.paths = paths;
+        self.archive_members = members.into_iter().collect();
+        let mut detector = Detector::default();
+        self.collections = self
+            .paths
+            .iter()
+            .filter_map(|path| {
+                let collection = detector.detect(path, self.archive_members.get(path))?;
+                Some((path.clone(), collection))
+            })
+            .collect();
+    }
+    pub fn from_spec(spec: &JobSpec) -> Option<Self> {
+        let mut job = Self::from_glob(spec.glob())?;
+        job.on_complete = spec.on_complete().cloned();
+        if let Some(on_complete) = &job.on_complete {
+            if on_complete.per_file {
+                job.sink_run = Some(SinkRun::new(job.id, on_complete.sink));
+            }
+        }
+        Some(job)
+    }
 }
 
 #[derive(serde::Serialize, Debug, Clone)]
@@ -78,6 +172,8 @@ pub struct Task {
     pub job_id: Uuid,
     pub id: Uuid,
     pub path: PathBuf,
+    pub archive: Option<ArchiveMember>,
+    pub collection: Option<Collection>,
     //
     #[serde(skip)]
     pub mapping_ref: Arc<Mutex<Mapping>>,
@@ -86,7 +182,14 @@ pub struct Task {
 impl Task {
     pub fn add_parsed_file_stats(&self, parser: crate::Parser) -> Result<(), CustomError> {
         let mut mapping = self.mapping_ref.lock().unwrap();
-        mapping.add_parsed_file(self.job_id, self.id, &self.path, parser)?;
+        mapping.add_parsed_file(
+            self.job_id,
+            self.id,
+            &self.path,
+            parser,
+            self.archive.clone(),
+            self.collection.clone(),
+        )?;
         Ok(())
     }
 }
@@ -111,6 +214,8 @@ impl Iterator for Job {
                 Some(Ok(Task {
                     job_id: self.id,
                     id: task_id,
+                    archive: self.archive_members.get(&path).cloned(),
+                    collection: self.collections.get(&path).cloned(),
                     path,
                     mapping_ref: self.mapping.clone(),
                 }))
@@ -123,7 +228,36 @@ impl Iterator for Job {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Status {
     Pending,
+    Running,
     Done,
+    Failed,
+}
+
+impl Status {
+    // Written to {job}/status.json as the job moves through the orchestrator
+    pub fn save(&self, job: Uuid) {
+        let storage = &crate::storage::STORAGE;
+        let res = serde_json::to_string(self)
+            .map_err(|e| CustomError::JobLoadError {
+                err: e.into(),
+                job_id: job,
+            })
+            .and_then(|contents| storage.write(storage.job_status_path(job), contents));
+        if let Err(e) = res {
+            error!("Failed to write status for job {}: {}", job, e);
+        }
+    }
+    pub fn load(job: Uuid) -> Result<Self, CustomError> {
+        let path = crate::storage::STORAGE.job_status_path(job);
+        let contents = fs::read_to_string(&path).map_err(|e| CustomError::JobLoadError {
+            err: format!("Failed to read status file at {}. {}", path.display(), e).into(),
+            job_id: job,
+        })?;
+        serde_json::from_str(&contents).map_err(|e| CustomError::JobLoadError {
+            err: e.into(),
+            job_id: job,
+        })
+    }
 }
 
 impl Default for Status {
@@ -131,3 +265,30 @@ impl Default for Status {
         Self::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::Sink;
+    use serde_json::json;
+
+    #[test]
+    fn job_spec() {
+        let plain: JobSpec = serde_json::from_value(json!("/data/**/*.evtx")).unwrap();
+        assert_eq!(plain.glob(), "/data/**/*.evtx");
+        assert_eq!(plain.on_complete(), None);
+        let full: JobSpec = serde_json::from_value(json!({
+            "glob": "/data/**/*.evtx",
+            "on_complete": {"sink": "elastic", "per_file": true}
+        }))
+        .unwrap();
+        assert_eq!(full.glob(), "/data/**/*.evtx");
+        assert_eq!(
+            full.on_complete(),
+            Some(&OnComplete {
+                sink: Sink::Elastic,
+                per_file: true
+            })
+        );
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{elastic::sanitise_string_elastic, error::CustomError, type_map::Mapping, Parser};
+use serde_json::json;
+use std::collections::BTreeMap;
+use type_casting::{schema::flatten, Types};
+
+// Saved searches bundled per parser, they reference the parser wide data views by id. Jobs
+// normalised to ECS get searches on the ECS fields.
+fn saved_objects(parser: &Parser, ecs: bool) -> Option<&'static str> {
+    match (parser, ecs) {
+        (Parser::Evtx, false) => Some(include_str!("../assets/kibana/evtx.ndjson")),
+        (Parser::Evtx, true) => Some(include_str!("../assets/kibana/evtx_ecs.ndjson")),
+        (Parser::Mft, false) => Some(include_str!("../assets/kibana/mft.ndjson")),
+        (Parser::Mft, true) => Some(include_str!("../assets/kibana/mft_ecs.ndjson")),
+        (Parser::None, _) => None,
+    }
+}
+
+fn request(
+    client: &reqwest::blocking::Client,
+    method: reqwest::Method,
+    address: &str,
+    path: &str,
+) -> reqwest::blocking::RequestBuilder {
+    let (user, password) = crate::ELASTIC_USER
+        .split_once(':')
+        .unwrap_or((crate::ELASTIC_USER.as_str(), ""));
+    client
+        .request(
+            method,
+            format!("{}/{}", address.trim_end_matches('/'), path),
+        )
+        .basic_auth(user, Some(password))
+        .header("kbn-xsrf", "true")
+}
+
+fn check_response(
+    res: reqwest::blocking::Response,
+    action: &str,
+) -> Result<reqwest::blocking::Response, CustomError> {
+    match res.status().is_success() {
+        true => Ok(res),
+        false => Err(CustomError::KibanaError(
+            format!(
+                "An error has occured whilst {}. {}",
+                action,
+                res.text().unwrap_or_default()
+            )
+            .into(),
+        )),
+    }
+}
+
+// The inferred Date field to use as a data view's timestamp, `@timestamp` when present otherwise
+// the least nested date field
+pub fn time_field(t: &Types) -> Option<String> {
+    flatten(t)
+        .into_iter()
+        .filter(|(_, t)| t == &Types::Date)
+        .map(|(path, _)| path)
+        .min_by_key(|path| {
+            (
+                path != "@timestamp",
+                path.matches('.').count(),
+                path.clone(),
+            )
+        })
+}
+
+// Data views keyed by id, one per index plus one per parser spanning all of its indices
+pub fn data_views(mapping: &Mapping) -> BTreeMap<String, serde_json::Value> {
+    let mut views = BTreeMap::new();
+    for (index, t) in &mapping.index_pattern_mappings {
+        let title = sanitise_string_elastic(index);
+        views.insert(
+            format!("ulp-{}", title),
+            data_view(&title, time_field(t).as_deref()),
+        );
+    }
+    for parser in mapping.parsers() {
+        let (prefix, title) = (parser.index_prefix(), parser.index_wildcard());
+        let time = mapping
+            .index_pattern_mappings
+            .iter()
+            .filter(|(index, _)| sanitise_string_elastic(index).starts_with(&prefix))
+            .find_map(|(_, t)| time_field(t));
+        views.insert(
+            format!("ulp-{}", prefix.trim_end_matches('_')),
+            data_view(&title, time.as_deref()),
+        );
+    }
+    views
+}
+
+fn data_view(title: &str, time_field: Option<&str>) -> serde_json::Value {
+    let mut attributes = json!({ "title": title });
+    if let Some(field) = time_field {
+        attributes["timeFieldName"] = json!(field);
+    }
+    json!({ "attributes": attributes })
+}
+
+// Create (or overwrite) the job's data views and import the bundled saved objects, a no-op unless
+// KIBANA_ADDRESS is set
+pub fn provision(mapping: &Mapping) -> Result<(), CustomError> {
+    let address = match crate::KIBANA_ADDRESS.as_ref() {
+        Some(address) => address,
+        None => return Ok(()),
+    };
+    let client = reqwest::blocking::Client::new();
+    for (id, body) in data_views(mapping) {
+        let res = request(
+            &client,
+            reqwest::Method::POST,
+            address,
+            &format!("api/saved_objects/index-pattern/{}?overwrite=true", id),
+        )
+        .json(&body)
+        .send()
+        .map_err(|e| CustomError::KibanaError(e.into()))?;
+        check_response(res, "creating a data view")?;
+        debug!("Created Kibana data view {}", id);
+    }
+    if !*crate::KIBANA_SAVED_OBJECTS {
+        return Ok(());
+    }
+    for parser in mapping.parsers() {
+        if let Some(objects) = saved_objects(parser, mapping.ecs) {
+            let part = reqwest::blocking::multipart::Part::text(objects)
+                .file_name(format!("{:?}.ndjson", parser).to_lowercase());
+            let res = request(
+                &client,
+                reqwest::Method::POST,
+                address,
+                "api/saved_objects/_import?overwrite=true",
+            )
+            .multipart(reqwest::blocking::multipart::Form::new().part("file", part))
+            .send()
+            .map_err(|e| CustomError::KibanaError(e.into()))?;
+            check_response(res, "importing saved objects")?;
+            info!("Imported Kibana saved objects for {:?}", parser);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_field_test() {
+        let t = Types::get_type(&json!({
+            "Event": {"System": {"TimeCreated_attributes": {"SystemTime": "2021-01-01T00:00:00Z"}}},
+            "Written": "2021-01-01T00:00:00Z",
+            "Name": "a"
+        }));
+        assert_eq!(time_field(&t), Some("Written".to_string()));
+        let t = Types::get_type(
+            &json!({"a": "2021-01-01T00:00:00Z", "@timestamp": "2021-01-01T00:00:00Z"}),
+        );
+        assert_eq!(time_field(&t), Some("@timestamp".to_string()));
+        assert_eq!(time_field(&Types::get_type(&json!({"a": 1}))), None);
+    }
+
+    #[test]
+    fn data_views_test() {
+        let mut mapping = Mapping::default();
+        mapping.index_pattern_mappings.insert(
+            "evtx_Microsoft-Windows-Security-Auditing".into(),
+            Types::get_type(&json!({"Event": {"System": {"SystemTime": "2021-01-01T00:00:00Z"}}})),
+        );
+        let views = data_views(&mapping);
+        assert_eq!(
+            views.get("ulp-evtx_microsoft-windows-security-auditing"),
+            Some(&json!({"attributes": {
+                "title": "evtx_microsoft-windows-security-auditing",
+                "timeFieldName": "Event.System.SystemTime"
+            }}))
+        );
+    }
+
+    #[test]
+    fn saved_objects_test() {
+        for parser in [Parser::Evtx, Parser::Mft] {
+            for ecs in [false, true] {
+                for line in saved_objects(&parser, ecs).unwrap().lines() {
+                    let object: serde_json::Value = serde_json::from_str(line).unwrap();
+                    let columns = object["attributes"]["columns"].as_array().unwrap();
+                    // Raw fields are only found under the parser's namespace once normalised
+                    let raw = columns
+                        .iter()
+                        .filter_map(|c| c.as_str())
+                        .any(|c| !c.contains('.') || c.starts_with("Event."));
+                    assert_eq!(raw, !ecs, "{}", object["id"]);
+                }
+            }
+        }
+    }
+}
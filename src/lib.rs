@@ -4,12 +4,30 @@ extern crate lazy_static;
 extern crate log;
 //
 pub mod api;
+pub mod archive;
+pub mod clickhouse;
+pub mod collection;
+pub mod data_file;
+pub mod drift;
+pub mod ecs;
 pub mod elastic;
 pub mod error;
 pub mod evtx;
+pub mod export;
 pub mod job;
+pub mod kibana;
 pub mod mft;
+pub mod mongo;
+pub mod overrides;
+pub mod postgresql;
+pub mod sink;
+pub mod splunk;
+pub mod sqlite;
+pub mod storage;
+pub mod syslog;
 pub mod type_map;
+pub mod upload;
+pub mod watch;
 pub mod workerpool;
 
 use job::Task;
@@ -22,14 +40,53 @@ use std::{
 const UPLOAD_DIR_ENV: &str = "UPLOAD_DIR";
 const MONGODB_ADDRESS_ENV: &str = "MONGODB_ADDRESS";
 const ELASTIC_USER_ENV: &str = "ELASTIC_USER";
+const ELASTIC_ADDRESS_ENV: &str = "ELASTIC_ADDRESS";
+const TYPE_OVERRIDES_ENV: &str = "TYPE_OVERRIDES";
+const ECS_NORMALISE_ENV: &str = "ECS_NORMALISE";
+const KIBANA_ADDRESS_ENV: &str = "KIBANA_ADDRESS";
+const KIBANA_SAVED_OBJECTS_ENV: &str = "KIBANA_SAVED_OBJECTS";
 // Env Var Reads
 lazy_static! {
     static ref UPLOAD_DIR_PATH: String =
         env::var(UPLOAD_DIR_ENV).unwrap_or_else(|_| "/tmp".to_string());
     static ref MONGODB_ADDRESS: String =
-        env::var(MONGODB_ADDRESS_ENV).expect("No Enviroment variable for MONGODB_ADDRESS");
+        env::var(MONGODB_ADDRESS_ENV).unwrap_or_else(|_| "mongodb://0.0.0.0:27017".to_string());
     static ref ELASTIC_USER: String =
         env::var(ELASTIC_USER_ENV).unwrap_or_else(|_| "elastic:changeme".to_string());
+    static ref ELASTIC_ADDRESS: String =
+        env::var(ELASTIC_ADDRESS_ENV).unwrap_or_else(|_| "http://0.0.0.0:9200".to_string());
+    static ref ECS_NORMALISE: bool = env_bool(ECS_NORMALISE_ENV, false);
+    // Kibana provisioning is skipped when unset, uses the Elastic credentials
+    static ref KIBANA_ADDRESS: Option<String> = env::var(KIBANA_ADDRESS_ENV).ok();
+    static ref KIBANA_SAVED_OBJECTS: bool = env_bool(KIBANA_SAVED_OBJECTS_ENV, false);
+    static ref TYPE_OVERRIDES: overrides::Overrides = match env::var(TYPE_OVERRIDES_ENV) {
+        Ok(path) => overrides::Overrides::from_path(&path)
+            .unwrap_or_else(|e| panic!("Failed to load type overrides from {}. {}", path, e)),
+        Err(_) => overrides::Overrides::default(),
+    };
+}
+
+fn env_usize(key: &str, default: usize) -> usize {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(default)
+}
+
+// A lowercase variant name, as serde reads it
+fn env_enum<T: serde::de::DeserializeOwned>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| serde_json::from_value(serde_json::Value::String(v.to_lowercase())).ok())
+        .unwrap_or(default)
+}
+
+fn env_bool(key: &str, default: bool) -> bool {
+    match env::var(key).as_deref() {
+        Ok("true") | Ok("1") => true,
+        Ok("false") | Ok("0") => false,
+        _ => default,
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Eq, PartialEq, Hash)]
@@ -53,6 +110,18 @@ impl Parser {
             Parser::None => "none",
         }
     }
+    // The part of the default index pattern before any placeholder, as an Elastic index name
+    pub fn index_prefix(&self) -> String {
+        let pattern = self.default_index_pattern();
+        elastic::sanitise_string_elastic(pattern.split("{{").next().unwrap_or(pattern))
+    }
+    // Matches every index generated from the default index pattern, ie. `evtx_*`
+    pub fn index_wildcard(&self) -> String {
+        match self.default_index_pattern().contains("{{") {
+            true => format!("{}*", self.index_prefix()),
+            false => self.index_prefix(),
+        }
+    }
 }
 
 impl TryFrom<&PathBuf> for Parser {
@@ -61,12 +130,19 @@ impl TryFrom<&PathBuf> for Parser {
         let mut file = fs::File::open(path)?;
         let mut buffer: [u8; 8] = [0; 8];
         file.read_exact(&mut buffer)?;
-        match &buffer[..] {
-            [0x46, 0x49, 0x4c, 0x45, 0x30, _, _, _] => Ok(Self::Mft),
-            [0x45, 0x6c, 0x66, 0x46, 0x69, 0x6c, 0x65, _] => Ok(Self::Evtx),
-            _ => match path.extension().map(|ext| ext.to_str().unwrap()) {
-                Some("evtx") => Ok(Self::Evtx),
-                _ => Ok(Self::None),
+        Ok(Self::detect(&buffer, path))
+    }
+}
+
+impl Parser {
+    // From a file's first 8 bytes, falling back to its extension
+    pub fn detect(header: &[u8], path: &std::path::Path) -> Self {
+        match header {
+            [0x46, 0x49, 0x4c, 0x45, 0x30, _, _, _] => Self::Mft,
+            [0x45, 0x6c, 0x66, 0x46, 0x69, 0x6c, 0x65, _] => Self::Evtx,
+            _ => match path.extension().and_then(|ext| ext.to_str()) {
+                Some("evtx") => Self::Evtx,
+                _ => Self::None,
             },
         }
     }
@@ -79,13 +155,15 @@ impl Parser {
                 debug!("Creating MFT Parser");
                 let mut mft: mft::Parser = TryFrom::try_from(task).unwrap();
                 debug!("Running MFT Parser");
-                mft.run(self.default_index_pattern().into()).unwrap();
+                let pattern = task.mapping_ref.lock().unwrap().index_pattern(self);
+                mft.run(pattern).unwrap();
             }
             Self::Evtx => {
                 debug!("Creating EVTX Parser");
                 let mut evtx: evtx::Parser = TryFrom::try_from(task).unwrap();
                 debug!("Running EVTX Parser");
-                evtx.run(self.default_index_pattern().into()).unwrap();
+                let pattern = task.mapping_ref.lock().unwrap().index_pattern(self);
+                evtx.run(pattern).unwrap();
             }
             _ => panic!("No Parser for this file"),
         }
@@ -10,7 +10,47 @@ use ulp::workerpool::Orchestrator;
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(String::as_str) == Some("export") {
+        if let Err(e) = export(&args[1..]) {
+            eprintln!("{}", e);
+            eprintln!("Usage: ulp export <job> [--format parquet|csv|bodyfile] [--output <dir>]");
+            std::process::exit(1);
+        }
+        return;
+    }
     info!("ULP Starting - Initializing Orchestrator");
     let orchestrator = Orchestrator::default();
     let _ = orchestrator.run().await.unwrap();
 }
+
+// ulp export <job> [--format parquet|csv|bodyfile] [--output <dir>]
+fn export(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    use ulp::{
+        export::{export, ExportFormat},
+        storage::STORAGE,
+    };
+    let job = args
+        .first()
+        .ok_or("No job id given")?
+        .parse::<uuid::Uuid>()?;
+    let mut format = ExportFormat::default();
+    let mut output = STORAGE.export_dir(job);
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        let value = options
+            .next()
+            .ok_or_else(|| format!("No value given for {}", option))?;
+        match option.as_str() {
+            "--format" => format = value.parse()?,
+            "--output" => output = value.into(),
+            _ => return Err(format!("Unknown option {}", option).into()),
+        }
+    }
+    let job = ulp::job::Job::load(job)?;
+    let mapping = job.mapping.lock().unwrap();
+    for path in export(&mapping, format, &output)? {
+        println!("{}", path.display());
+    }
+    Ok(())
+}
@@ -2,36 +2,33 @@
 use mft::csv::FlatMftEntryWithName;
 use std::{
     convert::TryFrom,
-    fs::{self, OpenOptions},
-    io::Write,
     sync::{Arc, Mutex},
 };
 type MftParser = mft::MftParser<std::io::BufReader<std::fs::File>>;
-use crate::{error::CustomError, job::Task, type_map::IndexPatternObject, type_map::Mapping};
+use crate::{
+    collection::Collection,
+    data_file::{DataWriter, DATA_CONFIG},
+    error::CustomError,
+    job::Task,
+    storage::STORAGE,
+    type_map::IndexPatternObject,
+    type_map::Mapping,
+};
 
 pub struct Parser {
     pub parser: MftParser,
     iter: Vec<FlatMftEntryWithName>,
     //
-    data_file: fs::File,
+    data_file: DataWriter,
     mapping_ref: Arc<Mutex<Mapping>>,
+    collection: Option<Collection>,
 }
 
 impl TryFrom<&Task> for Parser {
     type Error = CustomError;
     fn try_from(task: &Task) -> Result<Self, Self::Error> {
-        std::fs::create_dir_all(format!("{}/{}/", crate::UPLOAD_DIR_ENV, task.job_id))
-            .map_err(|e| CustomError::ParserRunError(e.into()))?;
-        let data_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(format!(
-                "{}/{}/{}.data",
-                crate::UPLOAD_DIR_ENV,
-                task.job_id,
-                task.id
-            ))
+        STORAGE.create_job_dir(task.job_id)?;
+        let data_file = DataWriter::create(STORAGE.data_path(task.job_id, task.id), &DATA_CONFIG)
             .map_err(|e| CustomError::ParserRunError(e.into()))?;
         let mut parser: MftParser =
             MftParser::from_path(&task.path).map_err(|e| CustomError::ParserRunError(e.into()))?;
@@ -48,17 +45,28 @@ impl TryFrom<&Task> for Parser {
             parser,
             data_file,
             mapping_ref: task.mapping_ref.clone(),
+            collection: task.collection.clone(),
         })
     }
 }
 impl Parser {
     pub fn run(&mut self, pattern: IndexPatternObject) -> Result<(), CustomError> {
+        let ecs = self.mapping_ref.lock().map(|m| m.ecs).unwrap_or(false);
         //  Iterate over inner parser object
         for entry in &self.iter {
             // Parse entry
             let json =
                 serde_json::to_value(entry).map_err(|e| CustomError::ParserRunError(e.into()))?;
-            writeln!(&mut self.data_file, "{}", json)
+            let json = match ecs {
+                true => crate::ecs::normalise(&crate::Parser::Mft, json),
+                false => json,
+            };
+            let json = match &self.collection {
+                Some(collection) => crate::collection::tag(collection, json),
+                None => json,
+            };
+            self.data_file
+                .write_record(&json)
                 .map_err(|e| CustomError::ParserRunError(e.into()))?;
             // Generate type map
             // Move lock from parser to inside the mapping, arc mutex contents and build into methods
@@ -73,6 +81,8 @@ impl Parser {
                 }
             }
         }
-        Ok(())
+        self.data_file
+            .finish()
+            .map_err(|e| CustomError::ParserRunError(e.into()))
     }
 }
@@ -0,0 +1,327 @@
+use crate::{
+    elastic::{document_id, sanitise_string_elastic},
+    error::CustomError,
+    type_map::{skip_failed, Mapping, ParsedFileStats},
+};
+use mongodb::{
+    bson::{doc, Bson, Document},
+    error::ErrorKind,
+    options::{IndexOptions, InsertManyOptions},
+    sync::{Client, Collection},
+    IndexModel,
+};
+use serde_json::Value;
+use std::sync::Mutex;
+use type_casting::{
+    schema::{flatten, list_item_type},
+    Types,
+};
+
+// Leaf fields that identify a record (or what it happened on) and get an index
+const KEY_FIELDS: &[&str] = &[
+    // EVTX
+    "EventID",
+    "EventRecordID",
+    "Computer",
+    "Channel",
+    "TargetUserName",
+    "ProcessId",
+    // MFT
+    "EntryId",
+    "FullPath",
+    // ECS
+    "event.code",
+    "host.name",
+    "user.name",
+    "file.path",
+];
+
+// Duplicate key, the document was inserted by an earlier run
+const DUPLICATE_KEY: i32 = 11000;
+
+lazy_static! {
+    static ref DATABASE: String =
+        std::env::var("MONGODB_DATABASE").unwrap_or_else(|_| "ulp".to_string());
+    static ref BATCH_SIZE: usize = crate::env_usize("MONGODB_BATCH_SIZE", 1000);
+    // Clients hold a connection pool, shared by every worker
+    static ref CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+}
+
+fn client() -> Result<Client, CustomError> {
+    let mut client = CLIENT.lock().unwrap();
+    if client.is_none() {
+        *client = Some(
+            Client::with_uri_str(crate::MONGODB_ADDRESS.as_str())
+                .map_err(|e| CustomError::MongoError(e.into()))?,
+        );
+    }
+    Ok(client.clone().unwrap())
+}
+
+fn collection(client: &Client, pattern: &str) -> Collection<Document> {
+    client
+        .database(&DATABASE)
+        .collection(&sanitise_string_elastic(pattern))
+}
+
+// Indexed field paths of an index pattern, its timestamp first
+pub fn index_fields(t: &Types) -> Vec<String> {
+    let mut fields = crate::kibana::time_field(t).into_iter().collect::<Vec<_>>();
+    for (path, t) in flatten(t) {
+        let key = KEY_FIELDS
+            .iter()
+            .any(|key| path == *key || path.ends_with(&format!(".{}", key)));
+        let indexable = !matches!(t, Types::Null | Types::List(_) | Types::Object(_));
+        if key && indexable && !fields.contains(&path) {
+            fields.push(path);
+        }
+    }
+    fields
+}
+
+// Create the timestamp and key field indexes of each index pattern's collection, existing indexes
+// are left as is
+pub fn create_indexes(mapping: &Mapping) -> Result<(), CustomError> {
+    let client = client()?;
+    for (pattern, t) in &mapping.index_pattern_mappings {
+        let indexes = index_fields(t)
+            .into_iter()
+            .map(|field| {
+                IndexModel::builder()
+                    .keys(doc! { field: 1 })
+                    .options(IndexOptions::builder().background(true).build())
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        if indexes.is_empty() {
+            continue;
+        }
+        collection(&client, pattern)
+            .create_indexes(indexes, None)
+            .map_err(|e| CustomError::MongoError(e.into()))?;
+    }
+    Ok(())
+}
+
+// Convert a casted record to BSON, Date fields become BSON dates so they can be range queried
+pub fn to_bson(value: Value, t: &Types) -> Bson {
+    match (value, t) {
+        (Value::Object(map), _) => {
+            let children = match t {
+                Types::Object(children) => Some(children),
+                _ => None,
+            };
+            Bson::Document(
+                map.into_iter()
+                    .map(|(key, value)| {
+                        let t = children.and_then(|c| c.get(&key)).unwrap_or(&Types::Null);
+                        (key, to_bson(value, t))
+                    })
+                    .collect(),
+            )
+        }
+        (Value::Array(list), _) => {
+            let item = match t {
+                Types::List(map) => list_item_type(map),
+                _ => Types::Null,
+            };
+            Bson::Array(list.into_iter().map(|v| to_bson(v, &item)).collect())
+        }
+        (Value::String(s), Types::Date) => match Types::str_date(&s) {
+            Ok(date) => Bson::DateTime(mongodb::bson::DateTime::from_millis(
+                date.timestamp_millis(),
+            )),
+            Err(_) => Bson::String(s),
+        },
+        (Value::String(s), _) => Bson::String(s),
+        (Value::Null, _) => Bson::Null,
+        (Value::Bool(b), _) => Bson::Boolean(b),
+        (Value::Number(n), _) => match n.as_i64() {
+            Some(i) => Bson::Int64(i),
+            None => Bson::Double(n.as_f64().unwrap_or_default()),
+        },
+    }
+}
+
+// Number of documents in a failed insert that did not make it in
+fn insert_failures(e: &mongodb::error::Error, batch: usize) -> usize {
+    match &*e.kind {
+        ErrorKind::BulkWrite(failure) if failure.write_concern_error.is_none() => failure
+            .write_errors
+            .iter()
+            .flatten()
+            .filter(|e| e.code != DUPLICATE_KEY)
+            .count(),
+        _ => batch,
+    }
+}
+
+fn insert_batch(
+    collection: &Collection<Document>,
+    batch: &[Document],
+) -> Result<(), mongodb::error::Error> {
+    let options = InsertManyOptions::builder().ordered(false).build();
+    collection.insert_many(batch, options).map(|_| ())
+}
+
+// Insert a parsed file's casted records into their index pattern's collection, with `_id`s derived
+// like Elastic's so re-running a job does not duplicate documents. Returns the number of documents
+// that failed.
+pub fn insert_file(map: &Mapping, file: &ParsedFileStats) -> Result<usize, CustomError> {
+    let client = client()?;
+    insert_batches(map, file, *BATCH_SIZE, |pattern, batch| {
+        insert_batch(&collection(&client, pattern), batch)
+    })
+}
+
+// Batch a parsed file's casted records per index pattern as BSON documents, `insert` sends each
+// batch. Returns the number of documents that failed, duplicates of earlier runs aside.
+fn insert_batches(
+    map: &Mapping,
+    file: &ParsedFileStats,
+    size: usize,
+    mut insert: impl FnMut(&str, &[Document]) -> Result<(), mongodb::error::Error>,
+) -> Result<usize, CustomError> {
+    let mut batches: std::collections::BTreeMap<String, Vec<Document>> = Default::default();
+    let mut failed = 0;
+    let mut send = |pattern: &str, batch: &mut Vec<Document>| {
+        let failed = match insert(pattern, batch) {
+            Ok(()) => 0,
+            Err(e) => {
+                let failed = insert_failures(&e, batch.len());
+                if failed > 0 {
+                    error!(
+                        "{} documents could not be inserted into {}: {}",
+                        failed, pattern, e
+                    );
+                }
+                failed
+            }
+        };
+        batch.clear();
+        failed
+    };
+    for (record, res) in map.casted_records(file)?.enumerate() {
+        let (pattern, json) = match skip_failed(res, &mut failed)? {
+            Some(record) => record,
+            None => continue,
+        };
+        let t = map
+            .index_pattern_mappings
+            .get(&pattern)
+            .unwrap_or(&Types::Null);
+        let mut document = match to_bson(json, t) {
+            Bson::Document(document) => document,
+            _ => continue,
+        };
+        document.insert(
+            "_id",
+            document_id(&file.file_hash, record, &file.parser_used),
+        );
+        let batch = batches.entry(pattern.clone()).or_default();
+        batch.push(document);
+        if batch.len() >= size {
+            failed += send(&pattern, batch);
+        }
+    }
+    for (pattern, mut batch) in batches {
+        if !batch.is_empty() {
+            failed += send(&pattern, &mut batch);
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn index_fields_test() {
+        let t = Types::get_type(&json!({"Event": {
+            "System": {
+                "EventID": 4624,
+                "EventRecordID": 1,
+                "Computer": "WS01",
+                "TimeCreated_attributes": {"SystemTime": "2021-01-01T00:00:00Z"}
+            },
+            "EventData": {"TargetUserName": "bob", "LogonType": 3}
+        }}));
+        assert_eq!(
+            index_fields(&t),
+            vec![
+                "Event.System.TimeCreated_attributes.SystemTime",
+                "Event.EventData.TargetUserName",
+                "Event.System.Computer",
+                "Event.System.EventID",
+                "Event.System.EventRecordID",
+            ]
+        );
+    }
+
+    #[test]
+    fn to_bson_test() {
+        let value = json!({"a": "2021-01-01T00:00:00Z", "b": [1, 2.5], "c": {"d": null}});
+        let t = Types::get_type(&value);
+        assert_eq!(
+            to_bson(value, &t),
+            Bson::Document(doc! {
+                "a": mongodb::bson::DateTime::from_millis(1609459200000),
+                "b": [Bson::Int64(1), Bson::Double(2.5)],
+                "c": {"d": Bson::Null},
+            })
+        );
+    }
+
+    #[test]
+    fn insert_batches_test() {
+        let (map, file) = crate::sink::test_file(&[
+            json!({"EntryId": 5, "FullPath": "a", "StandardInfoCreated": "2021-01-01T00:00:00Z"}),
+            json!({"EntryId": 6, "FullPath": "b", "StandardInfoCreated": "2021-01-02T00:00:00Z"}),
+            json!({"EntryId": 7, "FullPath": "c", "StandardInfoCreated": "2021-01-03T00:00:00Z"}),
+        ]);
+        let mut batches = Vec::new();
+        let failed = insert_batches(&map, &file, 2, |pattern, batch| {
+            batches.push((pattern.to_string(), batch.to_vec()));
+            match batches.len() {
+                // The first document was inserted by an earlier run, the second is rejected
+                1 => {
+                    let failure = mongodb::bson::from_document(doc! {"writeErrors": [
+                        {"index": 0, "code": DUPLICATE_KEY},
+                        {"index": 1, "code": 121, "errmsg": "Document failed validation"},
+                    ]})
+                    .unwrap();
+                    Err(ErrorKind::BulkWrite(failure).into())
+                }
+                _ => Ok(()),
+            }
+        })
+        .unwrap();
+        std::fs::remove_dir_all(file.parsed_file_path.parent().unwrap()).unwrap();
+        // The rejected document and the line that can't be read back
+        assert_eq!(failed, 2);
+        assert_eq!(
+            batches
+                .iter()
+                .map(|(p, b)| (p.as_str(), b.len()))
+                .collect::<Vec<_>>(),
+            vec![("mft", 2), ("mft", 1)]
+        );
+        assert_eq!(
+            batches[1].1[0],
+            doc! {
+                "EntryId": 7_i64,
+                "FullPath": "c",
+                "StandardInfoCreated": mongodb::bson::DateTime::from_millis(1609632000000),
+                "_id": document_id(&file.file_hash, 2, &crate::Parser::Mft),
+            }
+        );
+        // Anything but write errors fails the whole batch
+        let e: mongodb::error::Error = ErrorKind::Io(std::sync::Arc::new(
+            std::io::ErrorKind::ConnectionReset.into(),
+        ))
+        .into();
+        assert_eq!(insert_failures(&e, 3), 3);
+    }
+}
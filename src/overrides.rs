@@ -0,0 +1,371 @@
+use crate::error::CustomError;
+use glob::Pattern;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{collections::BTreeMap, fs, path::Path};
+use type_casting::Types;
+
+// User supplied overrides, read from the file at TYPE_OVERRIDES. Example:
+// {
+//     "fields": {
+//         "Event.System.EventID": "Int",
+//         "*.TargetUserName": {"type": "Str", "elastic": {"type": "keyword"}}
+//     },
+//     "indices": {
+//         "evtx_Microsoft-Windows-Security-Auditing": {
+//             "fields": {"Event.System.*": "Str"},
+//             "locked": {"catch_all": "unmapped"}
+//         }
+//     }
+// }
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Overrides {
+    // Applied to every index pattern and the global map
+    #[serde(default)]
+    pub fields: BTreeMap<Selector, FieldOverride>,
+    // Keyed by a glob matched against the generated index pattern
+    #[serde(default)]
+    pub indices: BTreeMap<Selector, IndexSchema>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IndexSchema {
+    #[serde(default)]
+    pub fields: BTreeMap<Selector, FieldOverride>,
+    // When set, fields not covered by `fields` (or the global fields) are handled as per Unmapped
+    #[serde(default)]
+    pub locked: Option<Unmapped>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Unmapped {
+    Reject,
+    CatchAll(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FieldOverride {
+    Type(Types),
+    Full {
+        #[serde(rename = "type")]
+        types: Option<Types>,
+        elastic: Option<Value>,
+    },
+}
+
+impl FieldOverride {
+    pub fn types(&self) -> Option<&Types> {
+        match self {
+            Self::Type(t) => Some(t),
+            Self::Full { types, .. } => types.as_ref(),
+        }
+    }
+    pub fn elastic(&self) -> Option<&Value> {
+        match self {
+            Self::Type(_) => None,
+            Self::Full { elastic, .. } => elastic.as_ref(),
+        }
+    }
+}
+
+// A dotted field path (or index pattern) glob, ie. `Event.System.EventID` or `*.TargetUserName`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Selector(Pattern);
+
+impl Selector {
+    pub fn matches(&self, path: &str) -> bool {
+        self.0.matches(path)
+    }
+    // Could this selector match a field nested below `path`
+    pub fn matches_below(&self, path: &str) -> bool {
+        let s = self.0.as_str();
+        let literal = &s[..s.find(&['*', '?', '['][..]).unwrap_or(s.len())];
+        let parent = format!("{}.", path);
+        parent.starts_with(literal) || literal.starts_with(&parent)
+    }
+}
+
+impl TryFrom<&str> for Selector {
+    type Error = glob::PatternError;
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        Pattern::new(s).map(Self)
+    }
+}
+
+impl Serialize for Selector {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Selector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Self::try_from(s.as_str()).map_err(de::Error::custom)
+    }
+}
+
+struct Rules<'a> {
+    fields: Vec<(&'a Selector, &'a FieldOverride)>,
+    locked: Option<&'a Unmapped>,
+}
+
+impl<'a> Rules<'a> {
+    fn get(&self, path: &str) -> Option<&'a FieldOverride> {
+        // Index specific fields are pushed last so take precedence
+        self.fields
+            .iter()
+            .rev()
+            .find(|(s, _)| s.matches(path))
+            .map(|(_, o)| *o)
+    }
+    fn covers(&self, path: &str, is_object: bool) -> bool {
+        self.fields
+            .iter()
+            .any(|(s, _)| s.matches(path) || (is_object && s.matches_below(path)))
+    }
+}
+
+impl Overrides {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, CustomError> {
+        let contents = fs::read_to_string(&path).map_err(|e| {
+            CustomError::SchemaError(
+                format!(
+                    "Failed to read overrides file {}: {}",
+                    path.as_ref().display(),
+                    e
+                )
+                .into(),
+            )
+        })?;
+        serde_json::from_str(&contents).map_err(|e| {
+            CustomError::SchemaError(format!("Failed to parse overrides file: {}", e).into())
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.indices.is_empty()
+    }
+    fn rules(&self, index: Option<&str>) -> Rules<'_> {
+        let mut fields = self.fields.iter().collect::<Vec<_>>();
+        let mut locked = None;
+        if let Some(index) = index {
+            for (selector, schema) in &self.indices {
+                if selector.matches(index) {
+                    fields.extend(schema.fields.iter());
+                    if schema.locked.is_some() {
+                        locked = schema.locked.as_ref();
+                    }
+                }
+            }
+        }
+        Rules { fields, locked }
+    }
+    // Override for a dotted field path, used when generating sink specific mappings
+    pub fn get(&self, index: Option<&str>, path: &str) -> Option<&FieldOverride> {
+        self.rules(index).get(path)
+    }
+    // Apply overrides to an inferred type map, index of None applies only the global fields
+    pub fn apply(&self, index: Option<&str>, t: &mut Types) {
+        if self.is_empty() {
+            return;
+        }
+        let rules = self.rules(index);
+        let removed = recurse(&rules, "", t);
+        if let (Some(Unmapped::CatchAll(field)), Types::Object(map)) = (rules.locked, t) {
+            if removed {
+                map.insert(field.clone(), Types::Str);
+            }
+        }
+        // Returns true when a field was removed due to locking
+        fn recurse(rules: &Rules, prefix: &str, t: &mut Types) -> bool {
+            let mut removed = false;
+            match t {
+                Types::Object(map) => {
+                    let keys = map.keys().cloned().collect::<Vec<_>>();
+                    for key in keys {
+                        let path = join(prefix, &key);
+                        if let Some(o) = rules.get(&path) {
+                            if let Some(forced) = o.types() {
+                                map.insert(key, forced.clone());
+                            }
+                            continue;
+                        }
+                        let is_object = matches!(map.get(&key), Some(Types::Object(_)));
+                        if rules.locked.is_some() && !rules.covers(&path, is_object) {
+                            map.remove(&key);
+                            removed = true;
+                            continue;
+                        }
+                        if let Some(child) = map.get_mut(&key) {
+                            removed |= recurse(rules, &path, child);
+                        }
+                    }
+                }
+                Types::List(map) => {
+                    for child in map.values_mut() {
+                        removed |= recurse(rules, prefix, child);
+                    }
+                }
+                _ => (),
+            }
+            removed
+        }
+    }
+    // Apply a locked schema to a value prior to casting, so it lines up with the type map. A
+    // record with fields outside a rejecting schema is refused, for the sinks to count as failed.
+    pub fn apply_value(&self, index: &str, value: &mut Value) -> Result<(), CustomError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let rules = self.rules(Some(index));
+        let locked = match rules.locked {
+            Some(locked) => locked,
+            None => return Ok(()),
+        };
+        fn recurse(
+            rules: &Rules,
+            prefix: &str,
+            value: &mut Value,
+            unmapped: &mut serde_json::Map<String, Value>,
+        ) {
+            match value {
+                Value::Object(map) => {
+                    let keys = map.keys().cloned().collect::<Vec<_>>();
+                    for key in keys {
+                        let path = join(prefix, &key);
+                        if rules.get(&path).is_some() {
+                            continue;
+                        }
+                        let is_object = matches!(map.get(&key), Some(Value::Object(_)));
+                        if !rules.covers(&path, is_object) {
+                            if let Some(v) = map.remove(&key) {
+                                unmapped.insert(path, v);
+                            }
+                            continue;
+                        }
+                        if let Some(child) = map.get_mut(&key) {
+                            recurse(rules, &path, child, unmapped);
+                        }
+                    }
+                }
+                Value::Array(list) => {
+                    for child in list.iter_mut() {
+                        recurse(rules, prefix, child, unmapped);
+                    }
+                }
+                _ => (),
+            }
+        }
+        let mut unmapped = serde_json::Map::new();
+        recurse(&rules, "", value, &mut unmapped);
+        if unmapped.is_empty() {
+            return Ok(());
+        }
+        match (locked, value) {
+            (Unmapped::CatchAll(field), Value::Object(map)) => {
+                map.insert(
+                    field.clone(),
+                    Value::String(Value::Object(unmapped).to_string()),
+                );
+            }
+            (Unmapped::Reject, _) => {
+                return Err(CustomError::SchemaError(
+                    format!(
+                        "Record rejected by the locked schema of {}, it has fields outside it: {}",
+                        index,
+                        unmapped.keys().cloned().collect::<Vec<_>>().join(", ")
+                    )
+                    .into(),
+                ))
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    match prefix.is_empty() {
+        true => key.to_string(),
+        false => format!("{}.{}", prefix, key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn overrides() -> Overrides {
+        serde_json::from_value(json!({
+            "fields": {
+                "Event.System.EventID": "Int",
+                "*.TargetUserName": {"type": "Str", "elastic": {"type": "keyword"}}
+            },
+            "indices": {
+                "evtx_security*": {
+                    "fields": {"Event.System.Computer": "Str"},
+                    "locked": {"catch_all": "unmapped"}
+                }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn forced_types() {
+        let overrides = overrides();
+        let mut t = Types::get_type(&json!({
+            "Event": {"System": {"EventID": "4624"}, "EventData": {"TargetUserName": "1"}}
+        }));
+        overrides.apply(Some("evtx_system"), &mut t);
+        assert_eq!(
+            t,
+            Types::get_type(&json!({
+                "Event": {"System": {"EventID": 1}, "EventData": {"TargetUserName": "a"}}
+            }))
+        );
+        assert_eq!(
+            overrides
+                .get(None, "Event.EventData.TargetUserName")
+                .and_then(|o| o.elastic()),
+            Some(&json!({"type": "keyword"}))
+        );
+    }
+
+    #[test]
+    fn locked_catch_all() {
+        let overrides = overrides();
+        let mut value = json!({
+            "Event": {"System": {"EventID": 4624, "Computer": "host", "Level": 0}}
+        });
+        let mut t = Types::get_type(&value);
+        overrides.apply(Some("evtx_security"), &mut t);
+        overrides.apply_value("evtx_security", &mut value).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "Event": {"System": {"EventID": 4624, "Computer": "host"}},
+                "unmapped": "{\"Event.System.Level\":0}"
+            })
+        );
+        assert!(type_casting::cast_value(&t, value).is_ok());
+    }
+
+    #[test]
+    fn locked_reject() {
+        let overrides: Overrides = serde_json::from_value(json!({
+            "indices": {"evtx_security*": {"fields": {"Event.System.EventID": "Int"}, "locked": "reject"}}
+        }))
+        .unwrap();
+        let mut value = json!({"Event": {"System": {"EventID": 4624}}});
+        assert!(overrides.apply_value("evtx_security", &mut value).is_ok());
+        let mut value = json!({"Event": {"System": {"EventID": 4624, "Level": 0}}});
+        let e = overrides
+            .apply_value("evtx_security", &mut value)
+            .unwrap_err();
+        assert!(e.to_string().contains("Event.System.Level"));
+    }
+}
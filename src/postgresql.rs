@@ -0,0 +1,480 @@
+use crate::{
+    elastic::document_id,
+    error::CustomError,
+    type_map::{get_value, skip_failed, Mapping, ParsedFileStats},
+};
+use postgres::{error::SqlState, Client, NoTls};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+};
+use type_casting::{
+    schema::{quote_identifier, sql_type, SqlDialect},
+    Types,
+};
+
+lazy_static! {
+    static ref POSTGRES_ADDRESS: String = std::env::var("POSTGRES_ADDRESS")
+        .unwrap_or_else(|_| "host=0.0.0.0 user=postgres".to_string());
+    // Range partition tables by day on the inferred timestamp
+    static ref PARTITION_BY_DAY: bool = crate::env_bool("POSTGRES_PARTITION_BY_DAY", false);
+    static ref COPY_ROWS: usize = crate::env_usize("POSTGRES_COPY_ROWS", 10000);
+}
+
+// Columns every table starts with, the whole record is kept in `doc`
+const ID: &str = "_id";
+const TIMESTAMP: &str = "@timestamp";
+const DOC: &str = "doc";
+// Temporary table a batch is copied into before being inserted
+const STAGING: &str = "ulp_staging";
+
+fn connect() -> Result<Client, CustomError> {
+    Client::connect(&POSTGRES_ADDRESS, NoTls).map_err(|e| CustomError::PostgresError(e.into()))
+}
+
+// Top level fields promoted to typed columns, nested objects are only in `doc`
+pub fn promoted_columns(t: &Types) -> Vec<(String, Types)> {
+    match t {
+        Types::Object(map) => map
+            .iter()
+            .filter(|(key, t)| {
+                !matches!(t, Types::Object(_)) && ![ID, TIMESTAMP, DOC].contains(&key.as_str())
+            })
+            .map(|(key, t)| (key.clone(), t.clone()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// An index pattern's table with its `_id`, `@timestamp` and `doc` columns, range partitioned by day
+// if asked, then a typed column per top level field. Each statement uses IF NOT EXISTS since
+// tables are prepared again before every file when sending per file.
+pub fn table_ddl(table: &str, t: &Types, partition: bool) -> Vec<String> {
+    let name = quote_identifier(table);
+    let mut statements = vec![format!(
+        "CREATE TABLE IF NOT EXISTS {} ({} TEXT, {} TIMESTAMPTZ, {} JSONB NOT NULL){};",
+        name,
+        quote_identifier(ID),
+        quote_identifier(TIMESTAMP),
+        quote_identifier(DOC),
+        match partition {
+            true => format!(" PARTITION BY RANGE ({})", quote_identifier(TIMESTAMP)),
+            false => String::new(),
+        }
+    )];
+    // Rows are inserted ON CONFLICT DO NOTHING so a file sent again isn't duplicated. Unique
+    // indexes on a partitioned table must include the partition key, rows without a timestamp
+    // are kept unique by an index of the default partition's own.
+    statements.push(format!(
+        "CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({});",
+        quote_identifier(&format!("{}_id", table)),
+        name,
+        match partition {
+            true => format!("{}, {}", quote_identifier(ID), quote_identifier(TIMESTAMP)),
+            false => quote_identifier(ID),
+        }
+    ));
+    if partition {
+        // Records without a timestamp
+        let default = format!("{}_default", table);
+        statements.push(format!(
+            "CREATE TABLE IF NOT EXISTS {} PARTITION OF {} DEFAULT;",
+            quote_identifier(&default),
+            name
+        ));
+        statements.push(format!(
+            "CREATE UNIQUE INDEX IF NOT EXISTS {} ON {} ({});",
+            quote_identifier(&format!("{}_id", default)),
+            quote_identifier(&default),
+            quote_identifier(ID)
+        ));
+    }
+    for (column, t) in promoted_columns(t) {
+        statements.push(format!(
+            "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {};",
+            name,
+            quote_identifier(&column),
+            sql_type(&t, SqlDialect::Postgres)
+        ));
+    }
+    statements
+}
+
+// Days are UTC, as are the timestamps copied in. Bounds give their offset so the partition
+// doesn't follow the session's time zone.
+fn partition_ddl(table: &str, day: chrono::NaiveDate) -> String {
+    format!(
+        "CREATE TABLE IF NOT EXISTS {} PARTITION OF {} FOR VALUES FROM ('{} 00:00:00+00') TO ('{} 00:00:00+00');",
+        quote_identifier(&format!("{}_{}", table, day.format("%Y%m%d"))),
+        quote_identifier(table),
+        day,
+        day.succ_opt().unwrap_or(day)
+    )
+}
+
+// Workers preparing or partitioning the same table race, IF NOT EXISTS does not cover that
+fn ignore_existing(res: Result<(), postgres::Error>) -> Result<(), CustomError> {
+    match res {
+        Err(e)
+            if e.code() == Some(&SqlState::DUPLICATE_TABLE)
+                || e.code() == Some(&SqlState::DUPLICATE_OBJECT)
+                || e.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+        {
+            Ok(())
+        }
+        res => res.map_err(|e| CustomError::PostgresError(e.into())),
+    }
+}
+
+// Whether an index pattern's table is partitioned by day. Existing tables keep the layout they
+// were created with whatever POSTGRES_PARTITION_BY_DAY is set to now.
+fn partitioned(client: &mut Client, table: &str, t: &Types) -> Result<bool, CustomError> {
+    let row = client
+        .query_opt(
+            "SELECT relkind::text FROM pg_class WHERE oid = to_regclass($1)",
+            &[&quote_identifier(table)],
+        )
+        .map_err(|e| CustomError::PostgresError(e.into()))?;
+    Ok(match row {
+        Some(row) => row.get::<_, String>(0) == "p",
+        None => *PARTITION_BY_DAY && crate::kibana::time_field(t).is_some(),
+    })
+}
+
+pub fn create_tables(mapping: &Mapping) -> Result<(), CustomError> {
+    let mut client = connect()?;
+    for (pattern, t) in &mapping.index_pattern_mappings {
+        let partition = partitioned(&mut client, pattern, t)?;
+        for statement in table_ddl(pattern, t, partition) {
+            ignore_existing(client.batch_execute(&statement))?;
+        }
+    }
+    Ok(())
+}
+
+// Postgres text and JSONB can't hold NUL, which EVTX strings can. It is dropped from strings and
+// keys before they are serialised.
+fn without_nul(value: &Value) -> Value {
+    match value {
+        Value::String(s) => Value::String(s.replace('\0', "")),
+        Value::Array(items) => Value::Array(items.iter().map(without_nul).collect()),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(key, value)| (key.replace('\0', ""), without_nul(value)))
+                .collect(),
+        ),
+        value => value.clone(),
+    }
+}
+
+// Escape a value for COPY's text format
+fn copy_text(value: &Value) -> String {
+    let text = match value {
+        Value::Null => return "\\N".to_string(),
+        Value::Bool(true) => return "t".to_string(),
+        Value::Bool(false) => return "f".to_string(),
+        Value::String(s) => s.replace('\0', ""),
+        value => without_nul(value).to_string(),
+    };
+    text.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+// Rows of an index pattern waiting to be copied
+struct Table {
+    columns: Vec<String>,
+    time_field: Option<String>,
+    rows: String,
+    count: usize,
+    days: BTreeSet<chrono::NaiveDate>,
+}
+
+// A table's rows in COPY text format, with the SQL copying them in through a staging table
+struct Batch {
+    // Days the rows fall on, each needs its partition when the table is partitioned
+    days: BTreeSet<chrono::NaiveDate>,
+    staging: String,
+    copy: String,
+    insert: String,
+    rows: String,
+}
+
+impl Table {
+    fn new(t: &Types) -> Self {
+        Table {
+            columns: promoted_columns(t).into_iter().map(|(c, _)| c).collect(),
+            time_field: crate::kibana::time_field(t),
+            rows: String::new(),
+            count: 0,
+            days: BTreeSet::new(),
+        }
+    }
+    fn push(&mut self, id: String, record: &Value) {
+        let time = self
+            .time_field
+            .as_deref()
+            .and_then(|field| get_value(record, field))
+            .and_then(Value::as_str)
+            .and_then(|s| Types::str_date(s).ok());
+        if let Some(time) = time {
+            self.days.insert(time.date_naive());
+        }
+        let mut row = vec![
+            copy_text(&Value::String(id)),
+            time.map(|t| t.to_rfc3339())
+                .map_or_else(|| "\\N".to_string(), |t| copy_text(&Value::String(t))),
+        ];
+        for column in &self.columns {
+            row.push(copy_text(record.get(column).unwrap_or(&Value::Null)));
+        }
+        row.push(copy_text(&Value::String(without_nul(record).to_string())));
+        self.rows.push_str(&row.join("\t"));
+        self.rows.push('\n');
+        self.count += 1;
+    }
+    // Take the rows waiting, COPY can't skip rows already in the table so they are copied into
+    // a staging table first
+    fn batch(&mut self, table: &str) -> Batch {
+        let columns = [ID, TIMESTAMP]
+            .iter()
+            .map(|c| c.to_string())
+            .chain(self.columns.iter().cloned())
+            .chain([DOC.to_string()])
+            .map(|c| quote_identifier(&c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let (table, staging) = (quote_identifier(table), quote_identifier(STAGING));
+        self.count = 0;
+        Batch {
+            days: std::mem::take(&mut self.days),
+            staging: format!(
+                "CREATE TEMPORARY TABLE {} (LIKE {}) ON COMMIT DROP;",
+                staging, table
+            ),
+            copy: format!("COPY {} ({}) FROM STDIN", staging, columns),
+            insert: format!(
+                "INSERT INTO {} ({}) SELECT {} FROM {} ON CONFLICT DO NOTHING;",
+                table, columns, columns, staging
+            ),
+            rows: std::mem::take(&mut self.rows),
+        }
+    }
+}
+
+// Copy a batch into its table in one transaction, creating the partitions its rows need first
+fn copy(client: &mut Client, table: &str, t: &Types, batch: Batch) -> Result<(), CustomError> {
+    if partitioned(client, table, t)? {
+        for day in &batch.days {
+            ignore_existing(client.batch_execute(&partition_ddl(table, *day)))?;
+        }
+    }
+    let mut tx = client
+        .transaction()
+        .map_err(|e| CustomError::PostgresError(e.into()))?;
+    tx.batch_execute(&batch.staging)
+        .map_err(|e| CustomError::PostgresError(e.into()))?;
+    let mut writer = tx
+        .copy_in(&batch.copy)
+        .map_err(|e| CustomError::PostgresError(e.into()))?;
+    writer
+        .write_all(batch.rows.as_bytes())
+        .map_err(|e| CustomError::PostgresError(e.into()))?;
+    writer
+        .finish()
+        .map_err(|e| CustomError::PostgresError(e.into()))?;
+    tx.batch_execute(&batch.insert)
+        .map_err(|e| CustomError::PostgresError(e.into()))?;
+    tx.commit()
+        .map_err(|e| CustomError::PostgresError(e.into()))
+}
+
+// COPY a parsed file's casted records into their index pattern's table, a transaction per
+// POSTGRES_COPY_ROWS rows. Records that can't be cast are counted as failed, but one value
+// Postgres won't take rolls back its whole transaction, so the file fails with that error and
+// earlier transactions stay committed.
+pub fn copy_file(map: &Mapping, file: &ParsedFileStats) -> Result<usize, CustomError> {
+    let mut client = connect()?;
+    copy_batches(map, file, *COPY_ROWS, |table, t, batch| {
+        copy(&mut client, table, t, batch)
+    })
+}
+
+// Batch a parsed file's casted records per index pattern, `copy` sends each batch. Returns the
+// number of records that failed.
+fn copy_batches(
+    map: &Mapping,
+    file: &ParsedFileStats,
+    rows: usize,
+    mut copy: impl FnMut(&str, &Types, Batch) -> Result<(), CustomError>,
+) -> Result<usize, CustomError> {
+    let mut tables: BTreeMap<String, Table> = BTreeMap::new();
+    let mut failed = 0;
+    for (record, res) in map.casted_records(file)?.enumerate() {
+        let (pattern, json) = match skip_failed(res, &mut failed)? {
+            Some(record) => record,
+            None => continue,
+        };
+        let t = map
+            .index_pattern_mappings
+            .get(&pattern)
+            .unwrap_or(&Types::Null);
+        let table = tables
+            .entry(pattern.clone())
+            .or_insert_with(|| Table::new(t));
+        table.push(
+            document_id(&file.file_hash, record, &file.parser_used),
+            &json,
+        );
+        if table.count >= rows {
+            copy(&pattern, t, table.batch(&pattern))?;
+        }
+    }
+    for (pattern, mut table) in tables {
+        if table.count > 0 {
+            let t = map
+                .index_pattern_mappings
+                .get(&pattern)
+                .unwrap_or(&Types::Null);
+            copy(&pattern, t, table.batch(&pattern))?;
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn table_ddl_test() {
+        let t = Types::get_type(&json!({"a": 1, "b": {"c": "x"}, "d": "127.0.0.1", "e": [1]}));
+        assert_eq!(
+            table_ddl("mft", &t, true),
+            vec![
+                r#"CREATE TABLE IF NOT EXISTS "mft" ("_id" TEXT, "@timestamp" TIMESTAMPTZ, "doc" JSONB NOT NULL) PARTITION BY RANGE ("@timestamp");"#,
+                r#"CREATE UNIQUE INDEX IF NOT EXISTS "mft_id" ON "mft" ("_id", "@timestamp");"#,
+                r#"CREATE TABLE IF NOT EXISTS "mft_default" PARTITION OF "mft" DEFAULT;"#,
+                r#"CREATE UNIQUE INDEX IF NOT EXISTS "mft_default_id" ON "mft_default" ("_id");"#,
+                r#"ALTER TABLE "mft" ADD COLUMN IF NOT EXISTS "a" BIGINT;"#,
+                r#"ALTER TABLE "mft" ADD COLUMN IF NOT EXISTS "d" INET;"#,
+                r#"ALTER TABLE "mft" ADD COLUMN IF NOT EXISTS "e" JSONB;"#,
+            ]
+        );
+        assert_eq!(
+            partition_ddl(
+                "mft",
+                chrono::NaiveDate::from_ymd_opt(2021, 12, 31).unwrap()
+            ),
+            r#"CREATE TABLE IF NOT EXISTS "mft_20211231" PARTITION OF "mft" FOR VALUES FROM ('2021-12-31 00:00:00+00') TO ('2022-01-01 00:00:00+00');"#
+        );
+    }
+
+    #[test]
+    fn copy_rows() {
+        let record = json!({
+            "a": "x\ty\\z",
+            "b": null,
+            "c": true,
+            "time": "2021-01-01T00:00:00Z",
+            "n": {"m": 1}
+        });
+        let mut table = Table::new(&Types::get_type(&record));
+        table.push("id".to_string(), &record);
+        assert_eq!(table.columns, vec!["a", "b", "c", "time"]);
+        assert_eq!(
+            table.rows,
+            format!(
+                "id\t2021-01-01T00:00:00+00:00\tx\\ty\\\\z\t\\N\tt\t2021-01-01T00:00:00Z\t{}\n",
+                record.to_string().replace('\\', "\\\\")
+            )
+        );
+        assert_eq!(table.days.len(), 1);
+        // A literal `\u0000` is kept
+        assert_eq!(
+            without_nul(&json!({"a\u{0}": ["b\u{0}\\u0000"]})),
+            json!({"a": ["b\\u0000"]})
+        );
+    }
+
+    #[test]
+    fn copy_batches_test() {
+        let (map, file) = crate::sink::test_file(&[
+            json!({"EntryId": 5, "FullPath": "a", "StandardInfoCreated": "2021-01-01T00:00:00Z"}),
+            json!({"EntryId": 6, "FullPath": "b\tc", "StandardInfoCreated": "2021-01-02T00:00:00Z"}),
+            json!({"EntryId": 7, "FullPath": "d", "StandardInfoCreated": "2021-01-02T01:00:00Z"}),
+        ]);
+        let mut batches = Vec::new();
+        let failed = copy_batches(&map, &file, 2, |table, t, batch| {
+            assert!(crate::kibana::time_field(t).is_some());
+            batches.push((table.to_string(), batch));
+            Ok(())
+        })
+        .unwrap();
+        std::fs::remove_dir_all(file.parsed_file_path.parent().unwrap()).unwrap();
+        // The line that can't be read back is counted, the rest are copied in batches of 2
+        assert_eq!(failed, 1);
+        assert_eq!(batches.len(), 2);
+        let (table, batch) = &batches[0];
+        assert_eq!(table, "mft");
+        assert_eq!(
+            batch.staging,
+            r#"CREATE TEMPORARY TABLE "ulp_staging" (LIKE "mft") ON COMMIT DROP;"#
+        );
+        assert_eq!(
+            batch.copy,
+            r#"COPY "ulp_staging" ("_id", "@timestamp", "EntryId", "FullPath", "StandardInfoCreated", "doc") FROM STDIN"#
+        );
+        assert_eq!(
+            batch.insert,
+            r#"INSERT INTO "mft" ("_id", "@timestamp", "EntryId", "FullPath", "StandardInfoCreated", "doc") SELECT "_id", "@timestamp", "EntryId", "FullPath", "StandardInfoCreated", "doc" FROM "ulp_staging" ON CONFLICT DO NOTHING;"#
+        );
+        let rows = batch.rows.lines().collect::<Vec<_>>();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[1].split('\t').take(4).collect::<Vec<_>>(),
+            vec![
+                document_id(&file.file_hash, 1, &crate::Parser::Mft).as_str(),
+                "2021-01-02T00:00:00+00:00",
+                "6",
+                "b\\tc"
+            ]
+        );
+        assert_eq!(batch.days.len(), 2);
+        // Days are taken with their batch
+        let (_, batch) = &batches[1];
+        assert_eq!(batch.rows.lines().count(), 1);
+        assert_eq!(
+            batch.days.iter().collect::<Vec<_>>(),
+            vec![&chrono::NaiveDate::from_ymd_opt(2021, 1, 2).unwrap()]
+        );
+    }
+
+    // The one test of a sink against a running database, the other sinks' batches are checked
+    // without one like above
+    #[test]
+    #[ignore = "needs a local postgres at POSTGRES_ADDRESS"]
+    fn copy_into_postgres() {
+        let (map, file) = crate::sink::test_file(&[
+            json!({"EntryId": 5, "FullPath": "a", "StandardInfoCreated": "2021-01-01T00:00:00Z"}),
+        ]);
+        create_tables(&map).unwrap();
+        assert_eq!(copy_file(&map, &file).unwrap(), 1);
+        // Sent again without duplicating the row
+        assert_eq!(copy_file(&map, &file).unwrap(), 1);
+        std::fs::remove_dir_all(file.parsed_file_path.parent().unwrap()).unwrap();
+        let rows = connect()
+            .unwrap()
+            .query(
+                r#"SELECT "FullPath", "doc"->>'FullPath' FROM "mft" WHERE "_id" = $1"#,
+                &[&document_id(&file.file_hash, 0, &crate::Parser::Mft)],
+            )
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].get::<_, String>(0), "a");
+        assert_eq!(rows[0].get::<_, String>(1), "a");
+    }
+}
@@ -0,0 +1,388 @@
+use crate::{
+    error::CustomError,
+    storage::STORAGE,
+    type_map::{Mapping, ParsedFileStats},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use uuid::Uuid;
+
+// Destinations a completed job's parsed data can be sent to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Sink {
+    Elastic,
+    Splunk,
+    Sqlite,
+    Mongo,
+    Postgres,
+    #[serde(rename = "clickhouse")]
+    ClickHouse,
+    Syslog,
+}
+
+impl Sink {
+    // Get the destination ready for the mapping (templates, index mappings, settings). Called once
+    // per run, see `update` for the mappings of files that finish later.
+    pub fn prepare(&self, job: Uuid, mapping: &Mapping) -> Result<(), CustomError> {
+        match self {
+            Sink::Elastic => {
+                elastic_mappings(mapping, false)?;
+                crate::elastic::begin_ingest(&indices(mapping))
+            }
+            // HEC indexes and sourcetypes are managed on the Splunk side
+            Sink::Splunk => Ok(()),
+            Sink::Sqlite => crate::sqlite::create_tables(STORAGE.sqlite_path(job), mapping),
+            Sink::Mongo => crate::mongo::create_indexes(mapping),
+            Sink::Postgres => crate::postgresql::create_tables(mapping),
+            Sink::ClickHouse => crate::clickhouse::create_tables(mapping),
+            // Check the field map file before anything is sent
+            Sink::Syslog => crate::syslog::field_maps(&crate::syslog::SYSLOG_CONFIG).map(|_| ()),
+        }
+    }
+    // Add the fields of a grown mapping to a prepared destination, fields already sent keep
+    // their type. Per file runs call this before every file after the first.
+    pub fn update(&self, job: Uuid, mapping: &Mapping) -> Result<(), CustomError> {
+        match self {
+            Sink::Elastic => {
+                // Only indices new to this file still need refreshes disabled
+                let created = indices(mapping)
+                    .into_iter()
+                    .zip(elastic_mappings(mapping, true)?)
+                    .filter_map(|(index, created)| created.then_some(index))
+                    .collect::<Vec<_>>();
+                crate::elastic::begin_ingest(&created)
+            }
+            // Creating tables and indexes is already additive
+            _ => self.prepare(job, mapping),
+        }
+    }
+    // Send a parsed file, returns the number of documents that failed
+    pub fn send_file(
+        &self,
+        job: Uuid,
+        mapping: Mapping,
+        file: &ParsedFileStats,
+    ) -> Result<usize, CustomError> {
+        match self {
+            Sink::Elastic => crate::elastic::normalise_then_send(job, mapping, file),
+            Sink::Splunk => crate::splunk::send_file(&crate::splunk::HEC_CONFIG, &mapping, file),
+            Sink::Sqlite => crate::sqlite::insert_file(STORAGE.sqlite_path(job), &mapping, file),
+            Sink::Mongo => crate::mongo::insert_file(&mapping, file),
+            Sink::Postgres => crate::postgresql::copy_file(&mapping, file),
+            Sink::ClickHouse => crate::clickhouse::insert_file(&mapping, file),
+            Sink::Syslog => crate::syslog::send_file(&crate::syslog::SYSLOG_CONFIG, &mapping, file),
+        }
+    }
+    // Called once every file of a job has been sent
+    pub fn finish(&self, mapping: &Mapping) -> Result<(), CustomError> {
+        match self {
+            Sink::Elastic => {
+                crate::elastic::finish_ingest(&indices(mapping))?;
+                crate::kibana::provision(mapping)
+            }
+            Sink::Splunk
+            | Sink::Sqlite
+            | Sink::Mongo
+            | Sink::Postgres
+            | Sink::ClickHouse
+            | Sink::Syslog => Ok(()),
+        }
+    }
+}
+
+fn indices(mapping: &Mapping) -> Vec<String> {
+    mapping.index_pattern_mappings.keys().cloned().collect()
+}
+
+// Send the templates and each index's mapping, returns whether each index was created
+fn elastic_mappings(mapping: &Mapping, additive: bool) -> Result<Vec<bool>, CustomError> {
+    let mut errors = Vec::new();
+    if let Err(e) = crate::elastic::send_templates(mapping) {
+        errors.push(e.to_string());
+    }
+    let mut created = Vec::new();
+    for (index, map) in &mapping.index_pattern_mappings {
+        match crate::elastic::send_mapping(
+            index.to_string(),
+            map.clone(),
+            &mapping.overrides,
+            additive,
+        ) {
+            Ok(c) => created.push(c),
+            Err(e) => {
+                created.push(false);
+                errors.push(e.to_string());
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(CustomError::ElasticError(
+            format!(
+                "{} index mappings could not be applied. {}",
+                errors.len(),
+                errors.join("; ")
+            )
+            .into(),
+        ));
+    }
+    Ok(created)
+}
+
+// What to do once a job's files have been parsed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OnComplete {
+    pub sink: Sink,
+    // Send each file as soon as its task finishes rather than once the whole job has
+    #[serde(default)]
+    pub per_file: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestState {
+    Running,
+    Done,
+    Failed,
+}
+
+// Written to {job}/ingest.json as a sink run progresses
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IngestStatus {
+    pub sink: Sink,
+    pub state: IngestState,
+    // Unknown until every file of the job has been handed to the sink
+    pub files_total: Option<usize>,
+    pub files_done: usize,
+    pub files_failed: usize,
+    pub documents_failed: usize,
+    pub errors: Vec<String>,
+}
+
+pub fn load_status(job: Uuid) -> Result<IngestStatus, CustomError> {
+    let contents = fs::read_to_string(STORAGE.ingest_status_path(job))
+        .map_err(|e| CustomError::SinkError(e.into()))?;
+    serde_json::from_str(&contents).map_err(|e| CustomError::SinkError(e.into()))
+}
+
+// A job being sent to a sink, shared by the workers sending its files. Whichever of the last
+// file or `close` comes second finishes the run.
+#[derive(Debug)]
+pub struct SinkRun {
+    pub job: Uuid,
+    pub sink: Sink,
+    dispatched: AtomicUsize,
+    closed: AtomicBool,
+    finished: AtomicBool,
+    // Held while the sink is prepared or updated, so files wait on the run's first prepare
+    prepared: Mutex<bool>,
+    // The most complete mapping seen, per file runs see it grow as tasks finish
+    mapping: Mutex<Mapping>,
+    status: Mutex<IngestStatus>,
+}
+
+impl SinkRun {
+    pub fn new(job: Uuid, sink: Sink) -> Arc<Self> {
+        let run = Arc::new(SinkRun {
+            job,
+            sink,
+            dispatched: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+            prepared: Mutex::new(false),
+            mapping: Mutex::new(Mapping::default()),
+            status: Mutex::new(IngestStatus {
+                sink,
+                state: IngestState::Running,
+                files_total: None,
+                files_done: 0,
+                files_failed: 0,
+                documents_failed: 0,
+                errors: Vec::new(),
+            }),
+        });
+        run.save(&run.status.lock().unwrap());
+        run
+    }
+    pub fn status(&self) -> IngestStatus {
+        self.status.lock().unwrap().clone()
+    }
+    // Prepare the sink the first time, after that only add the fields the mapping has gained
+    pub fn prepare(&self, mapping: &Mapping) -> Result<(), CustomError> {
+        let mut prepared = self.prepared.lock().unwrap();
+        match *prepared {
+            true => self.sink.update(self.job, mapping),
+            false => {
+                self.sink.prepare(self.job, mapping)?;
+                *prepared = true;
+                Ok(())
+            }
+        }
+    }
+    // Record a file being handed to a worker, before it is sent
+    pub fn dispatched(&self) {
+        self.dispatched.fetch_add(1, Ordering::SeqCst);
+    }
+    // Keep the mapping the sink is finished with up to date
+    pub fn observe(&self, mapping: &Mapping) {
+        let mut latest = self.mapping.lock().unwrap();
+        if mapping.file_mapping.len() >= latest.file_mapping.len() {
+            *latest = mapping.clone();
+        }
+    }
+    pub fn file_done(&self, mapping: &Mapping, res: Result<usize, CustomError>) {
+        self.observe(mapping);
+        {
+            let mut status = self.status.lock().unwrap();
+            status.files_done += 1;
+            match res {
+                Ok(failed) => status.documents_failed += failed,
+                Err(e) => {
+                    error!("{}", e);
+                    status.files_failed += 1;
+                    status.errors.push(e.to_string());
+                }
+            }
+            self.save(&status);
+        }
+        self.try_finish();
+    }
+    // No more files will be dispatched
+    pub fn close(&self) {
+        self.status.lock().unwrap().files_total = Some(self.dispatched.load(Ordering::SeqCst));
+        self.closed.store(true, Ordering::SeqCst);
+        self.try_finish();
+    }
+    // Preparing the sink failed, nothing will be sent
+    pub fn fail(&self, e: CustomError) {
+        error!("{}", e);
+        let mut status = self.status.lock().unwrap();
+        status.state = IngestState::Failed;
+        status.errors.push(e.to_string());
+        self.finished.store(true, Ordering::SeqCst);
+        self.save(&status);
+    }
+    fn try_finish(&self) {
+        let mut status = self.status.lock().unwrap();
+        if !self.closed.load(Ordering::SeqCst)
+            || Some(status.files_done) != status.files_total
+            || self.finished.swap(true, Ordering::SeqCst)
+        {
+            return;
+        }
+        if let Err(e) = self.sink.finish(&self.mapping.lock().unwrap()) {
+            status.errors.push(e.to_string());
+        }
+        status.state = match status.files_failed == 0 && status.errors.is_empty() {
+            true => IngestState::Done,
+            false => IngestState::Failed,
+        };
+        info!(
+            "Sending job {} to {:?} finished: {} files, {} failed documents",
+            self.job, self.sink, status.files_done, status.documents_failed
+        );
+        self.save(&status);
+    }
+    fn save(&self, status: &IngestStatus) {
+        let res = serde_json::to_string(status)
+            .map_err(|e| CustomError::SinkError(e.into()))
+            .and_then(|contents| STORAGE.write(STORAGE.ingest_status_path(self.job), contents));
+        if let Err(e) = res {
+            error!("Failed to write ingest status for job {}: {}", self.job, e);
+        }
+    }
+}
+
+// How failed requests to a sink are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl RetryPolicy {
+    // Exponential backoff with equal jitter
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        use rand::Rng;
+        let exp = self
+            .base
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max);
+        let half = exp / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+}
+
+lazy_static! {
+    // Shared by every sink that retries its requests
+    pub static ref RETRY_POLICY: RetryPolicy = RetryPolicy {
+        max_retries: crate::env_usize("SINK_MAX_RETRIES", 8) as u32,
+        base: Duration::from_millis(500),
+        max: Duration::from_secs(60),
+    };
+}
+
+// A mapping and a parsed MFT file holding `records` then a line that can't be read back, with a
+// hash of its own in a new directory, for the tests of the sinks
+#[cfg(test)]
+pub(crate) fn test_file(records: &[serde_json::Value]) -> (Mapping, ParsedFileStats) {
+    let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+    fs::create_dir_all(&dir).unwrap();
+    let mut map = Mapping::default();
+    let pattern = map.index_pattern(&crate::Parser::Mft);
+    let mut lines = String::new();
+    for record in records {
+        map.map_json(record, &pattern);
+        lines.push_str(&format!("{}\n", record));
+    }
+    lines.push_str("{\"FullPath\": \n");
+    let file = ParsedFileStats {
+        parsed_file_path: dir.join("a.data"),
+        parser_used: crate::Parser::Mft,
+        file_hash: Uuid::new_v4().to_string(),
+        ..Default::default()
+    };
+    fs::write(&file.parsed_file_path, lines).unwrap();
+    (map, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn on_complete_spec() {
+        let spec: OnComplete = serde_json::from_value(json!({"sink": "elastic"})).unwrap();
+        assert_eq!(
+            spec,
+            OnComplete {
+                sink: Sink::Elastic,
+                per_file: false
+            }
+        );
+        assert!(serde_json::from_value::<OnComplete>(json!({"sink": "nowhere"})).is_err());
+    }
+
+    #[test]
+    fn backoff_test() {
+        let policy = RetryPolicy {
+            max_retries: 8,
+            base: Duration::from_millis(500),
+            max: Duration::from_secs(60),
+        };
+        for attempt in 0..12 {
+            let exp = Duration::from_millis(500 * 2u64.pow(attempt)).min(policy.max);
+            let delay = policy.backoff(attempt);
+            assert!(delay >= exp / 2 && delay <= exp);
+        }
+    }
+}
@@ -0,0 +1,327 @@
+use crate::{
+    elastic::sanitise_string_elastic,
+    error::CustomError,
+    sink::RETRY_POLICY,
+    type_map::{get_value, skip_failed, Mapping, ParsedFileStats},
+};
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+use type_casting::Types;
+
+#[derive(Debug, Clone)]
+pub struct HecConfig {
+    pub address: String,
+    pub token: String,
+    // Fixed index, otherwise derived from the generated index pattern
+    pub index: Option<String>,
+    // Use indexer acknowledgement, the token must have it enabled
+    pub ack: bool,
+    pub ack_timeout: Duration,
+    // Splunk ships with a self signed certificate
+    pub insecure: bool,
+    pub max_bytes: usize,
+    pub max_events: usize,
+}
+
+lazy_static! {
+    pub static ref HEC_CONFIG: HecConfig = HecConfig {
+        address: std::env::var("SPLUNK_HEC_ADDRESS")
+            .unwrap_or_else(|_| "https://0.0.0.0:8088".to_string()),
+        token: std::env::var("SPLUNK_HEC_TOKEN").unwrap_or_default(),
+        index: std::env::var("SPLUNK_INDEX").ok(),
+        ack: crate::env_bool("SPLUNK_HEC_ACK", false),
+        ack_timeout: Duration::from_secs(crate::env_usize("SPLUNK_HEC_ACK_TIMEOUT", 60) as u64),
+        insecure: crate::env_bool("SPLUNK_HEC_INSECURE", false),
+        max_bytes: crate::env_usize("SPLUNK_HEC_MAX_BYTES", 1024 * 1024),
+        max_events: crate::env_usize("SPLUNK_HEC_MAX_EVENTS", 1000),
+    };
+}
+
+mod response {
+    use serde::Deserialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, Deserialize)]
+    pub struct Event {
+        pub text: String,
+        pub code: u64,
+        #[serde(rename = "ackId")]
+        pub ack_id: Option<u64>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Ack {
+        pub acks: BTreeMap<String, bool>,
+    }
+}
+
+// Splunk index names are lowercase letters, digits, `_` and `-`, not starting with either
+pub fn splunk_index(pattern: &str) -> String {
+    sanitise_string_elastic(pattern)
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .collect::<String>()
+        .trim_start_matches(&['_', '-'][..])
+        .to_string()
+}
+
+// Epoch seconds of the record's inferred timestamp
+fn event_time(record: &serde_json::Value, field: Option<&str>) -> Option<f64> {
+    let value = get_value(record, field?)?.as_str()?;
+    Types::str_date(value)
+        .ok()
+        .map(|date| date.timestamp_millis() as f64 / 1000.0)
+}
+
+struct Hec<'a> {
+    config: &'a HecConfig,
+    client: reqwest::blocking::Client,
+    channel: uuid::Uuid,
+    // Ack id and the number of events it covers
+    pending: Vec<(u64, usize)>,
+    failed: usize,
+}
+
+impl<'a> Hec<'a> {
+    fn new(config: &'a HecConfig) -> Result<Self, CustomError> {
+        let client = reqwest::blocking::Client::builder()
+            .danger_accept_invalid_certs(config.insecure)
+            .build()
+            .map_err(|e| CustomError::SplunkError(e.into()))?;
+        Ok(Hec {
+            config,
+            client,
+            channel: uuid::Uuid::new_v4(),
+            pending: Vec::new(),
+            failed: 0,
+        })
+    }
+    fn request(&self, path: &str) -> reqwest::blocking::RequestBuilder {
+        self.client
+            .post(format!(
+                "{}/{}",
+                self.config.address.trim_end_matches('/'),
+                path
+            ))
+            .header("Authorization", format!("Splunk {}", self.config.token))
+            .header("X-Splunk-Request-Channel", self.channel.to_string())
+    }
+    // Send a batch of concatenated events, retrying while HEC is busy
+    fn send(&mut self, body: String, events: usize) {
+        let mut attempt = 0;
+        loop {
+            let res = self
+                .request("services/collector/event")
+                .body(body.clone())
+                .send()
+                .map_err(|e| e.to_string())
+                .and_then(|res| match res.status().as_u16() {
+                    429 | 502 | 503 | 504 => Err(format!("HTTP {}", res.status())),
+                    _ => res.json::<response::Event>().map_err(|e| e.to_string()),
+                });
+            match res {
+                Ok(res) if res.code == 0 => {
+                    if let (true, Some(id)) = (self.config.ack, res.ack_id) {
+                        self.pending.push((id, events));
+                    }
+                    return;
+                }
+                Ok(res) => {
+                    error!("Splunk HEC rejected {} events: {}", events, res.text);
+                    self.failed += events;
+                    return;
+                }
+                Err(e) if attempt >= RETRY_POLICY.max_retries => {
+                    error!(
+                        "Splunk HEC request failed, giving up on {} events: {}",
+                        events, e
+                    );
+                    self.failed += events;
+                    return;
+                }
+                Err(e) => {
+                    let delay = RETRY_POLICY.backoff(attempt);
+                    warn!("Splunk HEC request failed, retrying in {:?}: {}", delay, e);
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+    // Poll until every sent batch is acknowledged as indexed, anything left at the timeout failed
+    fn wait_for_acks(&mut self) {
+        let start = Instant::now();
+        let mut delay = Duration::from_millis(250);
+        while !self.pending.is_empty() && start.elapsed() < self.config.ack_timeout {
+            let ids = self.pending.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+            let res = self
+                .request("services/collector/ack")
+                .json(&json!({ "acks": ids }))
+                .send()
+                .and_then(|res| res.json::<response::Ack>());
+            match res {
+                Ok(res) => self
+                    .pending
+                    .retain(|(id, _)| res.acks.get(&id.to_string()) != Some(&true)),
+                Err(e) => warn!("Splunk HEC ack request failed: {}", e),
+            }
+            if !self.pending.is_empty() {
+                std::thread::sleep(delay);
+                delay = (delay * 2).min(Duration::from_secs(5));
+            }
+        }
+        let unacked = self.pending.drain(..).map(|(_, n)| n).sum::<usize>();
+        if unacked > 0 {
+            error!("{} events were not acknowledged by Splunk", unacked);
+            self.failed += unacked;
+        }
+    }
+}
+
+// Send a parsed file's casted records to HEC, returns the number of events that failed
+pub fn send_file(
+    config: &HecConfig,
+    map: &Mapping,
+    file: &ParsedFileStats,
+) -> Result<usize, CustomError> {
+    let mut hec = Hec::new(config)?;
+    let mut time_fields = BTreeMap::new();
+    let source = file.source_file_path.display().to_string();
+    let (mut body, mut events) = (String::new(), 0);
+    let mut failed = 0;
+    for res in map.casted_records(file)? {
+        let (pattern, record) = match skip_failed(res, &mut failed)? {
+            Some(record) => record,
+            None => continue,
+        };
+        let time_field = time_fields.entry(pattern.clone()).or_insert_with(|| {
+            map.index_pattern_mappings
+                .get(&pattern)
+                .and_then(crate::kibana::time_field)
+        });
+        let mut event = json!({
+            "sourcetype": format!("ulp:{}", sanitise_string_elastic(&pattern)),
+            "source": source,
+            "index": config.index.clone().unwrap_or_else(|| splunk_index(&pattern)),
+        });
+        if let Some(time) = event_time(&record, time_field.as_deref()) {
+            event["time"] = json!(time);
+        }
+        event["event"] = record;
+        let event = event.to_string();
+        if events > 0
+            && (events >= config.max_events || body.len() + event.len() > config.max_bytes)
+        {
+            hec.send(std::mem::take(&mut body), events);
+            events = 0;
+        }
+        body.push_str(&event);
+        events += 1;
+    }
+    if events > 0 {
+        hec.send(body, events);
+    }
+    hec.wait_for_acks();
+    Ok(hec.failed + failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+
+    // Minimal HEC accepting every event and acknowledging every ack id
+    fn mock_hec(
+        received: Arc<Mutex<Vec<String>>>,
+    ) -> (tokio::runtime::Runtime, std::net::SocketAddr) {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let next_ack = Arc::new(Mutex::new(0u64));
+        let event = warp::path!("services" / "collector" / "event")
+            .and(warp::header::exact("authorization", "Splunk token"))
+            .and(warp::body::bytes())
+            .map(move |body: warp::hyper::body::Bytes| {
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8(body.to_vec()).unwrap());
+                let mut ack = next_ack.lock().unwrap();
+                *ack += 1;
+                warp::reply::json(&json!({"text": "Success", "code": 0, "ackId": *ack - 1}))
+            });
+        let ack = warp::path!("services" / "collector" / "ack")
+            .and(warp::body::json())
+            .map(|body: serde_json::Value| {
+                let acks = body["acks"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| (id.to_string(), true))
+                    .collect::<BTreeMap<_, _>>();
+                warp::reply::json(&json!({ "acks": acks }))
+            });
+        let (addr, server) = rt.block_on(async {
+            warp::serve(warp::post().and(event.or(ack))).bind_ephemeral(([127, 0, 0, 1], 0))
+        });
+        rt.spawn(server);
+        (rt, addr)
+    }
+
+    #[test]
+    fn send_to_mock_hec() {
+        let records = [
+            json!({"FullPath": "a", "Created": "2021-01-01T00:00:00Z"}),
+            json!({"FullPath": "b", "Created": "2021-01-01T00:00:01Z"}),
+            json!({"FullPath": "c", "Created": "2021-01-01T00:00:02Z"}),
+        ];
+        let path = std::env::temp_dir().join(format!("{}.data", uuid::Uuid::new_v4()));
+        let mut map = Mapping::default();
+        let pattern = map.index_pattern(&crate::Parser::Mft);
+        for record in &records {
+            map.map_json(record, &pattern);
+        }
+        std::fs::write(
+            &path,
+            records
+                .iter()
+                .map(|r| format!("{}\n", r))
+                .collect::<String>(),
+        )
+        .unwrap();
+        let file = ParsedFileStats {
+            parsed_file_path: path.clone(),
+            parser_used: crate::Parser::Mft,
+            ..Default::default()
+        };
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let (_rt, addr) = mock_hec(received.clone());
+        let config = HecConfig {
+            address: format!("http://{}", addr),
+            token: "token".into(),
+            index: None,
+            ack: true,
+            ack_timeout: Duration::from_secs(5),
+            insecure: false,
+            max_bytes: 1024,
+            max_events: 2,
+        };
+        let failed = send_file(&config, &map, &file).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(failed, 0);
+        let received = received.lock().unwrap();
+        // Batched by event count
+        assert_eq!(received.len(), 2);
+        let first: serde_json::Value = serde_json::Deserializer::from_str(&received[0])
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(first["index"], "mft");
+        assert_eq!(first["sourcetype"], "ulp:mft");
+        assert_eq!(first["time"], 1609459200.0);
+        assert_eq!(first["event"]["FullPath"], "a");
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{
+    error::CustomError,
+    type_map::{skip_failed, Mapping, ParsedFileStats},
+};
+use rusqlite::{types::Value as SqlValue, Connection};
+use serde_json::Value;
+use std::{collections::BTreeMap, path::Path, time::Duration};
+use type_casting::schema::{
+    flatten, flatten_value, quote_identifier, sql_ddl, sql_type, SqlDialect,
+};
+
+// Per file sink runs have several workers writing to the same database
+fn open(path: impl AsRef<Path>) -> Result<Connection, CustomError> {
+    let conn = Connection::open(path).map_err(|e| CustomError::SqliteError(e.into()))?;
+    conn.busy_timeout(Duration::from_secs(60))
+        .and_then(|_| conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(())))
+        .map_err(|e| CustomError::SqliteError(e.into()))?;
+    Ok(conn)
+}
+
+// Create a table per index pattern, columns the mapping has gained since are added
+pub fn create_tables(path: impl AsRef<Path>, mapping: &Mapping) -> Result<(), CustomError> {
+    let conn = open(path)?;
+    for (index, t) in &mapping.index_pattern_mappings {
+        let ddl = match sql_ddl(index, t, SqlDialect::Sqlite) {
+            Some(ddl) => ddl,
+            None => continue,
+        };
+        conn.execute_batch(&ddl)
+            .map_err(|e| CustomError::SqliteError(e.into()))?;
+        let existing = columns(&conn, index)?;
+        for (column, t) in flatten(t) {
+            if existing.contains(&column) {
+                continue;
+            }
+            conn.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {};",
+                quote_identifier(index),
+                quote_identifier(&column),
+                sql_type(&t, SqlDialect::Sqlite)
+            ))
+            .map_err(|e| CustomError::SqliteError(e.into()))?;
+        }
+    }
+    Ok(())
+}
+
+fn columns(conn: &Connection, table: &str) -> Result<Vec<String>, CustomError> {
+    conn.prepare(&format!("PRAGMA table_info({})", quote_identifier(table)))
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get::<_, String>(1))?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .map_err(|e| CustomError::SqliteError(e.into()))
+}
+
+fn sql_value(value: Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => SqlValue::Text(s),
+        value => SqlValue::Text(value.to_string()),
+    }
+}
+
+// Insert a parsed file's casted records in a single transaction, returns the number of records
+// that could not be inserted
+pub fn insert_file(
+    path: impl AsRef<Path>,
+    map: &Mapping,
+    file: &ParsedFileStats,
+) -> Result<usize, CustomError> {
+    let mut conn = open(path)?;
+    let tx = conn
+        .transaction()
+        .map_err(|e| CustomError::SqliteError(e.into()))?;
+    // Column order of each table's insert statement
+    let mut inserts: BTreeMap<String, (String, Vec<String>)> = BTreeMap::new();
+    let mut failed = 0;
+    for res in map.casted_records(file)? {
+        let (pattern, record) = match skip_failed(res, &mut failed)? {
+            Some(record) => record,
+            None => continue,
+        };
+        let (sql, columns) = match inserts.get(&pattern) {
+            Some(insert) => insert,
+            None => {
+                let columns = map
+                    .index_pattern_mappings
+                    .get(&pattern)
+                    .map(|t| flatten(t).into_iter().map(|(c, _)| c).collect::<Vec<_>>())
+                    .unwrap_or_default();
+                let sql = format!(
+                    "INSERT INTO {} ({}) VALUES ({})",
+                    quote_identifier(&pattern),
+                    columns
+                        .iter()
+                        .map(|c| quote_identifier(c))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    vec!["?"; columns.len()].join(", ")
+                );
+                inserts.entry(pattern.clone()).or_insert((sql, columns))
+            }
+        };
+        // Records without any fields have no table
+        if columns.is_empty() {
+            continue;
+        }
+        let mut values = flatten_value(record);
+        let params = columns
+            .iter()
+            .map(|c| values.remove(c).map(sql_value).unwrap_or(SqlValue::Null))
+            .collect::<Vec<_>>();
+        let res = tx
+            .prepare_cached(sql)
+            .and_then(|mut stmt| stmt.execute(rusqlite::params_from_iter(params)));
+        if let Err(e) = res {
+            debug!("Failed to insert a record into {}: {}", pattern, e);
+            failed += 1;
+        }
+    }
+    tx.commit()
+        .map_err(|e| CustomError::SqliteError(e.into()))?;
+    if failed > 0 {
+        warn!(
+            "{} records from {} could not be inserted",
+            failed,
+            file.parsed_file_path.display()
+        );
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn insert_records() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let records = [
+            json!({"FullPath": "a", "FileSize": 1, "Flags": {"Deleted": true}}),
+            json!({"FullPath": "b", "FileSize": 2, "Flags": {"Deleted": false}, "Names": ["b", "c"]}),
+        ];
+        let mut map = Mapping::default();
+        let pattern = map.index_pattern(&crate::Parser::Mft);
+        map.map_json(&records[0], &pattern);
+        let file = ParsedFileStats {
+            parsed_file_path: dir.join("a.data"),
+            parser_used: crate::Parser::Mft,
+            ..Default::default()
+        };
+        std::fs::write(&file.parsed_file_path, format!("{}\n", records[0])).unwrap();
+        let db = dir.join("job.sqlite");
+        create_tables(&db, &map).unwrap();
+        assert_eq!(insert_file(&db, &map, &file).unwrap(), 0);
+        // A later file widening the mapping adds columns, a record that can't be read back is
+        // counted and the rest of the file is still inserted
+        map.map_json(&records[1], &pattern);
+        std::fs::write(
+            &file.parsed_file_path,
+            format!("{{\"FullPath\": \n{}\n", records[1]),
+        )
+        .unwrap();
+        create_tables(&db, &map).unwrap();
+        assert_eq!(insert_file(&db, &map, &file).unwrap(), 1);
+
+        let conn = Connection::open(&db).unwrap();
+        let rows = conn
+            .prepare(r#"SELECT "FullPath", "FileSize", "Flags.Deleted", "Names" FROM "mft""#)
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, bool>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ("a".to_string(), 1, true, None),
+                ("b".to_string(), 2, false, Some(r#"["b","c"]"#.to_string())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+use crate::error::CustomError;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use uuid::Uuid;
+
+lazy_static! {
+    pub static ref STORAGE: Storage = Storage::new(
+        crate::UPLOAD_DIR_PATH.as_str(),
+        crate::env_usize("STORAGE_MIN_FREE_BYTES", 1024 * 1024 * 1024) as u64,
+    );
+}
+
+// Where everything ulp writes lives, one directory per job under the UPLOAD_DIR root
+#[derive(Debug, Clone)]
+pub struct Storage {
+    root: PathBuf,
+    // Space left free on top of a job's input size when it starts
+    min_free: u64,
+}
+
+impl Storage {
+    pub fn new(root: impl Into<PathBuf>, min_free: u64) -> Self {
+        Storage {
+            root: root.into(),
+            min_free,
+        }
+    }
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    pub fn job_dir(&self, job: Uuid) -> PathBuf {
+        self.root.join(job.to_string())
+    }
+    pub fn create_job_dir(&self, job: Uuid) -> Result<PathBuf, CustomError> {
+        let dir = self.job_dir(job);
+        fs::create_dir_all(&dir).map_err(|e| {
+            CustomError::StorageError(format!("Failed to create {}: {}", dir.display(), e).into())
+        })?;
+        Ok(dir)
+    }
+    // The completed job, its mapping and parsed file stats
+    pub fn mapping_path(&self, job: Uuid) -> PathBuf {
+        self.job_dir(job).join("mappings.json")
+    }
+    // A task's parsed records, see `data_file` for the compressed and rotated names
+    pub fn data_path(&self, job: Uuid, task: Uuid) -> PathBuf {
+        self.job_dir(job).join(format!("{}.data", task))
+    }
+    // Parsing status of a job, until its mapping file is written
+    pub fn job_status_path(&self, job: Uuid) -> PathBuf {
+        self.job_dir(job).join("status.json")
+    }
+    pub fn ingest_status_path(&self, job: Uuid) -> PathBuf {
+        self.job_dir(job).join("ingest.json")
+    }
+    pub fn dead_letter_path(&self, job: Uuid) -> PathBuf {
+        self.job_dir(job).join("dead_letter.ndjson")
+    }
+    // Dead letters taken aside while they are replayed
+    pub fn replay_path(&self, job: Uuid) -> PathBuf {
+        self.job_dir(job)
+            .join(format!("dead_letter.{}.replay", Uuid::new_v4()))
+    }
+    pub fn sqlite_path(&self, job: Uuid) -> PathBuf {
+        self.job_dir(job).join("job.sqlite")
+    }
+    pub fn export_dir(&self, job: Uuid) -> PathBuf {
+        self.job_dir(job).join("export")
+    }
+    // Members extracted from a job's archives
+    pub fn archive_dir(&self, job: Uuid) -> PathBuf {
+        self.job_dir(job).join("archives")
+    }
+    pub fn baseline_dir(&self) -> PathBuf {
+        self.root.join("baselines")
+    }
+    // An upload being received, and its progress
+    pub fn upload_path(&self, id: Uuid) -> PathBuf {
+        self.root.join("uploads").join(format!("{}.part", id))
+    }
+    pub fn upload_state_path(&self, id: Uuid) -> PathBuf {
+        self.root.join("uploads").join(format!("{}.json", id))
+    }
+    // A verified upload, in a directory of its own so names can't clash
+    pub fn evidence_path(&self, id: Uuid, name: &str) -> PathBuf {
+        self.root.join("evidence").join(id.to_string()).join(name)
+    }
+    // Folders being watched, and the SHA-256 of files they have created jobs for
+    pub fn watches_path(&self) -> PathBuf {
+        self.root.join("watch").join("watches.json")
+    }
+    pub fn watch_seen_path(&self) -> PathBuf {
+        self.root.join("watch").join("sha256.txt")
+    }
+    // Replace a file's contents in one step, so it is never read half written
+    pub fn write(
+        &self,
+        path: impl AsRef<Path>,
+        contents: impl AsRef<[u8]>,
+    ) -> Result<(), CustomError> {
+        let path = path.as_ref();
+        let err = |e: std::io::Error| {
+            CustomError::StorageError(format!("Failed to write {}: {}", path.display(), e).into())
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(err)?;
+        }
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".{}.tmp", Uuid::new_v4()));
+        fs::write(&tmp, contents).map_err(err)?;
+        fs::rename(&tmp, path).map_err(err)
+    }
+    // Check there is room for `bytes` more, on top of STORAGE_MIN_FREE_BYTES
+    pub fn ensure_space(&self, bytes: u64) -> Result<(), CustomError> {
+        fs::create_dir_all(&self.root).map_err(|e| {
+            CustomError::StorageError(
+                format!("Failed to create {}: {}", self.root.display(), e).into(),
+            )
+        })?;
+        let needed = bytes.saturating_add(self.min_free);
+        match free_space(&self.root) {
+            Some(free) if free < needed => Err(CustomError::StorageError(
+                format!(
+                    "Not enough free space in {}, {} bytes are free and {} are needed",
+                    self.root.display(),
+                    free,
+                    needed
+                )
+                .into(),
+            )),
+            _ => Ok(()),
+        }
+    }
+    // Create a job's directory once there is room for it, its parsed data is estimated to take
+    // as much space as its input files
+    pub fn prepare_job(&self, job: Uuid, inputs: &[PathBuf]) -> Result<(), CustomError> {
+        let input = inputs
+            .iter()
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|m| m.len())
+            .sum::<u64>();
+        self.ensure_space(input).map_err(|e| {
+            CustomError::StorageError(format!("Job {} can't be started. {}", job, e).into())
+        })?;
+        self.create_job_dir(job).map(|_| ())
+    }
+}
+
+// Bytes available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: `path` is NUL terminated and `stat` is only read once statvfs has filled it
+    match unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } {
+        0 => {
+            let stat = unsafe { stat.assume_init() };
+            // Field widths differ between platforms
+            #[allow(clippy::unnecessary_cast)]
+            Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn job_paths() {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let storage = Storage::new(&root, 0);
+        let (job, task) = (Uuid::new_v4(), Uuid::new_v4());
+        assert_eq!(
+            storage.data_path(job, task),
+            root.join(job.to_string()).join(format!("{}.data", task))
+        );
+        storage.prepare_job(job, &[]).unwrap();
+        assert!(storage.job_dir(job).is_dir());
+        storage.write(storage.mapping_path(job), "{}").unwrap();
+        storage.write(storage.mapping_path(job), "[]").unwrap();
+        assert_eq!(fs::read_to_string(storage.mapping_path(job)).unwrap(), "[]");
+        assert_eq!(fs::read_dir(storage.job_dir(job)).unwrap().count(), 1);
+        // No filesystem has this much room
+        let err = Storage::new(&root, u64::MAX / 2).prepare_job(job, &[]);
+        assert!(matches!(err, Err(CustomError::StorageError(_))));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
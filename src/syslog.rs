@@ -0,0 +1,529 @@
+use crate::{
+    error::CustomError,
+    sink::RETRY_POLICY,
+    type_map::{get_value, skip_failed, Mapping, ParsedFileStats},
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    net::{TcpStream, UdpSocket},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use type_casting::{schema::flatten_value, Types};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Cef,
+    Leef,
+}
+
+// How the records of an index pattern are turned into events, fields are dotted record paths
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct FieldMap {
+    // CEF Device Event Class ID / LEEF Event ID, the index pattern when unset
+    pub signature_id: Option<String>,
+    // CEF Name / LEEF `cat`, the index pattern when unset
+    pub name: Option<String>,
+    // 0-10, 5 when unset or not a number
+    pub severity: Option<String>,
+    // Syslog HOSTNAME
+    pub host: Option<String>,
+    // Only forward records whose fields equal these values, a list matches any of its values
+    #[serde(default)]
+    pub filter: BTreeMap<String, Value>,
+    // Extension key to field, every field under its path when empty
+    #[serde(default)]
+    pub extensions: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyslogConfig {
+    pub address: String,
+    pub transport: Transport,
+    pub format: Format,
+    pub facility: u8,
+    // Events per second, 0 is unlimited
+    pub rate: usize,
+    pub insecure: bool,
+    // JSON file of field maps keyed by index pattern prefix, only matching patterns are forwarded
+    pub field_map: Option<String>,
+}
+
+lazy_static! {
+    pub static ref SYSLOG_CONFIG: SyslogConfig = SyslogConfig {
+        address: std::env::var("SYSLOG_ADDRESS").unwrap_or_else(|_| "0.0.0.0:514".to_string()),
+        transport: crate::env_enum("SYSLOG_TRANSPORT", Transport::Udp),
+        format: crate::env_enum("SYSLOG_FORMAT", Format::Cef),
+        facility: crate::env_usize("SYSLOG_FACILITY", 1) as u8,
+        rate: crate::env_usize("SYSLOG_RATE", 0),
+        insecure: crate::env_bool("SYSLOG_INSECURE", false),
+        field_map: std::env::var("SYSLOG_FIELD_MAP").ok(),
+    };
+    // When the next message may be sent under SYSLOG_RATE, shared by every worker forwarding
+    static ref NEXT_SLOT: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+// Largest UDP payload over IPv4, longer messages can't be sent as one datagram
+const UDP_MAX_BYTES: usize = 65507;
+
+// Field maps of the configured file, without one every index pattern is forwarded with defaults
+pub fn field_maps(
+    config: &SyslogConfig,
+) -> Result<Option<BTreeMap<String, FieldMap>>, CustomError> {
+    let path = match &config.field_map {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let contents = std::fs::read_to_string(path).map_err(|e| CustomError::SyslogError(e.into()))?;
+    serde_json::from_str(&contents)
+        .map(Some)
+        .map_err(|e| CustomError::SyslogError(e.into()))
+}
+
+// The field map of the longest prefix of the index pattern
+fn field_map<'a>(
+    maps: &'a Option<BTreeMap<String, FieldMap>>,
+    pattern: &str,
+) -> Option<&'a FieldMap> {
+    lazy_static! {
+        static ref DEFAULT: FieldMap = FieldMap::default();
+    }
+    match maps {
+        Some(maps) => maps
+            .iter()
+            .filter(|(prefix, _)| pattern.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, map)| map),
+        None => Some(&DEFAULT),
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+fn field(record: &Value, path: Option<&str>) -> Option<String> {
+    path.and_then(|path| get_value(record, path)).and_then(text)
+}
+
+fn selected(map: &FieldMap, record: &Value) -> bool {
+    map.filter.iter().all(|(path, expected)| {
+        let value = get_value(record, path).unwrap_or(&Value::Null);
+        match expected {
+            Value::Array(values) => values.contains(value),
+            expected => expected == value,
+        }
+    })
+}
+
+fn extensions(map: &FieldMap, record: &Value) -> Vec<(String, String)> {
+    match map.extensions.is_empty() {
+        true => flatten_value(record.clone())
+            .into_iter()
+            .filter_map(|(path, value)| Some((path, text(&value)?)))
+            .collect(),
+        false => map
+            .extensions
+            .iter()
+            .filter_map(|(key, path)| Some((key.clone(), field(record, Some(path))?)))
+            .collect(),
+    }
+}
+
+fn header_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace(['\r', '\n'], " ")
+}
+
+fn cef_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('=', "\\=")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+}
+
+// Extension keys can't hold spaces or the separators
+fn key(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_whitespace() && *c != '=' && *c != '|')
+        .collect()
+}
+
+fn severity(map: &FieldMap, record: &Value) -> u8 {
+    field(record, map.severity.as_deref())
+        .and_then(|s| s.parse::<u8>().ok())
+        .map_or(5, |s| s.min(10))
+}
+
+pub fn cef(map: &FieldMap, pattern: &str, record: &Value, time: Option<DateTime<Utc>>) -> String {
+    let mut ext = extensions(map, record);
+    if let Some(time) = time {
+        ext.insert(0, ("rt".to_string(), time.timestamp_millis().to_string()));
+    }
+    format!(
+        "CEF:0|ulp|ulp|{}|{}|{}|{}|{}",
+        env!("CARGO_PKG_VERSION"),
+        header_escape(
+            &field(record, map.signature_id.as_deref()).unwrap_or_else(|| pattern.to_string())
+        ),
+        header_escape(&field(record, map.name.as_deref()).unwrap_or_else(|| pattern.to_string())),
+        severity(map, record),
+        ext.iter()
+            .map(|(k, v)| format!("{}={}", key(k), cef_escape(v)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    )
+}
+
+// LEEF 1.0, attributes are tab delimited
+pub fn leef(map: &FieldMap, pattern: &str, record: &Value, time: Option<DateTime<Utc>>) -> String {
+    let mut ext = vec![
+        (
+            "cat".to_string(),
+            field(record, map.name.as_deref()).unwrap_or_else(|| pattern.to_string()),
+        ),
+        ("sev".to_string(), severity(map, record).to_string()),
+    ];
+    if let Some(time) = time {
+        ext.push(("devTime".to_string(), time.timestamp_millis().to_string()));
+        ext.push(("devTimeFormat".to_string(), "epoch".to_string()));
+    }
+    ext.extend(extensions(map, record));
+    format!(
+        "LEEF:1.0|ulp|ulp|{}|{}|{}",
+        env!("CARGO_PKG_VERSION"),
+        header_escape(
+            &field(record, map.signature_id.as_deref()).unwrap_or_else(|| pattern.to_string())
+        ),
+        ext.iter()
+            .map(|(k, v)| format!("{}={}", key(k), v.replace(['\t', '\r', '\n'], " ")))
+            .collect::<Vec<_>>()
+            .join("\t")
+    )
+}
+
+// RFC5424 header fields are printable ASCII without spaces
+fn header_field(s: Option<String>, max: usize) -> String {
+    let s = s
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect::<String>();
+    match s.is_empty() {
+        true => "-".to_string(),
+        false => s,
+    }
+}
+
+// An RFC5424 message with informational severity, timestamped with the record's own time
+pub fn rfc5424(
+    facility: u8,
+    time: Option<DateTime<Utc>>,
+    host: Option<String>,
+    msgid: &str,
+    msg: &str,
+) -> String {
+    format!(
+        "<{}>1 {} {} ulp - {} - {}",
+        facility as u16 * 8 + 6,
+        time.map_or_else(
+            || "-".to_string(),
+            |t| t.to_rfc3339_opts(SecondsFormat::Micros, true)
+        ),
+        header_field(host, 255),
+        header_field(Some(msgid.to_string()), 32),
+        msg
+    )
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+    Tls(Box<native_tls::TlsStream<TcpStream>>),
+}
+
+impl Connection {
+    fn open(config: &SyslogConfig) -> Result<Self, CustomError> {
+        let err = |e: Box<dyn std::error::Error>| CustomError::SyslogError(e);
+        match config.transport {
+            Transport::Udp => {
+                let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| err(e.into()))?;
+                socket.connect(&config.address).map_err(|e| err(e.into()))?;
+                Ok(Connection::Udp(socket))
+            }
+            Transport::Tcp => TcpStream::connect(&config.address)
+                .map(Connection::Tcp)
+                .map_err(|e| err(e.into())),
+            Transport::Tls => {
+                let connector = native_tls::TlsConnector::builder()
+                    .danger_accept_invalid_certs(config.insecure)
+                    .build()
+                    .map_err(|e| err(e.into()))?;
+                let stream = TcpStream::connect(&config.address).map_err(|e| err(e.into()))?;
+                let domain = config
+                    .address
+                    .rsplit_once(':')
+                    .map_or(config.address.as_str(), |(host, _)| host);
+                connector
+                    .connect(domain, stream)
+                    .map(|s| Connection::Tls(Box::new(s)))
+                    .map_err(|e| err(e.to_string().into()))
+            }
+        }
+    }
+    // One message per datagram, stream transports use octet counting (RFC6587)
+    fn send(&mut self, message: &str) -> std::io::Result<()> {
+        let framed = format!("{} {}", message.len(), message);
+        match self {
+            Connection::Udp(socket) => socket.send(truncate(message, UDP_MAX_BYTES)).map(|_| ()),
+            Connection::Tcp(stream) => stream.write_all(framed.as_bytes()),
+            Connection::Tls(stream) => stream.write_all(framed.as_bytes()),
+        }
+    }
+}
+
+// A message cut to at most `max` bytes, on a character boundary
+fn truncate(message: &str, max: usize) -> &[u8] {
+    if message.len() <= max {
+        return message.as_bytes();
+    }
+    let end = (0..=max)
+        .rev()
+        .find(|&i| message.is_char_boundary(i))
+        .unwrap_or(0);
+    warn!(
+        "Truncated a {} byte syslog message to {} bytes to fit a datagram",
+        message.len(),
+        end
+    );
+    &message.as_bytes()[..end]
+}
+
+// Wait for the next slot under SYSLOG_RATE. The slot is taken before sleeping so concurrent
+// workers queue up behind each other and the rate holds across all of them.
+fn pace(rate: usize) {
+    if rate == 0 {
+        return;
+    }
+    let wait = {
+        let mut next = NEXT_SLOT.lock().unwrap();
+        let now = Instant::now();
+        let slot = (*next).max(now);
+        *next = slot + Duration::from_secs(1) / rate as u32;
+        slot - now
+    };
+    std::thread::sleep(wait);
+}
+
+struct Forwarder<'a> {
+    config: &'a SyslogConfig,
+    connection: Option<Connection>,
+    failed: usize,
+}
+
+impl<'a> Forwarder<'a> {
+    // Send a message, reconnecting while the receiver is unavailable
+    fn send(&mut self, message: &str) {
+        pace(self.config.rate);
+        let mut attempt = 0;
+        loop {
+            let res = match &mut self.connection {
+                Some(connection) => connection.send(message).map_err(|e| e.to_string()),
+                None => Connection::open(self.config)
+                    .and_then(|mut connection| {
+                        let res = connection
+                            .send(message)
+                            .map_err(|e| CustomError::SyslogError(e.into()));
+                        self.connection = Some(connection);
+                        res
+                    })
+                    .map_err(|e| e.to_string()),
+            };
+            match res {
+                Ok(_) => return,
+                Err(e) if attempt >= RETRY_POLICY.max_retries => {
+                    error!("Failed to forward a syslog message, giving up: {}", e);
+                    self.failed += 1;
+                    return;
+                }
+                Err(e) => {
+                    let delay = RETRY_POLICY.backoff(attempt);
+                    warn!(
+                        "Failed to forward a syslog message, retrying in {:?}: {}",
+                        delay, e
+                    );
+                    self.connection = None;
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+// Forward a parsed file's selected casted records, returns the number of messages that failed
+pub fn send_file(
+    config: &SyslogConfig,
+    map: &Mapping,
+    file: &ParsedFileStats,
+) -> Result<usize, CustomError> {
+    let maps = field_maps(config)?;
+    let mut forwarder = Forwarder {
+        config,
+        connection: None,
+        failed: 0,
+    };
+    let mut time_fields = BTreeMap::new();
+    for res in map.casted_records(file)? {
+        let (pattern, record) = match skip_failed(res, &mut forwarder.failed)? {
+            Some(record) => record,
+            None => continue,
+        };
+        let field_map = match field_map(&maps, &pattern) {
+            Some(field_map) if selected(field_map, &record) => field_map,
+            _ => continue,
+        };
+        let time_field = time_fields.entry(pattern.clone()).or_insert_with(|| {
+            map.index_pattern_mappings
+                .get(&pattern)
+                .and_then(crate::kibana::time_field)
+        });
+        let time = field(&record, time_field.as_deref()).and_then(|s| Types::str_date(&s).ok());
+        let msg = match config.format {
+            Format::Cef => cef(field_map, &pattern, &record, time),
+            Format::Leef => leef(field_map, &pattern, &record, time),
+        };
+        let host = field(&record, field_map.host.as_deref());
+        forwarder.send(&rfc5424(config.facility, time, host, &pattern, &msg));
+    }
+    Ok(forwarder.failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::io::Read;
+
+    #[test]
+    fn format_events() {
+        let record = json!({
+            "Event": {"System": {"EventID": 4624, "Computer": "WS01", "Channel": "Security"}},
+            "Message": "a=b|c\nd"
+        });
+        let map: FieldMap = serde_json::from_value(json!({
+            "signature_id": "Event.System.EventID",
+            "name": "Event.System.Channel",
+            "host": "Event.System.Computer",
+            "filter": {"Event.System.EventID": [4624, 4625]},
+            "extensions": {"dhost": "Event.System.Computer", "msg": "Message"}
+        }))
+        .unwrap();
+        assert!(selected(&map, &record));
+        assert!(!selected(
+            &map,
+            &json!({"Event": {"System": {"EventID": 1}}})
+        ));
+        let time = Types::str_date("2021-01-01T00:00:00Z").ok();
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            cef(&map, "evtx", &record, time),
+            format!(
+                "CEF:0|ulp|ulp|{}|4624|Security|5|rt=1609459200000 dhost=WS01 msg=a\\=b|c\\nd",
+                version
+            )
+        );
+        assert_eq!(
+            leef(&map, "evtx", &record, None),
+            format!(
+                "LEEF:1.0|ulp|ulp|{}|4624|cat=Security\tsev=5\tdhost=WS01\tmsg=a=b|c d",
+                version
+            )
+        );
+        assert_eq!(
+            rfc5424(1, time, Some("WS 01".to_string()), "evtx_Security", "m"),
+            "<14>1 2021-01-01T00:00:00.000000Z WS01 ulp - evtx_Security - m"
+        );
+        // Without a field map every field is an extension
+        assert_eq!(
+            cef(&FieldMap::default(), "mft", &json!({"a": {"b": 1}}), None),
+            format!("CEF:0|ulp|ulp|{}|mft|mft|5|a.b=1", version)
+        );
+        // Cut on a character boundary to fit a datagram
+        assert_eq!(truncate("aé", 2), b"a");
+        assert_eq!(truncate("ab", 2), b"ab");
+    }
+
+    #[test]
+    fn forward_over_tcp() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = SyslogConfig {
+            address: listener.local_addr().unwrap().to_string(),
+            transport: Transport::Tcp,
+            format: Format::Cef,
+            facility: 1,
+            rate: 0,
+            insecure: false,
+            field_map: None,
+        };
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let records = [json!({"FullPath": "a"}), json!({"FullPath": "b"})];
+        let mut map = Mapping::default();
+        let pattern = map.index_pattern(&crate::Parser::Mft);
+        map.map_json(&records[0], &pattern);
+        let file = ParsedFileStats {
+            parsed_file_path: dir.join("a.data"),
+            parser_used: crate::Parser::Mft,
+            ..Default::default()
+        };
+        std::fs::write(
+            &file.parsed_file_path,
+            format!("{}\n{}\n", records[0], records[1]),
+        )
+        .unwrap();
+        let receiver = std::thread::spawn(move || {
+            let mut received = String::new();
+            listener
+                .accept()
+                .unwrap()
+                .0
+                .read_to_string(&mut received)
+                .unwrap();
+            received
+        });
+        assert_eq!(send_file(&config, &map, &file).unwrap(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+        let message = |path: &str| {
+            let message = format!(
+                "<14>1 - - ulp - mft - CEF:0|ulp|ulp|{}|mft|mft|5|FullPath={}",
+                env!("CARGO_PKG_VERSION"),
+                path
+            );
+            format!("{} {}", message.len(), message)
+        };
+        assert_eq!(
+            receiver.join().unwrap(),
+            format!("{}{}", message("a"), message("b"))
+        );
+    }
+}
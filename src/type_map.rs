@@ -1,4 +1,6 @@
-use crate::error::CustomError;
+use crate::{
+    archive::ArchiveMember, collection::Collection, error::CustomError, overrides::Overrides,
+};
 use std::{
     collections::BTreeMap,
     fs, io,
@@ -35,6 +37,18 @@ impl From<&str> for IndexPatternObject {
 }
 
 impl IndexPatternObject {
+    // Read the evaluated parts from below a field, ie. once a record has been nested by ECS
+    pub fn namespaced(self, namespace: &str) -> Self {
+        let parts = self
+            .parts
+            .into_iter()
+            .map(|(key, eval)| match eval {
+                true => (format!("{}.{}", namespace, key), eval),
+                false => (key, eval),
+            })
+            .collect();
+        Self { parts }
+    }
     pub fn generate_index_pattern(&self, data: &serde_json::Value) -> String {
         let mut path = String::new();
         for (key, eval) in self.parts.iter() {
@@ -62,7 +76,28 @@ impl IndexPatternObject {
     }
 }
 
-fn get_value<'a>(value: &'a serde_json::Value, key: &str) -> Option<&'a serde_json::Value> {
+// A record from `Mapping::casted_records`, None when it couldn't be read back or cast. That is
+// logged and counted in `failed`, any other error ends the file.
+pub(crate) fn skip_failed<T>(
+    res: Result<T, CustomError>,
+    failed: &mut usize,
+) -> Result<Option<T>, CustomError> {
+    match res {
+        Ok(record) => Ok(Some(record)),
+        Err(e @ CustomError::RecordError { .. }) => {
+            warn!("{}", e);
+            *failed += 1;
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Value at a dotted path, numeric parts index into lists
+pub(crate) fn get_value<'a>(
+    value: &'a serde_json::Value,
+    key: &str,
+) -> Option<&'a serde_json::Value> {
     fn recurse<'a>(keys: &[&str], data: &'a serde_json::Value) -> Option<&'a serde_json::Value> {
         if let Some(key) = keys.get(0) {
             match key.parse::<usize>() {
@@ -95,6 +130,11 @@ pub struct Mapping {
     //
     pub file_mapping: Vec<ParsedFileStats>,
     // pub change_log: Vec<()>,
+    #[serde(default)]
+    pub overrides: Overrides,
+    // Records are normalised to ECS before being written and mapped
+    #[serde(default)]
+    pub ecs: bool,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Default)]
@@ -105,15 +145,46 @@ pub struct ParsedFileStats {
     pub file_size: u64,
     pub file_hash: String,
     pub parser_used: crate::Parser,
+    // Set when the source file was extracted from an archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<ArchiveMember>,
+    // Set when the source file was found in a KAPE or Velociraptor collection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub collection: Option<Collection>,
 }
 
 impl Mapping {
+    pub fn with_overrides(overrides: Overrides) -> Self {
+        Self {
+            overrides,
+            ..Default::default()
+        }
+    }
+    pub fn index_pattern(&self, parser: &crate::Parser) -> IndexPatternObject {
+        let pattern = IndexPatternObject::from(parser.default_index_pattern());
+        match self.ecs {
+            true => pattern.namespaced(crate::ecs::namespace(parser)),
+            false => pattern,
+        }
+    }
+    // Parsers used by the job's files, in the order first used
+    pub fn parsers(&self) -> Vec<&crate::Parser> {
+        let mut parsers = Vec::new();
+        for file in &self.file_mapping {
+            if !parsers.contains(&&file.parser_used) {
+                parsers.push(&file.parser_used);
+            }
+        }
+        parsers
+    }
     pub fn add_parsed_file<P: AsRef<Path> + Into<PathBuf>>(
         &mut self,
         job_uuid: uuid::Uuid,
         uuid: uuid::Uuid,
         path: P,
         parser_used: crate::Parser,
+        archive: Option<ArchiveMember>,
+        collection: Option<Collection>,
     ) -> Result<(), CustomError> {
         use sha2::Digest;
         let mut hash_digest = sha2::Sha256::new();
@@ -136,11 +207,10 @@ impl Mapping {
             parsed_file_uuid: uuid,
             source_file_path: path.into(),
             // Generate path
-            parsed_file_path: fs::canonicalize(format!(
-                "{}/{}/{}.data",
-                crate::UPLOAD_DIR_ENV,
-                job_uuid,
-                uuid
+            parsed_file_path: fs::canonicalize(crate::data_file::segment_path(
+                crate::storage::STORAGE.data_path(job_uuid, uuid),
+                0,
+                crate::data_file::DATA_CONFIG.compression,
             ))
             .map_err(|e| {
                 CustomError::StatGenerationError(
@@ -150,27 +220,79 @@ impl Mapping {
             file_size,
             file_hash,
             parser_used,
+            archive,
+            collection,
         });
         Ok(())
     }
     pub fn map_json(&mut self, value: &serde_json::Value, index_pattern: &IndexPatternObject) {
         // Update global map
-        let value_type = Types::get_type(value);
-        merge(&mut self.map, value_type);
+        let mut value_type = Types::get_type(value);
+        let mut global_type = value_type.clone();
+        self.overrides.apply(None, &mut global_type);
+        merge(&mut self.map, global_type);
         // self.map = new_map;
         // Index pattern
         let pattern = index_pattern.generate_index_pattern(value);
+        self.overrides.apply(Some(&pattern), &mut value_type);
         match self.index_pattern_mappings.remove(&pattern) {
             Some(mut index_map) => {
-                merge(&mut index_map, Types::get_type(value));
+                merge(&mut index_map, value_type);
                 self.index_pattern_mappings.insert(pattern, index_map);
             }
             None => {
-                self.index_pattern_mappings
-                    .insert(pattern, Types::get_type(value));
+                self.index_pattern_mappings.insert(pattern, value_type);
             }
         }
     }
+    // Read a parsed file back as (index pattern, casted record) pairs
+    pub fn casted_records<'a>(
+        &'a self,
+        file: &ParsedFileStats,
+    ) -> Result<
+        impl Iterator<Item = Result<(String, serde_json::Value), CustomError>> + 'a,
+        CustomError,
+    > {
+        self.casted_records_from(file, 0)
+    }
+    // As above, starting after the file's first `skip` records
+    pub fn casted_records_from<'a>(
+        &'a self,
+        file: &ParsedFileStats,
+        skip: usize,
+    ) -> Result<
+        impl Iterator<Item = Result<(String, serde_json::Value), CustomError>> + 'a,
+        CustomError,
+    > {
+        let index_pattern = self.index_pattern(&file.parser_used);
+        let lines =
+            crate::data_file::read_lines_from(&file.parsed_file_path, skip).map_err(|e| {
+                CustomError::TypeCastError(
+                    format!(
+                        "Failed to read parsed file {}: {}",
+                        file.parsed_file_path.display(),
+                        e
+                    )
+                    .into(),
+                )
+            })?;
+        // A line that can't be read ends the file, a record that can't be parsed or cast is
+        // returned as a RecordError for the sink to count and carry on
+        Ok(lines.map(move |line| {
+            let line = line.map_err(|e| CustomError::TypeCastError(e.into()))?;
+            let failed = |index: String, err: Box<dyn std::error::Error>| {
+                let record = serde_json::from_str(&line)
+                    .unwrap_or_else(|_| serde_json::Value::String(line.clone()));
+                CustomError::RecordError { err, index, record }
+            };
+            let json = serde_json::from_str(&line).map_err(|e| failed(String::new(), e.into()))?;
+            let pattern = index_pattern.generate_index_pattern(&json);
+            match self.cast_json(json, Some(&pattern)) {
+                Ok(json) => Ok((pattern, json)),
+                Err(e) => Err(failed(pattern, e.into())),
+            }
+        }))
+    }
     pub fn cast_json(
         &self,
         value: serde_json::Value,
@@ -181,6 +303,8 @@ impl Mapping {
                 CustomError::TypeCastError(format!("Failed to cast type, {:?}", e).into())
             }),
             Some(pattern) => {
+                let mut value = value;
+                self.overrides.apply_value(pattern, &mut value)?;
                 let map = self.index_pattern_mappings.get(pattern).ok_or_else(|| {
                     CustomError::TypeCastError(
                         format!(
@@ -214,5 +338,10 @@ mod tests {
             }
         });
         assert_eq!(pattern.generate_index_pattern(&data), "apple_aaa_pear_bbb");
+        let pattern = super::IndexPatternObject::from("{{x.y}}_aaa").namespaced("evtx");
+        assert_eq!(
+            pattern.generate_index_pattern(&json!({ "evtx": data })),
+            "apple_aaa"
+        );
     }
 }
@@ -0,0 +1,349 @@
+use crate::{
+    error::CustomError,
+    job::{Job, JobSpec},
+    sink::OnComplete,
+    storage::Storage,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+lazy_static! {
+    // Largest chunk accepted by PATCH /upload/{id}
+    pub static ref UPLOAD_CHUNK_BYTES: u64 =
+        crate::env_usize("UPLOAD_CHUNK_BYTES", 64 * 1024 * 1024) as u64;
+    // Chunks of an upload are appended one at a time so its offset stays consistent. Only
+    // uploads with a request in flight have an entry.
+    static ref LOCKS: Mutex<HashMap<Uuid, Arc<Mutex<()>>>> = Mutex::new(HashMap::new());
+}
+
+// Run `f` holding an upload's lock, which is evicted once no other request is waiting on it
+fn locked<T>(id: Uuid, f: impl FnOnce() -> T) -> T {
+    let lock = LOCKS.lock().unwrap().entry(id).or_default().clone();
+    let res = {
+        let _guard = lock.lock().unwrap();
+        f()
+    };
+    let mut locks = LOCKS.lock().unwrap();
+    // Held by LOCKS and this request alone, clones are only taken under the LOCKS mutex
+    if Arc::strong_count(&lock) == 2 {
+        locks.remove(&id);
+    }
+    res
+}
+
+// Body of POST /upload, ie.
+// {"name": "Security.evtx", "size": 1052672, "sha256": "...", "job": {"on_complete": {"sink": "elastic"}}}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UploadSpec {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    // Create a job for the file once it has been received
+    #[serde(default)]
+    pub job: Option<UploadJob>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct UploadJob {
+    #[serde(default)]
+    pub on_complete: Option<OnComplete>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Upload {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub spec: UploadSpec,
+    // Bytes received so far, where the next chunk starts
+    pub offset: u64,
+    // Set once every byte has been received and verified
+    pub path: Option<PathBuf>,
+    // The job created for it, if one was asked for
+    pub job_id: Option<Uuid>,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    NotFound,
+    Invalid(String),
+    // The chunk did not start at the upload's offset, which is given
+    Conflict(u64),
+    // The received file's hash, it did not match and has been discarded
+    HashMismatch(String),
+    Failed(CustomError),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadError::NotFound => write!(f, "Unknown upload"),
+            UploadError::Invalid(e) => write!(f, "Invalid upload: {}", e),
+            UploadError::Conflict(offset) => write!(f, "Upload is at offset {}", offset),
+            UploadError::HashMismatch(hash) => {
+                write!(f, "Uploaded file has a different SHA-256: {}", hash)
+            }
+            UploadError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+fn io_err(e: io::Error) -> UploadError {
+    UploadError::Failed(CustomError::UploadError(e.into()))
+}
+
+// Upload names become file names, anything that could leave the upload's directory or be read
+// as part of a glob is replaced
+pub fn sanitise_name(name: &str) -> Option<String> {
+    let name = name
+        .rsplit(['/', '\\'])
+        .next()?
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || "._-".contains(c) {
+            true => c,
+            false => '_',
+        })
+        .collect::<String>();
+    match name.trim_matches('.').is_empty() {
+        true => None,
+        false => Some(name),
+    }
+}
+
+pub fn create(storage: &Storage, spec: UploadSpec) -> Result<Upload, UploadError> {
+    let name = sanitise_name(&spec.name)
+        .ok_or_else(|| UploadError::Invalid(format!("unusable file name {:?}", spec.name)))?;
+    if spec.sha256.len() != 64 || !spec.sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(UploadError::Invalid(
+            "sha256 is not a SHA-256 hex digest".into(),
+        ));
+    }
+    if spec.size == 0 {
+        return Err(UploadError::Invalid("size is 0".into()));
+    }
+    storage
+        .ensure_space(spec.size)
+        .map_err(UploadError::Failed)?;
+    let upload = Upload {
+        id: Uuid::new_v4(),
+        spec: UploadSpec {
+            name,
+            sha256: spec.sha256.to_lowercase(),
+            ..spec
+        },
+        offset: 0,
+        path: None,
+        job_id: None,
+    };
+    let part = storage.upload_path(upload.id);
+    if let Some(dir) = part.parent() {
+        fs::create_dir_all(dir).map_err(io_err)?;
+    }
+    fs::File::create(part).map_err(io_err)?;
+    save(storage, &upload)?;
+    Ok(upload)
+}
+
+pub fn load(storage: &Storage, id: Uuid) -> Result<Upload, UploadError> {
+    let contents = match fs::read_to_string(storage.upload_state_path(id)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(UploadError::NotFound),
+        Err(e) => return Err(io_err(e)),
+    };
+    serde_json::from_str(&contents)
+        .map_err(|e| UploadError::Failed(CustomError::UploadError(e.into())))
+}
+
+fn save(storage: &Storage, upload: &Upload) -> Result<(), UploadError> {
+    let contents = serde_json::to_string(upload)
+        .map_err(|e| UploadError::Failed(CustomError::UploadError(e.into())))?;
+    storage
+        .write(storage.upload_state_path(upload.id), contents)
+        .map_err(UploadError::Failed)
+}
+
+// Append a chunk starting at `offset`, once the last byte is in the file is verified and moved
+// to its evidence directory. If that fails on anything but the hash, an empty chunk at the
+// upload's size retries it.
+pub fn append(
+    storage: &Storage,
+    id: Uuid,
+    offset: u64,
+    chunk: &[u8],
+) -> Result<Upload, UploadError> {
+    locked(id, || {
+        let mut upload = load(storage, id)?;
+        if upload.path.is_some() || offset != upload.offset {
+            return Err(UploadError::Conflict(upload.offset));
+        }
+        if offset + chunk.len() as u64 > upload.spec.size {
+            return Err(UploadError::Invalid(format!(
+                "chunk ends past the upload's size of {}",
+                upload.spec.size
+            )));
+        }
+        if !chunk.is_empty() {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(storage.upload_path(id))
+                .map_err(io_err)?;
+            // Drop anything an interrupted chunk left past the offset
+            file.set_len(offset).map_err(io_err)?;
+            io::Seek::seek(&mut file, io::SeekFrom::Start(offset)).map_err(io_err)?;
+            file.write_all(chunk).map_err(io_err)?;
+            file.sync_data().map_err(io_err)?;
+            upload.offset += chunk.len() as u64;
+            save(storage, &upload)?;
+        }
+        if upload.offset == upload.spec.size {
+            complete(storage, &mut upload)?;
+            save(storage, &upload)?;
+        }
+        Ok(upload)
+    })
+}
+
+// Remove an upload that can't be completed, along with whatever it received
+pub fn discard(storage: &Storage, id: Uuid) -> Result<(), UploadError> {
+    for path in [storage.upload_path(id), storage.upload_state_path(id)] {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(io_err(e)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn complete(storage: &Storage, upload: &mut Upload) -> Result<(), UploadError> {
+    let part = storage.upload_path(upload.id);
+    let path = storage.evidence_path(upload.id, &upload.spec.name);
+    // Files are only moved once verified, a retry after that only has to record it
+    if !path.exists() {
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs::File::open(&part).map_err(io_err)?, &mut hasher).map_err(io_err)?;
+        let hash = format!("{:x}", hasher.finalize());
+        if hash != upload.spec.sha256 {
+            discard(storage, upload.id)?;
+            return Err(UploadError::HashMismatch(hash));
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(io_err)?;
+        }
+        fs::rename(&part, &path).map_err(io_err)?;
+    }
+    info!("Received upload {} as {}", upload.id, path.display());
+    upload.path = Some(path);
+    Ok(())
+}
+
+// The job asked for with a completed upload, created once
+pub fn job(storage: &Storage, upload: &mut Upload) -> Result<Option<Job>, UploadError> {
+    let (path, spec) = match (&upload.path, &upload.spec.job, upload.job_id) {
+        (Some(path), Some(spec), None) => (path, spec),
+        _ => return Ok(None),
+    };
+    let job = Job::from_spec(&JobSpec::Full {
+        glob: glob::Pattern::escape(&path.to_string_lossy()),
+        on_complete: spec.on_complete.clone(),
+    })
+    .ok_or_else(|| {
+        UploadError::Failed(CustomError::UploadError(
+            format!("No job could be created for {}", path.display()).into(),
+        ))
+    })?;
+    upload.job_id = Some(job.id);
+    save(storage, upload)?;
+    Ok(Some(job))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumable_upload() {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let storage = Storage::new(&root, 0);
+        let contents = b"ElfFile\0 some evtx bytes";
+        let spec = UploadSpec {
+            name: "../C:\\Windows\\Security log.evtx".to_string(),
+            size: contents.len() as u64,
+            sha256: format!("{:x}", Sha256::digest(contents)),
+            job: Some(UploadJob::default()),
+        };
+        let upload = create(&storage, spec.clone()).unwrap();
+        assert_eq!(upload.spec.name, "Security_log.evtx");
+        append(&storage, upload.id, 0, &contents[..10]).unwrap();
+        // A retried or out of order chunk is told where to resume from
+        assert!(matches!(
+            append(&storage, upload.id, 4, &contents[4..]),
+            Err(UploadError::Conflict(10))
+        ));
+        assert_eq!(load(&storage, upload.id).unwrap().offset, 10);
+        let mut upload = append(&storage, upload.id, 10, &contents[10..]).unwrap();
+        let path = upload.path.clone().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), contents);
+        assert!(!storage.upload_path(upload.id).exists());
+        assert!(matches!(
+            append(&storage, upload.id, 10, &contents[10..]),
+            Err(UploadError::Conflict(_))
+        ));
+
+        let job = job(&storage, &mut upload).unwrap().unwrap();
+        assert_eq!(job.paths, vec![path]);
+        assert_eq!(load(&storage, upload.id).unwrap().job_id, Some(job.id));
+
+        let mismatched = create(
+            &storage,
+            UploadSpec {
+                sha256: "0".repeat(64),
+                ..spec.clone()
+            },
+        )
+        .unwrap();
+        assert!(matches!(
+            append(&storage, mismatched.id, 0, contents),
+            Err(UploadError::HashMismatch(_))
+        ));
+        assert!(matches!(
+            load(&storage, mismatched.id),
+            Err(UploadError::NotFound)
+        ));
+
+        // A completion that failed, here with no room for the evidence directory, is retried
+        // with an empty chunk at the upload's size
+        let retried = create(&storage, spec.clone()).unwrap();
+        let evidence = storage.evidence_path(retried.id, &retried.spec.name);
+        fs::write(evidence.parent().unwrap(), b"").unwrap();
+        assert!(matches!(
+            append(&storage, retried.id, 0, contents),
+            Err(UploadError::Failed(_))
+        ));
+        assert!(matches!(
+            append(&storage, retried.id, 0, contents),
+            Err(UploadError::Conflict(offset)) if offset == contents.len() as u64
+        ));
+        fs::remove_file(evidence.parent().unwrap()).unwrap();
+        let retried = append(&storage, retried.id, contents.len() as u64, &[]).unwrap();
+        assert_eq!(fs::read(retried.path.unwrap()).unwrap(), contents);
+        assert!(LOCKS.lock().unwrap().is_empty());
+
+        let discarded = create(&storage, spec).unwrap();
+        append(&storage, discarded.id, 0, &contents[..10]).unwrap();
+        discard(&storage, discarded.id).unwrap();
+        assert!(!storage.upload_path(discarded.id).exists());
+        assert!(matches!(
+            load(&storage, discarded.id),
+            Err(UploadError::NotFound)
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,409 @@
+use crate::{
+    api::ApiMessageType,
+    archive::ArchiveFormat,
+    error::CustomError,
+    job::{Job, JobSpec},
+    sink::OnComplete,
+    storage::Storage,
+    workerpool::Queue,
+    Parser,
+};
+use notify::{RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+lazy_static! {
+    static ref WATCHES: Mutex<HashMap<Uuid, Running>> = Mutex::new(HashMap::new());
+    // SHA-256 of every file a watch has created a job for, shared by all watches
+    static ref SEEN: Mutex<Option<Seen>> = Mutex::new(None);
+}
+
+// How often pending files are checked for having settled
+const TICK: Duration = Duration::from_secs(1);
+
+// Body of POST /watch, ie.
+// {"path": "/drop", "settle_secs": 30, "on_complete": {"sink": "elastic"}}
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchSpec {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub on_complete: Option<OnComplete>,
+    // A file is picked up once it has stopped growing for this long
+    #[serde(default = "default_settle_secs")]
+    pub settle_secs: u64,
+}
+
+fn default_settle_secs() -> u64 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub spec: WatchSpec,
+}
+
+struct Running {
+    watch: Watch,
+    stop: Arc<AtomicBool>,
+}
+
+fn err(e: impl std::fmt::Display) -> CustomError {
+    CustomError::WatchError(e.to_string().into())
+}
+
+// Hashes already processed, kept one per line so they survive a restart
+struct Seen {
+    path: PathBuf,
+    hashes: HashSet<String>,
+}
+
+impl Seen {
+    fn load(path: PathBuf) -> io::Result<Self> {
+        let hashes = match fs::read_to_string(&path) {
+            Ok(contents) => contents.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Seen { path, hashes })
+    }
+    // Whether the hash is new, it is recorded if so
+    fn insert(&mut self, hash: String) -> io::Result<bool> {
+        if self.hashes.contains(&hash) {
+            return Ok(false);
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", hash)?;
+        self.hashes.insert(hash);
+        Ok(true)
+    }
+}
+
+// Whether a parser or archive format recognises the file, nothing else is worth a job
+fn recognised(path: &Path) -> bool {
+    matches!(Parser::try_from(&path.to_path_buf()), Ok(parser) if parser != Parser::None)
+        || matches!(ArchiveFormat::detect(path), Ok(Some(_)))
+}
+
+// Create and prepare a job for a settled file unless its contents have been processed already.
+// The hash is only recorded once the job has been prepared, a file that fails is tried again
+// when it next changes or the watch restarts.
+fn submit(
+    storage: &Storage,
+    queue: &Queue<ApiMessageType>,
+    watch: &Watch,
+    path: &Path,
+) -> Result<(), CustomError> {
+    if !recognised(path) {
+        debug!("Watch {}: {} is not recognised", watch.id, path.display());
+        return Ok(());
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path).map_err(err)?, &mut hasher).map_err(err)?;
+    let hash = format!("{:x}", hasher.finalize());
+    let mut seen = SEEN.lock().unwrap();
+    let seen = match &mut *seen {
+        Some(seen) if seen.path == storage.watch_seen_path() => seen,
+        seen => seen.insert(Seen::load(storage.watch_seen_path()).map_err(err)?),
+    };
+    if seen.hashes.contains(&hash) {
+        info!(
+            "Watch {}: {} has already been processed",
+            watch.id,
+            path.display()
+        );
+        return Ok(());
+    }
+    let job = Job::from_spec(&JobSpec::Full {
+        glob: glob::Pattern::escape(&path.to_string_lossy()),
+        on_complete: watch.spec.on_complete.clone(),
+    })
+    .ok_or_else(|| err(format!("No job could be created for {}", path.display())))?;
+    storage.prepare_job(job.id, &job.paths)?;
+    seen.insert(hash).map_err(err)?;
+    info!(
+        "Watch {}: created job {} for {}",
+        watch.id,
+        job.id,
+        path.display()
+    );
+    queue.push(ApiMessageType::Created(Box::new(job)));
+    Ok(())
+}
+
+// Files seen changing, with their size and when it last changed
+#[derive(Default)]
+struct Pending {
+    files: HashMap<PathBuf, (u64, Instant)>,
+}
+
+impl Pending {
+    fn changed(&mut self, path: PathBuf) {
+        // Partial uploads, temporary and hidden files are left until they are renamed
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or(".");
+        if name.starts_with('.') || name.ends_with(".part") || name.ends_with(".tmp") {
+            return;
+        }
+        // A directory moved in only raises an event for itself
+        if path.is_dir() {
+            let mut files = Vec::new();
+            existing_files(&path, &mut files);
+            files.into_iter().for_each(|path| self.changed(path));
+        } else if path.is_file() {
+            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+            self.files.insert(path, (size, Instant::now()));
+        }
+    }
+    // Files that have kept the same size for `settle`
+    fn settled(&mut self, settle: Duration) -> Vec<PathBuf> {
+        let mut settled = Vec::new();
+        self.files.retain(|path, (size, since)| {
+            let current = match fs::metadata(path) {
+                Ok(metadata) if metadata.is_file() => metadata.len(),
+                _ => return false,
+            };
+            if current != *size {
+                (*size, *since) = (current, Instant::now());
+                return true;
+            }
+            if since.elapsed() < settle {
+                return true;
+            }
+            settled.push(path.clone());
+            false
+        });
+        settled
+    }
+}
+
+fn existing_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        match path.is_dir() {
+            true => existing_files(&path, files),
+            false => files.push(path),
+        }
+    }
+}
+
+fn run(
+    storage: &'static Storage,
+    queue: Queue<ApiMessageType>,
+    watch: Watch,
+    stop: Arc<AtomicBool>,
+) -> Result<(), CustomError> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).map_err(err)?;
+    watcher
+        .watch(&watch.spec.path, RecursiveMode::Recursive)
+        .map_err(err)?;
+    let settle = Duration::from_secs(watch.spec.settle_secs);
+    let mut pending = Pending::default();
+    // Files dropped while nothing was watching
+    let mut files = Vec::new();
+    existing_files(&watch.spec.path, &mut files);
+    files.into_iter().for_each(|path| pending.changed(path));
+    while !stop.load(Ordering::Relaxed) {
+        match rx.recv_timeout(TICK) {
+            Ok(Ok(event)) => event
+                .paths
+                .into_iter()
+                .for_each(|path| pending.changed(path)),
+            Ok(Err(e)) => warn!("Watch {}: {}", watch.id, e),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        for path in pending.settled(settle) {
+            if let Err(e) = submit(storage, &queue, &watch, &path) {
+                error!("Watch {}: {}", watch.id, e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn save(storage: &Storage, watches: &HashMap<Uuid, Running>) -> Result<(), CustomError> {
+    let watches = watches.values().map(|r| &r.watch).collect::<Vec<_>>();
+    storage.write(
+        storage.watches_path(),
+        serde_json::to_string(&watches).map_err(err)?,
+    )
+}
+
+fn start(storage: &'static Storage, queue: &Queue<ApiMessageType>, watch: Watch) -> Running {
+    let stop = Arc::new(AtomicBool::new(false));
+    let (queue, thread_watch, thread_stop) = (queue.clone(), watch.clone(), stop.clone());
+    thread::spawn(move || {
+        let id = thread_watch.id;
+        if let Err(e) = run(storage, queue, thread_watch, thread_stop) {
+            error!("Watch {} stopped. {}", id, e);
+        }
+    });
+    Running { watch, stop }
+}
+
+// Start watching a folder for new evidence. Jobs are created through the API queue.
+pub fn add(
+    storage: &'static Storage,
+    queue: &Queue<ApiMessageType>,
+    spec: WatchSpec,
+) -> Result<Watch, CustomError> {
+    let path =
+        fs::canonicalize(&spec.path).map_err(|e| err(format!("{}: {}", spec.path.display(), e)))?;
+    if !path.is_dir() {
+        return Err(err(format!("{} is not a directory", path.display())));
+    }
+    // ulp's own output would be picked up again
+    let root = fs::canonicalize(storage.root()).unwrap_or_else(|_| storage.root().to_path_buf());
+    if root.starts_with(&path) {
+        return Err(err(format!("{} contains UPLOAD_DIR", path.display())));
+    }
+    let watch = Watch {
+        id: Uuid::new_v4(),
+        spec: WatchSpec { path, ..spec },
+    };
+    let mut watches = WATCHES.lock().unwrap();
+    watches.insert(watch.id, start(storage, queue, watch.clone()));
+    save(storage, &watches)?;
+    info!("Watching {} as {}", watch.spec.path.display(), watch.id);
+    Ok(watch)
+}
+
+pub fn list() -> Vec<Watch> {
+    let watches = WATCHES.lock().unwrap();
+    watches.values().map(|r| r.watch.clone()).collect()
+}
+
+// Stop a watch, false if there was none with this id
+pub fn remove(storage: &Storage, id: Uuid) -> Result<bool, CustomError> {
+    let mut watches = WATCHES.lock().unwrap();
+    match watches.remove(&id) {
+        Some(running) => {
+            running.stop.store(true, Ordering::Relaxed);
+            save(storage, &watches)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Restart the watches saved before ulp was last stopped
+pub fn restore(storage: &'static Storage, queue: &Queue<ApiMessageType>) {
+    let contents = match fs::read_to_string(storage.watches_path()) {
+        Ok(contents) => contents,
+        Err(_) => return,
+    };
+    let saved: Vec<Watch> = match serde_json::from_str(&contents) {
+        Ok(saved) => saved,
+        Err(e) => {
+            error!("{}", err(e));
+            return;
+        }
+    };
+    let mut watches = WATCHES.lock().unwrap();
+    for watch in saved {
+        info!("Watching {} as {}", watch.spec.path.display(), watch.id);
+        watches.insert(watch.id, start(storage, queue, watch));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The next message, failing rather than hanging if none comes
+    fn take(queue: &Queue<ApiMessageType>) -> ApiMessageType {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        loop {
+            if let Some(message) = queue.try_take() {
+                return message;
+            }
+            assert!(Instant::now() < deadline, "no job was created");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn watch_folder() {
+        let root = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let drop = root.join("drop");
+        fs::create_dir_all(drop.join("host")).unwrap();
+        let storage: &'static Storage = Box::leak(Box::new(Storage::new(root.join("ulp"), 0)));
+        let queue = Queue::<ApiMessageType>::new();
+        // Already there when the watch starts
+        fs::write(drop.join("a.evtx"), "ElfFile\0 a").unwrap();
+        let watch = add(
+            storage,
+            &queue,
+            WatchSpec {
+                path: drop.clone(),
+                on_complete: None,
+                settle_secs: 1,
+            },
+        )
+        .unwrap();
+        assert!(add(
+            storage,
+            &queue,
+            WatchSpec {
+                path: root.clone(),
+                on_complete: None,
+                settle_secs: 1,
+            },
+        )
+        .is_err());
+        fs::write(drop.join("c.evtx.part"), "ElfFile\0 c").unwrap();
+        // Too short to be recognised
+        fs::write(drop.join("e.evtx"), "e").unwrap();
+        // Moved in as a whole directory
+        let staged = root.join("host");
+        fs::create_dir_all(&staged).unwrap();
+        fs::write(staged.join("b.evtx"), "ElfFile\0 b").unwrap();
+        fs::remove_dir(drop.join("host")).unwrap();
+        fs::rename(&staged, drop.join("host")).unwrap();
+
+        let mut paths = (0..2)
+            .map(|_| match take(&queue) {
+                ApiMessageType::Created(job) => job.paths[0].display().to_string(),
+                message => panic!("unexpected message {:?}", message),
+            })
+            .collect::<Vec<_>>();
+        paths.sort();
+        let drop = fs::canonicalize(drop).unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                drop.join("a.evtx").display().to_string(),
+                drop.join("host/b.evtx").display().to_string(),
+            ]
+        );
+        // The same contents again
+        fs::write(drop.join("d.evtx"), "ElfFile\0 a").unwrap();
+        thread::sleep(TICK * 3);
+        assert_eq!(queue.lock().len(), 0);
+        assert_eq!(list(), vec![watch.clone()]);
+        assert!(remove(storage, watch.id).unwrap());
+        assert!(!remove(storage, watch.id).unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -6,11 +6,12 @@ mod orchestrator {
     use super::*;
     use crate::{
         api::{ApiMessageType, Store},
-        job::Job,
+        job::{Job, Status},
+        sink::{Sink, SinkRun},
+        type_map::Mapping,
     };
 
     use std::{
-        fs,
         sync::{Arc, Mutex},
         thread::spawn,
     };
@@ -56,6 +57,10 @@ mod orchestrator {
         pub async fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
             let job_store = self.job_store.clone();
             let api_queue = self.api_queue.clone();
+            // Restart folder watches, they create jobs through the API queue
+            crate::watch::restore(&crate::storage::STORAGE, &api_queue);
+            // Dead letters of replays cut short by a restart go back to their dead letter files
+            crate::elastic::restore_replays();
             // Run Warp API
             debug!("Spawning async Orchestrator API thread");
             tokio::spawn(async move {
@@ -65,66 +70,72 @@ mod orchestrator {
             });
             // Run the thread for converting api messages to job messages
             let api_queue = self.api_queue.clone();
-            let worker_queue = self.worker_queue.clone();
             let pool = self.pool.clone();
             debug!("Spawning Orchestrator API message reader thread");
             let _api_message_handle = spawn(move || loop {
                 let message_wrapper = api_queue.take();
                 match message_wrapper {
-                    ApiMessageType::Job(message) => match Job::from_glob(message.as_str()) {
+                    ApiMessageType::Job(message) => match Job::from_spec(&message) {
                         Some(job) => {
-                            trace!("Converted message: {} to job: {:?}", &message, &job);
-                            worker_queue.push(job);
+                            trace!("Converted message: {:?} to job: {:?}", &message, &job);
+                            match crate::storage::STORAGE.prepare_job(job.id, &job.paths) {
+                                Ok(()) => {
+                                    Status::Pending.save(job.id);
+                                    pool.lock()
+                                        .unwrap()
+                                        .send_message(Message::Expand(Box::new(job)))
+                                }
+                                Err(e) => error!("{}", e),
+                            }
                         }
-                        None => error!("Failed to convert message to job: {}", &message),
+                        None => error!("Failed to convert message to job: {:?}", &message),
                     },
+                    ApiMessageType::Created(job) => {
+                        info!("Job {} issued", job.id);
+                        match crate::storage::STORAGE.prepare_job(job.id, &job.paths) {
+                            Ok(()) => {
+                                Status::Pending.save(job.id);
+                                pool.lock().unwrap().send_message(Message::Expand(job))
+                            }
+                            Err(e) => error!("{}", e),
+                        }
+                    }
                     ApiMessageType::Elastic(uuid) => {
                         info!("Elastic ingestion Job issued for uuid: {}", &uuid);
                         // Read mapping into memory
-                        let target_dir = format!("{}/{}", crate::UPLOAD_DIR_ENV, uuid);
-                        // Serialize job
-                        let job: Job =
-                            match fs::read_to_string(format!("{}/{}", target_dir, "mappings.json"))
-                            {
-                                Ok(j) => serde_json::from_str(&j).unwrap(),
-                                Err(e) => {
-                                    error!(
-                                        "Failed to read mapping file at {}. {} ",
-                                        format!("{}/{}", target_dir, "mappings.json"),
-                                        e
-                                    );
-                                    panic!(
-                                        "Failed to read mapping file at {}/mappings.json. {}",
-                                        target_dir, e
-                                    )
-                                }
-                            };
-                        let mapping = job.mapping.lock().unwrap();
-                        // For each index mapping issue the elastic mapping
-                        for (index, map) in &mapping.index_pattern_mappings {
-                            pool.lock().unwrap().send_message(Message::ElasticMapping {
-                                index: index.to_string(),
-                                map: map.clone(),
-                            });
-                        }
-                        // For each parsed file issue a Message Job to thread
-                        for parsed_file in &mapping.file_mapping {
-                            // Track elapsed and submit on 1/2 second intervals
-                            use std::time::{Duration, Instant};
-                            let start = Instant::now();
-                            //
-                            pool.lock().unwrap().send_message(Message::Elastic {
-                                map: mapping.clone(),
-                                data: parsed_file.parsed_file_path.clone(),
-                                parser: parsed_file.parser_used.clone(),
-                            });
-                            //
-                            let duration = start.elapsed();
-                            if duration < Duration::from_millis(5) {
-                                std::thread::sleep(Duration::from_millis(5) - duration);
+                        let job = match Job::load(uuid) {
+                            Ok(job) => job,
+                            Err(e) => {
+                                error!("{}", e);
+                                continue;
                             }
+                        };
+                        send_to_sink(
+                            &pool,
+                            SinkRun::new(uuid, Sink::Elastic),
+                            &job.mapping.lock().unwrap(),
+                        );
+                    }
+                    ApiMessageType::Kibana(uuid) => {
+                        info!("Kibana provisioning issued for uuid: {}", &uuid);
+                        let res = Job::load(uuid)
+                            .and_then(|job| crate::kibana::provision(&job.mapping.lock().unwrap()));
+                        if let Err(e) = res {
+                            error!("{}", e);
                         }
                     }
+                    ApiMessageType::Export(uuid, format) => {
+                        info!("{:?} export issued for uuid: {}", format, &uuid);
+                        pool.lock()
+                            .unwrap()
+                            .send_message(Message::Export { job: uuid, format });
+                    }
+                    ApiMessageType::Replay(uuid) => {
+                        info!("Dead letter replay issued for uuid: {}", &uuid);
+                        pool.lock()
+                            .unwrap()
+                            .send_message(Message::ElasticReplay { job: uuid });
+                    }
                 }
             });
             // Read in job messages from queue and push to workers as tasks (1 file = 1 task)
@@ -134,6 +145,7 @@ mod orchestrator {
             debug!("Spawning Orchestrator Job reader / Task issuer thread");
             let _job_task_handle = spawn(move || loop {
                 let worker_job = worker_queue.take();
+                Status::Running.save(worker_job.id);
                 // Clone the job so we can send it to the worker
                 for task_res in worker_job.clone() {
                     match task_res {
@@ -154,11 +166,23 @@ mod orchestrator {
             // Read the tasks coming back from the workers and match to jobs and track tasks returning
             let processing_queue = self.processing_queue.clone();
             let completed_queue = self.completed_queue.clone();
+            let worker_queue = self.worker_queue.clone();
             let pool_receiver = self.pool.lock().unwrap().receiver.clone();
             let pool = self.pool.clone();
             debug!("Spawning Orchestrator Task receiver / Completed issuer thread");
             let _task_recv_handle = spawn(move || loop {
                 let message = pool_receiver.lock().unwrap().recv().unwrap();
+                // Jobs come back once their archives have been expanded
+                if let super::Message::Expand(job) = message {
+                    match job.paths.is_empty() {
+                        true => {
+                            error!("Job {} has no files left to parse", job.id);
+                            Status::Failed.save(job.id);
+                        }
+                        false => worker_queue.push(*job),
+                    }
+                    continue;
+                }
                 if let super::Message::Task(task) = message {
                     debug!("Task Message received from WorkerPool: {}", &task.id);
                     // Match message to job in working queue
@@ -179,7 +203,26 @@ mod orchestrator {
                         if does_contain {
                             info!("Confirmed task {} has finished processing", &task.id);
                             info!("{} tasks waiting", pool.lock().unwrap().queue.lock().len());
+                            let task_id = task.id;
                             working_job.processed.push(task);
+                            // Send the file on now if the job is ingesting per file
+                            if let Some(run) = &working_job.sink_run {
+                                let mapping = working_job.mapping.lock().unwrap().clone();
+                                let parsed_file = mapping
+                                    .file_mapping
+                                    .iter()
+                                    .find(|f| f.parsed_file_uuid == task_id)
+                                    .cloned();
+                                if let Some(file) = parsed_file {
+                                    run.dispatched();
+                                    pool.lock().unwrap().send_message(Message::Sink {
+                                        run: run.clone(),
+                                        map: mapping,
+                                        file,
+                                        prepare: true,
+                                    });
+                                }
+                            }
 
                             let sent_len = working_job.sent.lock().unwrap().len();
                             if sent_len != 0 && sent_len == working_job.processed.len() {
@@ -195,38 +238,74 @@ mod orchestrator {
                     }
                 }
             });
-            // Loop on status of workers, eventually to be replaced with optional CLI GUI for non-docker runs
+            // Finish completed jobs on their own thread, preparing and closing a sink blocks on
+            // its requests so must stay off the async runtime
+            let completed_queue = self.completed_queue.clone();
+            let pool = self.pool.clone();
             info!("Entering main Orchestrator loop");
-            loop {
-                let completed = self.completed_queue.take();
-                std::fs::create_dir_all(format!("{}/{}/", crate::UPLOAD_DIR_ENV, completed.id))
+            let completed_handle = spawn(move || loop {
+                let mut completed = completed_queue.take();
+                completed.status = Status::Done;
+                completed.status.save(completed.id);
+                let storage = &crate::storage::STORAGE;
+                storage
+                    .write(
+                        storage.mapping_path(completed.id),
+                        serde_json::to_string(&completed).unwrap(),
+                    )
                     .unwrap();
-                let mut mapping_file = fs::OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(format!(
-                        "{}/{}/{}",
-                        crate::UPLOAD_DIR_ENV,
-                        completed.id,
-                        "mappings.json"
-                    ))
-                    .unwrap();
-                use std::io::Write;
-                write!(
-                    &mut mapping_file,
-                    "{}",
-                    serde_json::to_string(&completed).unwrap()
-                )
-                .unwrap();
                 info!(
                     "Completed Job {} in: {:?}\n\tFiles: {}",
                     completed.id,
                     completed.completed.elapsed(),
                     completed.paths.len()
                 );
+                match (&completed.on_complete, &completed.sink_run) {
+                    (Some(_), Some(run)) => {
+                        run.observe(&completed.mapping.lock().unwrap());
+                        run.close();
+                    }
+                    (Some(on_complete), None) => send_to_sink(
+                        &pool,
+                        SinkRun::new(completed.id, on_complete.sink),
+                        &completed.mapping.lock().unwrap(),
+                    ),
+                    (None, _) => (),
+                }
+            });
+            tokio::task::spawn_blocking(move || completed_handle.join())
+                .await?
+                .map_err(|_| "Orchestrator completed job thread panicked")?;
+            Ok(())
+        }
+    }
+
+    // Prepare the sink with a job's full mapping then hand every parsed file to the workers
+    fn send_to_sink(pool: &Arc<Mutex<WorkerPool>>, run: Arc<SinkRun>, mapping: &Mapping) {
+        run.observe(mapping);
+        if let Err(e) = run.prepare(mapping) {
+            run.fail(e);
+            return;
+        }
+        for parsed_file in &mapping.file_mapping {
+            // Track elapsed and submit on 1/2 second intervals
+            use std::time::{Duration, Instant};
+            let start = Instant::now();
+            //
+            run.dispatched();
+            pool.lock().unwrap().send_message(Message::Sink {
+                run: run.clone(),
+                map: mapping.clone(),
+                file: parsed_file.clone(),
+                prepare: false,
+            });
+            //
+            let duration = start.elapsed();
+            if duration < Duration::from_millis(5) {
+                std::thread::sleep(Duration::from_millis(5) - duration);
             }
         }
+        run.close();
     }
 }
 
@@ -313,13 +392,34 @@ mod pool {
                                 panic!("Worker {} failed to send results to orchestrator", id)
                             });
                         }
-                        Elastic { map, data, parser } => {
-                            //
-                            crate::elastic::normalise_then_send(map, data, &parser).unwrap();
+                        Sink {
+                            run,
+                            map,
+                            file,
+                            prepare,
+                        } => {
+                            let res = match prepare {
+                                true => run.prepare(&map),
+                                false => Ok(()),
+                            }
+                            .and_then(|_| run.sink.send_file(run.job, map.clone(), &file));
+                            run.file_done(&map, res);
+                        }
+                        Expand(mut job) => {
+                            job.expand(&crate::storage::STORAGE);
+                            output.send(Expand(job)).unwrap_or_else(|_| {
+                                panic!("Worker {} failed to send results to orchestrator", id)
+                            });
+                        }
+                        ElasticReplay { job } => {
+                            if let Err(e) = crate::elastic::replay_dead_letters(job) {
+                                error!("Dead letter replay for job {} failed: {}", job, e);
+                            }
                         }
-                        ElasticMapping { map, index } => {
-                            //
-                            crate::elastic::send_mapping(index, map).unwrap();
+                        Export { job, format } => {
+                            if let Err(e) = crate::export::export_job(job, format) {
+                                error!("{:?} export of job {} failed: {}", format, job, e);
+                            }
                         }
                         Debug(_) => {
                             debug!("Processing task ({:?}): {:?}", id, &task_wrapper);
@@ -336,22 +436,32 @@ mod pool {
     }
 
     pub mod message {
-        use crate::{job::Task, type_map::Mapping};
-        use std::path::PathBuf;
-        use type_casting::Types as TypeMap;
+        use crate::{
+            job::{Job, Task},
+            sink::SinkRun,
+            type_map::{Mapping, ParsedFileStats},
+        };
+        use std::sync::Arc;
         //
         #[derive(Clone, Debug)]
         pub enum Message {
             Debug(i64),
             Task(Task),
-            Elastic {
+            // Expand a prepared job's archives, it is sent back once they have been
+            Expand(Box<Job>),
+            // Send a parsed file to the run's sink, preparing the sink first when ingesting per file
+            Sink {
+                run: Arc<SinkRun>,
                 map: Mapping,
-                data: PathBuf,
-                parser: crate::Parser,
+                file: ParsedFileStats,
+                prepare: bool,
+            },
+            ElasticReplay {
+                job: uuid::Uuid,
             },
-            ElasticMapping {
-                map: TypeMap,
-                index: String,
+            Export {
+                job: uuid::Uuid,
+                format: crate::export::ExportFormat,
             },
         }
 
@@ -4,6 +4,7 @@ use std::collections::BTreeMap;
 use std::error::Error;
 pub use types::Types;
 
+pub mod schema;
 mod types;
 
 #[cfg(test)]
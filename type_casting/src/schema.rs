@@ -0,0 +1,189 @@
+use crate::{merge_consume, Types};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlDialect {
+    Postgres,
+    Sqlite,
+}
+
+// Flatten nested objects into dotted column paths, lists are kept as a single column
+pub fn flatten(t: &Types) -> Vec<(String, Types)> {
+    let mut columns = Vec::new();
+    recurse(t, "", &mut columns);
+    return columns;
+    fn recurse(t: &Types, prefix: &str, columns: &mut Vec<(String, Types)>) {
+        match t {
+            Types::Object(map) => {
+                for (key, value) in map {
+                    let path = match prefix.is_empty() {
+                        true => key.to_string(),
+                        false => format!("{}.{}", prefix, key),
+                    };
+                    recurse(value, &path, columns);
+                }
+            }
+            _ => columns.push((prefix.to_string(), t.clone())),
+        }
+    }
+}
+
+// Flatten a value to the same column paths as `flatten`, lists are kept whole
+pub fn flatten_value(value: Value) -> BTreeMap<String, Value> {
+    let mut columns = BTreeMap::new();
+    recurse(value, "", &mut columns);
+    return columns;
+    fn recurse(value: Value, prefix: &str, columns: &mut BTreeMap<String, Value>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    let path = match prefix.is_empty() {
+                        true => key,
+                        false => format!("{}.{}", prefix, key),
+                    };
+                    recurse(value, &path, columns);
+                }
+            }
+            value => {
+                columns.insert(prefix.to_string(), value);
+            }
+        }
+    }
+}
+
+// A single type covering every element of a list, mixed containers and primitives fall back to Str
+pub fn list_item_type(map: &BTreeMap<usize, Types>) -> Types {
+    fn kind(t: &Types) -> u8 {
+        match t {
+            Types::Null => 0,
+            Types::Object(_) => 1,
+            Types::List(_) => 2,
+            _ => 3,
+        }
+    }
+    let mut item = Types::Null;
+    for value in map.values() {
+        match (kind(&item), kind(value)) {
+            (_, 0) => continue,
+            (a, b) if a != 0 && a != b => return Types::Str,
+            _ => item = merge_consume(item, value.clone()),
+        }
+    }
+    item
+}
+
+pub fn json_schema(t: &Types) -> Value {
+    let mut schema = recurse(t);
+    if let Value::Object(ref mut map) = schema {
+        map.insert(
+            "$schema".to_string(),
+            json!("https://json-schema.org/draft/2020-12/schema"),
+        );
+    }
+    return schema;
+    fn recurse(t: &Types) -> Value {
+        match t {
+            Types::Null => json!({"type": "null"}),
+            Types::Bool => json!({"type": ["boolean", "null"]}),
+            Types::Int => json!({"type": ["integer", "null"]}),
+            Types::Float => json!({"type": ["number", "null"]}),
+            Types::IPv4 => json!({"type": ["string", "null"], "format": "ipv4"}),
+            Types::IPv6 => json!({"type": ["string", "null"], "format": "ipv6"}),
+            Types::Date => json!({"type": ["string", "null"], "format": "date-time"}),
+            Types::Str => json!({"type": ["string", "null"]}),
+            Types::List(map) => {
+                json!({"type": ["array", "null"], "items": recurse(&list_item_type(map))})
+            }
+            Types::Object(map) => {
+                let mut properties = Map::new();
+                for (key, value) in map {
+                    properties.insert(key.clone(), recurse(value));
+                }
+                json!({"type": ["object", "null"], "properties": properties})
+            }
+        }
+    }
+}
+
+// Arrow schema in the JSON representation used by the Arrow integration format
+pub fn arrow_schema(t: &Types) -> Value {
+    let fields = match t {
+        Types::Object(map) => map
+            .iter()
+            .map(|(key, value)| field(key, value))
+            .collect::<Vec<_>>(),
+        _ => vec![field("value", t)],
+    };
+    return json!({ "fields": fields });
+    fn field(name: &str, t: &Types) -> Value {
+        let (data_type, children) = match t {
+            Types::Null => (json!({"name": "null"}), vec![]),
+            Types::Bool => (json!({"name": "bool"}), vec![]),
+            Types::Int => (
+                json!({"name": "int", "bitWidth": 64, "isSigned": true}),
+                vec![],
+            ),
+            Types::Float => (
+                json!({"name": "floatingpoint", "precision": "DOUBLE"}),
+                vec![],
+            ),
+            Types::Date => (
+                json!({"name": "timestamp", "unit": "MICROSECOND", "timezone": "UTC"}),
+                vec![],
+            ),
+            Types::IPv4 | Types::IPv6 | Types::Str => (json!({"name": "utf8"}), vec![]),
+            Types::List(map) => (
+                json!({"name": "list"}),
+                vec![field("item", &list_item_type(map))],
+            ),
+            Types::Object(map) => (
+                json!({"name": "struct"}),
+                map.iter().map(|(k, v)| field(k, v)).collect(),
+            ),
+        };
+        json!({
+            "name": name,
+            "nullable": true,
+            "type": data_type,
+            "children": children,
+        })
+    }
+}
+
+pub fn sql_type(t: &Types, dialect: SqlDialect) -> &'static str {
+    use SqlDialect::*;
+    match (t, dialect) {
+        (Types::Bool, Postgres) => "BOOLEAN",
+        (Types::Int, Postgres) => "BIGINT",
+        (Types::Float, Postgres) => "DOUBLE PRECISION",
+        (Types::IPv4 | Types::IPv6, Postgres) => "INET",
+        (Types::Date, Postgres) => "TIMESTAMPTZ",
+        (Types::List(_) | Types::Object(_), Postgres) => "JSONB",
+        (Types::Null | Types::Str, Postgres) => "TEXT",
+        (Types::Bool | Types::Int, Sqlite) => "INTEGER",
+        (Types::Float, Sqlite) => "REAL",
+        (_, Sqlite) => "TEXT",
+    }
+}
+
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// CREATE TABLE statement with one column per flattened field, none for a type without fields
+// since a table needs at least one column
+pub fn sql_ddl(table: &str, t: &Types, dialect: SqlDialect) -> Option<String> {
+    let columns = flatten(t)
+        .iter()
+        .map(|(name, t)| format!("    {} {}", quote_identifier(name), sql_type(t, dialect)))
+        .collect::<Vec<_>>();
+    if columns.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "CREATE TABLE IF NOT EXISTS {} (\n{}\n);",
+        quote_identifier(table),
+        columns.join(",\n")
+    ))
+}
@@ -546,3 +546,72 @@ mod int_casts {
         assert_eq!(cast_value(&type_map, v_err_1).map_err(|_| ()), Err(()));
     }
 }
+//
+mod schema_exports {
+    use super::*;
+    use crate::schema::{
+        arrow_schema, flatten, flatten_value, json_schema, list_item_type, sql_ddl, SqlDialect,
+    };
+    #[test]
+    fn flattened_columns() {
+        let type_map = Types::get_type(&json!({"a": {"b": 1, "c": {"d": "x"}}, "e": [1, 2]}));
+        let columns = flatten(&type_map)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect::<Vec<_>>();
+        assert_eq!(columns, vec!["a.b", "a.c.d", "e"]);
+    }
+    #[test]
+    fn flattened_values() {
+        let columns = flatten_value(json!({"a": {"b": 1, "c": {"d": "x"}}, "e": [1, 2]}));
+        assert_eq!(
+            columns.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("a.b".to_string(), json!(1)),
+                ("a.c.d".to_string(), json!("x")),
+                ("e".to_string(), json!([1, 2]))
+            ]
+        );
+    }
+    #[test]
+    fn list_items() {
+        let list = |v: Value| match Types::get_type(&v) {
+            Types::List(map) => map,
+            _ => unreachable!(),
+        };
+        assert_eq!(list_item_type(&list(json!([1, 2.5]))), Types::Float);
+        assert_eq!(list_item_type(&list(json!([null, 1]))), Types::Int);
+        assert_eq!(list_item_type(&list(json!([{"a": 1}, 2]))), Types::Str);
+    }
+    #[test]
+    fn json_schema_object() {
+        let schema = json_schema(&Types::get_type(&json!({"a": 1, "b": "127.0.0.1"})));
+        assert_eq!(
+            schema["properties"]["a"]["type"],
+            json!(["integer", "null"])
+        );
+        assert_eq!(schema["properties"]["b"]["format"], json!("ipv4"));
+    }
+    #[test]
+    fn arrow_struct() {
+        let schema = arrow_schema(&Types::get_type(&json!({"a": {"b": true}})));
+        assert_eq!(schema["fields"][0]["type"]["name"], json!("struct"));
+        assert_eq!(schema["fields"][0]["children"][0]["name"], json!("b"));
+    }
+    #[test]
+    fn sql_tables() {
+        let type_map = Types::get_type(&json!({"a": {"b": 1}, "c": "2022-03-23T00:00:00Z"}));
+        assert_eq!(
+            sql_ddl("t", &type_map, SqlDialect::Postgres).unwrap(),
+            "CREATE TABLE IF NOT EXISTS \"t\" (\n    \"a.b\" BIGINT,\n    \"c\" TIMESTAMPTZ\n);"
+        );
+        assert_eq!(
+            sql_ddl("t", &type_map, SqlDialect::Sqlite).unwrap(),
+            "CREATE TABLE IF NOT EXISTS \"t\" (\n    \"a.b\" INTEGER,\n    \"c\" TEXT\n);"
+        );
+        assert_eq!(
+            sql_ddl("t", &Types::get_type(&json!({})), SqlDialect::Postgres),
+            None
+        );
+    }
+}